@@ -0,0 +1,160 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use eyre::WrapErr;
+
+use crate::{plan::Plan, target::TargetPath};
+
+/// Files changed in the working tree relative to `since`, relative to `root`.
+pub fn changed_files(root: &Path, since: &str) -> eyre::Result<Vec<PathBuf>> {
+    let output = std::process::Command::new("git")
+        .current_dir(root)
+        .args(["diff", "--name-only", "--relative", since, "--"])
+        .output()
+        .wrap_err("Running git diff")?;
+    eyre::ensure!(
+        output.status.success(),
+        "git diff against {since} failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Targets in `plan` that declare one of `changed` as a src, along with everything that
+/// transitively depends on them. A changed file that no build declares as a src, like a header
+/// or the FFS file itself, conservatively affects every target in its package.
+pub fn affected(plan: &Plan, root: &Path, changed: &[PathBuf]) -> Vec<TargetPath> {
+    let changed = changed.iter().map(|f| root.join(f)).collect::<HashSet<_>>();
+    let srcs = plan
+        .steps
+        .iter()
+        .filter_map(|step| Some((step, step.def().as_build()?)))
+        .flat_map(|(step, build)| build.srcs.iter().map(|src| step.dir.join(src)))
+        .collect::<Vec<_>>();
+    let dirty_packages = changed
+        .iter()
+        .filter(|f| !srcs.iter().any(|src| f.starts_with(src)))
+        .filter_map(|f| package(root, f))
+        .collect::<HashSet<_>>();
+    let mut affected = HashSet::new();
+
+    // Steps are ordered dependencies first, so a single pass sees every dependency before its
+    // dependents.
+    for step in &plan.steps {
+        let srcs_changed = step.def().as_build().is_some_and(|build| {
+            build.srcs.iter().any(|src| {
+                let src = step.dir.join(src);
+                changed.iter().any(|f| f.starts_with(&src))
+            })
+        });
+
+        if srcs_changed
+            || dirty_packages.contains(&step.dir)
+            || step.deps.iter().any(|d| affected.contains(d))
+        {
+            affected.insert(step.path.clone());
+        }
+    }
+
+    plan.steps
+        .iter()
+        .map(|s| &s.path)
+        .filter(|p| affected.contains(*p))
+        .cloned()
+        .collect()
+}
+
+/// The directory of the nearest FFS file at or above `file`, within `root`.
+fn package(root: &Path, file: &Path) -> Option<PathBuf> {
+    file.ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(root))
+        .find(|dir| dir.join("FFS").is_file())
+        .map(Path::to_path_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{starlark::Reader, testing::workspace};
+
+    #[test]
+    fn leaf_src_change_affects_dependents() {
+        let dir = workspace([
+            (
+                "lib/FFS",
+                r#"
+build("leaf", "cat a.txt > out", ["a.txt"], {"out": "out"})
+build("other", "cat b.txt > out2", ["b.txt"], {"out": "out2"})
+"#,
+            ),
+            (
+                "FFS",
+                r#"
+task("app", "cat //lib/leaf:out")
+task("wrapper", "true", prereqs = ["//app"])
+task("unrelated", "cat //lib/other:out")
+"#,
+            ),
+        ]);
+        let root = dir.path();
+
+        let reader = Reader::new(root);
+        let targets = ["//app", "//wrapper", "//unrelated"].map(|t| t.parse().unwrap());
+        let plan = Plan::new(&reader, root, &targets).unwrap();
+
+        let affected = affected(&plan, root, &[PathBuf::from("lib/a.txt")]);
+        let affected = affected.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(affected, ["//lib/leaf", "//app", "//wrapper"]);
+    }
+
+    #[test]
+    fn unclaimed_files_dirty_their_package() {
+        let dir = workspace([
+            (
+                "lib/FFS",
+                r#"
+build("leaf", "cat a.txt > out", ["a.txt"], {"out": "out"})
+build("gen", "cp -r data out", ["data"], {"out": "out/"})
+"#,
+            ),
+            ("lib/nested/FFS", r#"task("deep", "true")"#),
+            (
+                "FFS",
+                r#"
+task("app", "cat //lib/leaf:out")
+task("uses_gen", "ls //lib/gen:out")
+"#,
+            ),
+        ]);
+        let root = dir.path();
+
+        let reader = Reader::new(root);
+        let targets = ["//app", "//uses_gen", "//lib/nested/deep"].map(|t| t.parse().unwrap());
+        let plan = Plan::new(&reader, root, &targets).unwrap();
+        let affected = |changed: &[&str]| {
+            let changed = changed.iter().map(PathBuf::from).collect::<Vec<_>>();
+            let mut affected = affected(&plan, root, &changed)
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>();
+            affected.sort();
+            affected
+        };
+
+        assert_eq!(affected(&["lib/data/x.json"]), ["//lib/gen", "//uses_gen"]);
+        assert_eq!(
+            affected(&["lib/header.h"]),
+            ["//app", "//lib/gen", "//lib/leaf", "//uses_gen"]
+        );
+        assert_eq!(affected(&["lib/nested/notes.txt"]), ["//lib/nested/deep"]);
+        assert_eq!(affected(&["README.md"]), ["//app", "//uses_gen"]);
+    }
+}
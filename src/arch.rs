@@ -0,0 +1,52 @@
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+impl FromStr for Arch {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "x86_64" | "amd64" | "x64" => Arch::X86_64,
+            "aarch64" | "arm64" => Arch::Aarch64,
+
+            s => eyre::bail!("Unknown architecture: {s:?}"),
+        })
+    }
+}
+
+impl Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+        };
+        write!(f, "{name}")
+    }
+}
+
+pub fn host() -> Arch {
+    std::env::consts::ARCH
+        .parse()
+        .expect("host architecture is supported")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_aliases() {
+        for s in ["x86_64", "amd64", "x64"] {
+            assert_eq!(s.parse::<Arch>().unwrap(), Arch::X86_64);
+        }
+        for s in ["aarch64", "arm64"] {
+            assert_eq!(s.parse::<Arch>().unwrap(), Arch::Aarch64);
+        }
+        assert!("sparc".parse::<Arch>().is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::target::TargetPath;
+
+/// An append-only JSONL record of every command executed.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+pub struct Record<'r> {
+    pub target: &'r TargetPath,
+    pub command: &'r str,
+    pub workdir: &'r Path,
+    pub env: Vec<&'r str>,
+    pub start_ms: u128,
+    pub end_ms: u128,
+    pub exit_status: Option<i32>,
+    /// Sha256 of each declared output present after the command ran.
+    pub outputs: BTreeMap<String, String>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> eyre::Result<AuditLog> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, record: &Record) -> eyre::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        // A single write per record keeps lines whole even with multiple writers.
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+pub fn unix_ms(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
@@ -0,0 +1,2706 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use dashmap::{DashMap, DashSet};
+use eyre::OptionExt;
+
+use crate::{
+    audit::{self, AuditLog, Record},
+    cache::{self, Cache, Logs},
+    command::{self, quote},
+    digest,
+    executor::{Execution, Executor},
+    fingerprint::Fingerprints,
+    plan::{Plan, Step},
+    reporting::{Status, TestFailure, TEST_TAG},
+    sandbox::Sandbox,
+    target::{Build, HttpFile, Output, Source, TargetDef, TargetPath},
+    timings::Timings,
+};
+
+/// Lines of a streamed command's stderr repeated in the error when it fails.
+const FAILURE_TAIL_LINES: usize = 20;
+
+pub struct Builder {
+    executor: Arc<Executor>,
+
+    root: PathBuf,
+    outputs: DashMap<Output, PathBuf>,
+    /// Which target declared each output file, so two targets can't silently write the same one.
+    producers: DashMap<PathBuf, TargetPath>,
+    built: Mutex<Vec<TargetPath>>,
+    /// Targets already built by this builder, which later plans can skip.
+    memo: DashSet<TargetPath>,
+    /// Named mutexes from `lock = "..."`, so targets sharing one never run at the same time.
+    locks: DashMap<String, Arc<Mutex<()>>>,
+    /// How long each target built by this builder took, for [`Builder::critical_path`].
+    took: DashMap<TargetPath, Duration>,
+    /// Stderr of test targets whose command failed, for [`Reporter::finish_test`].
+    ///
+    /// [`Reporter::finish_test`]: crate::reporting::Reporter::finish_test
+    failed_stderr: DashMap<TargetPath, String>,
+
+    audit_log: Option<AuditLog>,
+    cache_namespace: Option<String>,
+    touch: bool,
+    check_mtime: bool,
+    use_cache: bool,
+    keep_going: bool,
+    strict_outputs: bool,
+    sandbox: bool,
+    replay: bool,
+    /// A target and arguments appended to its command.
+    extra_args: Option<(TargetPath, Vec<String>)>,
+    dry_run: bool,
+    /// With `dry_run`, each command that would have run, in order.
+    rehearsed: Mutex<Vec<(TargetPath, String)>>,
+    jobs: usize,
+    slots: Slots,
+    cache: Cache,
+    fingerprints: Fingerprints,
+    timings: Timings,
+    eta_default: Duration,
+}
+
+/// Scheduling state shared between the workers of [`Builder::execute`].
+struct Schedule {
+    /// Per step, how many of its dependencies have yet to be built.
+    waiting_on: Vec<usize>,
+    ready: VecDeque<usize>,
+    running: usize,
+    finished: usize,
+    /// How long the steps not yet finished, successfully or not, took in earlier runs.
+    remaining: Duration,
+    error: Option<eyre::Report>,
+    /// With `keep_going`, every step that failed so far.
+    failures: Vec<(TargetPath, eyre::Report)>,
+}
+
+/// Every target that failed during a build with [`Builder::keep_going`].
+#[derive(Debug, thiserror::Error)]
+#[error("{}", summarize(.failures, *.skipped))]
+pub struct Failures {
+    pub failures: Vec<(TargetPath, eyre::Report)>,
+    /// Dependents of failed targets that were never started.
+    pub skipped: usize,
+}
+
+fn summarize(failures: &[(TargetPath, eyre::Report)], skipped: usize) -> String {
+    let mut summary = format!("{} targets failed:", failures.len());
+    for (target, e) in failures {
+        summary.push_str(&format!("\n  {target}: {e}"));
+    }
+    if skipped > 0 {
+        summary.push_str(&format!("\n{skipped} dependent targets were skipped"));
+    }
+    summary
+}
+
+/// What executing a plan would do with a build, per [`Builder::forecast`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forecast {
+    /// Its command will run.
+    Run,
+    /// Its outputs will be restored from the cache.
+    Cached,
+    /// Its outputs are newer than its inputs, so it won't run.
+    UpToDate,
+}
+
+/// Counts the commands running at once against [`Builder::jobs`]. Each step the scheduler runs
+/// holds one, and foreach steps take any left over to run more of their items at once.
+#[derive(Default)]
+struct Slots {
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    /// Takes a slot, waiting until fewer than `limit` are in use.
+    fn acquire(&self, limit: usize) {
+        let mut used = self.used.lock().unwrap();
+        while *used >= limit {
+            used = self.freed.wait(used).unwrap();
+        }
+        *used += 1;
+    }
+
+    /// Takes a slot if fewer than `limit` are in use, without waiting.
+    fn try_acquire(&self, limit: usize) -> bool {
+        let mut used = self.used.lock().unwrap();
+        let free = *used < limit;
+        if free {
+            *used += 1;
+        }
+        free
+    }
+
+    fn release(&self) {
+        *self.used.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+}
+
+impl Builder {
+    pub fn new(executor: Arc<Executor>, root: impl AsRef<Path>) -> Self {
+        Self {
+            executor,
+
+            root: root.as_ref().to_path_buf(),
+            outputs: Default::default(),
+            producers: Default::default(),
+            built: Default::default(),
+            memo: Default::default(),
+            locks: Default::default(),
+            took: Default::default(),
+            failed_stderr: Default::default(),
+
+            audit_log: None,
+            cache_namespace: None,
+            touch: false,
+            check_mtime: true,
+            use_cache: true,
+            keep_going: false,
+            strict_outputs: false,
+            sandbox: false,
+            replay: true,
+            extra_args: None,
+            dry_run: false,
+            rehearsed: Default::default(),
+            jobs: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            slots: Default::default(),
+            cache: Cache::new(root.as_ref().join(".ffs/cache")),
+            fingerprints: Fingerprints::load(root.as_ref().join(".ffs/fingerprints.json")),
+            timings: Timings::load(root.as_ref().join(".ffs/timings.json")),
+            eta_default: Duration::from_secs(10),
+        }
+    }
+
+    pub fn audit_log(mut self, log: AuditLog) -> Self {
+        self.audit_log = Some(log);
+        self
+    }
+
+    /// Isolates cache entries from those written under other namespaces, e.g. other branches
+    /// sharing the same cache.
+    pub fn cache_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.cache_namespace = Some(namespace.into());
+        self
+    }
+
+    /// Instead of running commands, creates or updates the mtime of their declared outputs, as
+    /// if they had been built.
+    pub fn touch(mut self, touch: bool) -> Self {
+        self.touch = touch;
+        self
+    }
+
+    /// Whether to skip builds whose outputs are all newer than their inputs, or whose inputs are
+    /// unchanged since they last succeeded.
+    pub fn check_mtime(mut self, check_mtime: bool) -> Self {
+        self.check_mtime = check_mtime;
+        self
+    }
+
+    /// Whether builds are restored from and stored in the output cache.
+    pub fn use_cache(mut self, use_cache: bool) -> Self {
+        self.use_cache = use_cache;
+        self
+    }
+
+    /// After a failure, keep building everything that doesn't depend on it, reporting all
+    /// failures at the end as [`Failures`].
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Whether a command that succeeds without rewriting its pre-existing output files is an
+    /// error. Output directories aren't checked.
+    pub fn strict_outputs(mut self, strict: bool) -> Self {
+        self.strict_outputs = strict;
+        self
+    }
+
+    /// Run each `build` in a [`Sandbox`] holding only its srcs and the outputs and srcs of other
+    /// targets it references, so reading undeclared files fails.
+    pub fn sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Whether builds restored from the cache show what their commands printed when they ran,
+    /// through [`Executor::replay`].
+    pub fn replay(mut self, replay: bool) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    /// Appends `args`, each quoted as a single word, to the last command `target` runs.
+    pub fn extra_args(mut self, target: TargetPath, args: Vec<String>) -> Self {
+        self.extra_args = Some((target, args));
+        self
+    }
+
+    /// Instead of running commands, records them for [`Builder::rehearsed`]. Outputs are assumed
+    /// to be at their declared paths. Builds one target at a time, so the order is stable.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// How long targets that have never been built are assumed to take when estimating how
+    /// long a build has left.
+    pub fn eta_default(mut self, eta_default: Duration) -> Self {
+        self.eta_default = eta_default;
+        self
+    }
+
+    /// How many commands may run at once, across targets and the items of foreach targets.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Builds every step, running any whose dependencies are built concurrently. After a
+    /// failure no new steps start, but those already running are allowed to finish.
+    pub fn execute(&self, plan: &Plan) -> eyre::Result<()> {
+        self.executor.reporter().begin_build(plan.steps.len());
+        let index = plan
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (&s.path, i))
+            .collect::<HashMap<_, _>>();
+        check_visibility(plan, &index)?;
+
+        let mut dependents = vec![Vec::new(); plan.steps.len()];
+        let mut waiting_on = vec![0; plan.steps.len()];
+        for (i, step) in plan.steps.iter().enumerate() {
+            let deps = step
+                .deps
+                .iter()
+                .chain(&step.def().after)
+                .collect::<HashSet<_>>();
+            for dep in deps {
+                let Some(&d) = index.get(dep) else { continue };
+                dependents[d].push(i);
+                waiting_on[i] += 1;
+            }
+        }
+
+        // Steps that `always` run, or depend on one that does, are rebuilt even if already built.
+        let mut forced = vec![false; plan.steps.len()];
+        for (i, step) in plan.steps.iter().enumerate() {
+            forced[i] = step.def().always
+                || step
+                    .deps
+                    .iter()
+                    .filter_map(|d| index.get(d))
+                    .any(|&d| forced[d]);
+        }
+
+        // Looked up once, so each finished step only subtracts its own from the remaining total.
+        let expected = plan
+            .steps
+            .iter()
+            .map(|s| self.timings.expected(&s.path, self.eta_default))
+            .collect::<Vec<_>>();
+        let remaining: Duration = expected.iter().sum();
+
+        let ready = (0..plan.steps.len()).filter(|&i| waiting_on[i] == 0);
+        let schedule = Mutex::new(Schedule {
+            ready: ready.collect(),
+            waiting_on,
+            running: 0,
+            finished: 0,
+            remaining,
+            error: None,
+            failures: Vec::new(),
+        });
+        let changed = Condvar::new();
+        self.executor.reporter().estimate(remaining);
+
+        let worker = || loop {
+            let next = {
+                let mut state = schedule.lock().unwrap();
+                loop {
+                    let all_done = state.finished == plan.steps.len();
+                    if state.error.is_some() || all_done {
+                        break None;
+                    }
+                    if let Some(i) = state.ready.pop_front() {
+                        state.running += 1;
+                        break Some(i);
+                    }
+                    if state.running == 0 {
+                        break None;
+                    }
+                    state = changed.wait(state).unwrap();
+                }
+            };
+            let Some(i) = next else { return };
+
+            let step = &plan.steps[i];
+            let remembered = !forced[i] && self.memo.contains(&step.path);
+            if !remembered {
+                self.slots.acquire(self.jobs);
+            }
+            let start = Instant::now();
+            let result = if remembered {
+                Ok(Status::Skipped)
+            } else {
+                let result = self.build(step);
+                self.took.insert(step.path.clone(), start.elapsed());
+                self.slots.release();
+                result
+            };
+            let status = *result.as_ref().unwrap_or(&Status::Failed);
+            if status == Status::Built && !self.dry_run && !self.touch {
+                self.timings.record(&step.path, start.elapsed());
+            }
+            self.executor
+                .reporter()
+                .finish_target(&step.path, status, start.elapsed());
+            if step.def().tags.contains(TEST_TAG) {
+                let failure = result.as_ref().err().map(|e| TestFailure {
+                    message: e.to_string(),
+                    stderr: self
+                        .failed_stderr
+                        .remove(&step.path)
+                        .map(|(_, stderr)| stderr)
+                        .unwrap_or_default(),
+                });
+                self.executor
+                    .reporter()
+                    .finish_test(&step.path, failure.as_ref());
+            }
+
+            let mut state = schedule.lock().unwrap();
+            state.running -= 1;
+            state.remaining = state.remaining.saturating_sub(expected[i]);
+            match result {
+                Ok(_) => {
+                    if self.memo.insert(step.path.clone()) {
+                        self.built.lock().unwrap().push(step.path.clone());
+                    }
+                    state.finished += 1;
+                    for &dependent in &dependents[i] {
+                        state.waiting_on[dependent] -= 1;
+                        if state.waiting_on[dependent] == 0 {
+                            state.ready.push_back(dependent);
+                        }
+                    }
+                }
+                // Dependents of a failed step are never ready, so they are skipped.
+                Err(e) if self.keep_going => state.failures.push((step.path.clone(), e)),
+                Err(e) => {
+                    state.error.get_or_insert(e);
+                }
+            }
+            changed.notify_all();
+            let remaining = state.remaining;
+            drop(state);
+            self.executor.reporter().estimate(remaining);
+        };
+
+        std::thread::scope(|scope| {
+            let jobs = if self.dry_run { 1 } else { self.jobs };
+            for _ in 0..jobs.min(plan.steps.len()) {
+                scope.spawn(worker);
+            }
+        });
+
+        let state = schedule.into_inner().unwrap();
+        let saved = self.fingerprints.save().and(self.timings.save());
+        if let Some(e) = state.error {
+            return Err(e);
+        }
+        if !state.failures.is_empty() {
+            let skipped = plan.steps.len() - state.finished - state.failures.len();
+            return Err(Failures {
+                failures: state.failures,
+                skipped,
+            }
+            .into());
+        }
+        saved
+    }
+
+    /// Commands that would have run during a dry run, with their targets, in order.
+    pub fn rehearsed(&self) -> Vec<(TargetPath, String)> {
+        self.rehearsed.lock().unwrap().clone()
+    }
+
+    /// Targets that have been successfully built, in the order they finished.
+    pub fn built(&self) -> Vec<TargetPath> {
+        self.built.lock().unwrap().clone()
+    }
+
+    /// The chain of dependencies in `plan` that took longest end to end, with how long each
+    /// target in it took. Targets this builder didn't build count as taking no time.
+    pub fn critical_path(&self, plan: &Plan) -> Vec<(TargetPath, Duration)> {
+        let index = plan
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (&s.path, i))
+            .collect::<HashMap<_, _>>();
+        let took = |i: usize| {
+            self.took
+                .get(&plan.steps[i].path)
+                .map_or(Duration::ZERO, |t| *t)
+        };
+
+        // Per step, the longest chain ending with it and the dependency before it in that chain.
+        // Steps are ordered dependencies first, so each dependency's chain is already known.
+        let mut chains = Vec::<(Duration, Option<usize>)>::with_capacity(plan.steps.len());
+        for (i, step) in plan.steps.iter().enumerate() {
+            let longest = step
+                .deps
+                .iter()
+                .filter_map(|d| index.get(d).copied())
+                .filter(|&d| d < i)
+                .max_by_key(|&d| chains[d].0);
+            let before = longest.map_or(Duration::ZERO, |d| chains[d].0);
+            chains.push((before + took(i), longest));
+        }
+
+        let mut path = Vec::new();
+        let mut current = (0..chains.len()).max_by_key(|&i| chains[i].0);
+        while let Some(i) = current {
+            path.push((plan.steps[i].path.clone(), took(i)));
+            current = chains[i].1;
+        }
+        path.reverse();
+        path
+    }
+
+    fn build(&self, step: &Step) -> eyre::Result<Status> {
+        let task = step.def();
+        let mut status = Status::Built;
+        let mut store_as = None;
+        let mut fingerprint = None;
+        match task {
+            TargetDef::Group(_) | TargetDef::Alias(_) => self.export(step)?,
+            _ if self.dry_run => self.rehearse(step)?,
+            _ if self.touch => {
+                self.touch_outputs(step)?;
+                // Cached and fingerprinted like a build that ran, so later runs skip it.
+                if let TargetDef::Build(build) = task {
+                    if !build.srcs.is_empty() && !task.outs.is_empty() {
+                        let key = self.input_key(step, build)?;
+                        store_as = Some((key.clone(), Logs::default())).filter(|_| self.use_cache);
+                        fingerprint = Some(key);
+                    }
+                }
+            }
+            TargetDef::HttpFile(http) => self.fetch(step, http)?,
+            TargetDef::Foreach(_) => self.fan_out(step)?,
+            TargetDef::Build(build) if build.srcs.is_empty() || task.outs.is_empty() => {
+                self.run_checked(step)?;
+            }
+            TargetDef::Build(_) if task.always => {
+                self.run_checked(step)?;
+            }
+            TargetDef::Build(build)
+                if self.check_mtime
+                    && self.extra_args_for(&step.path).is_empty()
+                    && self.is_up_to_date(step, build) =>
+            {
+                status = Status::Skipped
+            }
+            TargetDef::Build(build) => {
+                let key = self.input_key(step, build)?;
+                let unchanged = self.check_mtime
+                    && self.fingerprints.matches(&step.path, &key)
+                    && self.out_files(step).values().all(|f| f.exists());
+                if unchanged {
+                    status = Status::Skipped;
+                } else {
+                    match self.cache.lookup(&key).filter(|_| self.use_cache) {
+                        Some(hit) => {
+                            hit.restore(&self.out_files(step))?;
+                            if self.replay {
+                                let logs = &hit.logs;
+                                self.executor
+                                    .replay(&step.path, &logs.stdout, &logs.stderr)?;
+                            }
+                            status = Status::Cached;
+                        }
+                        None => {
+                            let logs = self.run_checked(step)?;
+                            store_as = Some((key.clone(), logs)).filter(|_| self.use_cache);
+                        }
+                    }
+                }
+                fingerprint = Some(key);
+            }
+            _ => {
+                self.run_checked(step)?;
+            }
+        }
+
+        for (name, out) in &task.outs {
+            let file = out.file(&self.root, &step.dir);
+            self.claim(&step.path, &file)?;
+            if self.dry_run {
+                self.outputs.insert(step.path.output(name), file);
+                continue;
+            }
+
+            if out.dir {
+                eyre::ensure!(
+                    file.is_dir(),
+                    "Missing output directory: {name} @ {}",
+                    file.display()
+                );
+            } else {
+                eyre::ensure!(
+                    file.exists(),
+                    "Missing output file: {name} @ {}",
+                    file.display()
+                );
+            }
+
+            // Touched outputs are placeholders, so their checksums can't match.
+            if let Some(expected) = out.sha256.as_ref().filter(|_| !self.touch) {
+                let actual = digest::sha256_file(&file)?;
+                eyre::ensure!(
+                    &actual == expected,
+                    "Checksum mismatch for {name} @ {}: expected sha256:{expected}, got sha256:{actual}",
+                    file.display()
+                );
+            }
+
+            if out.executable {
+                make_executable(&file)?;
+            }
+
+            self.outputs.insert(step.path.output(name), file);
+        }
+
+        if let Some((key, logs)) = store_as {
+            self.cache.store(&key, &self.out_files(step), &logs)?;
+        }
+        if let Some(key) = fingerprint {
+            self.fingerprints.record(&step.path, key);
+        }
+
+        Ok(status)
+    }
+
+    /// What executing `plan` would do with each of its builds, without running anything: reuse up
+    /// to date outputs, restore them from the cache, or run. Builds depending on one that will run
+    /// are assumed to run too, since their inputs will change. Outputs are registered as if built,
+    /// so the builder is used up.
+    pub fn forecast(self, plan: &Plan) -> eyre::Result<HashMap<TargetPath, Forecast>> {
+        let mut forecast = HashMap::new();
+        for step in &plan.steps {
+            self.assume_built(step)?;
+            let task = step.def();
+            let TargetDef::Build(build) = task else {
+                continue;
+            };
+            let dep_runs = step
+                .deps
+                .iter()
+                .any(|d| forecast.get(d) == Some(&Forecast::Run));
+            let status = if dep_runs || build.srcs.is_empty() || task.outs.is_empty() || task.always
+            {
+                Forecast::Run
+            } else if self.check_mtime
+                && self.extra_args_for(&step.path).is_empty()
+                && self.is_up_to_date(step, build)
+            {
+                Forecast::UpToDate
+            } else {
+                // Inputs that can't be read yet, like outputs of tasks that haven't run, can only
+                // be produced by running.
+                match self.input_key(step, build) {
+                    Ok(key)
+                        if self.check_mtime
+                            && self.fingerprints.matches(&step.path, &key)
+                            && self.out_files(step).values().all(|f| f.exists()) =>
+                    {
+                        Forecast::UpToDate
+                    }
+                    Ok(key) if self.use_cache && self.cache.lookup(&key).is_some() => {
+                        Forecast::Cached
+                    }
+                    _ => Forecast::Run,
+                }
+            };
+            forecast.insert(step.path.clone(), status);
+        }
+        Ok(forecast)
+    }
+
+    /// Registers the step's outputs as if it had been built, without running anything, so later
+    /// steps' commands can be resolved.
+    pub fn assume_built(&self, step: &Step) -> eyre::Result<()> {
+        let task = step.def();
+        if matches!(task, TargetDef::Group(_) | TargetDef::Alias(_)) {
+            self.export(step)?;
+        }
+        for (name, out) in &task.outs {
+            let file = out.file(&self.root, &step.dir);
+            self.outputs.insert(step.path.output(name), file);
+        }
+        Ok(())
+    }
+
+    /// Runs the step's commands, requiring they succeed and, with `strict_outputs`, that they
+    /// rewrote every output file that already existed. Returns what they printed.
+    fn run_checked(&self, step: &Step) -> eyre::Result<Logs> {
+        let files = step
+            .def()
+            .outs
+            .iter()
+            .filter(|(_, out)| self.strict_outputs && !out.dir)
+            .map(|(name, out)| (name, out.file(&self.root, &step.dir)))
+            .collect::<Vec<_>>();
+        let before = files
+            .iter()
+            .map(|(_, file)| modified(file))
+            .collect::<Vec<_>>();
+
+        let output = self.run(step)?;
+        self.ensure_success(step, &output)?;
+
+        for ((name, file), before) in files.iter().zip(before) {
+            eyre::ensure!(
+                before.is_none() || modified(file) != before,
+                "{} succeeded without writing output {name} @ {}",
+                step.path,
+                file.display()
+            );
+        }
+        Ok(Logs {
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+
+    /// Records `target` as producing `file`, erroring if a different target already does.
+    fn claim(&self, target: &TargetPath, file: &Path) -> eyre::Result<()> {
+        let producer = self
+            .producers
+            .entry(file.to_path_buf())
+            .or_insert_with(|| target.clone());
+        eyre::ensure!(
+            *producer == *target,
+            "Output collision: {} and {target} both produce {}",
+            *producer,
+            file.display()
+        );
+        Ok(())
+    }
+
+    fn out_files(&self, step: &Step) -> BTreeMap<String, PathBuf> {
+        step.def()
+            .outs
+            .iter()
+            .map(|(name, out)| (name.clone(), out.file(&self.root, &step.dir)))
+            .collect()
+    }
+
+    /// Cache key covering everything that affects a build's outputs: its command, environment,
+    /// host requirement, and the contents of its srcs and any outputs it references.
+    fn input_key(&self, step: &Step, build: &Build) -> eyre::Result<String> {
+        let task = step.def();
+        let mut runs_on = build.runs_on.iter().collect::<Vec<_>>();
+        runs_on.sort();
+        let mut inputs = vec![
+            format!("command {:?}", self.commands(step)?),
+            format!("env {:?}", task.env),
+            format!("runs_on {runs_on:?}"),
+            format!("arch {:?}", build.arch),
+            format!("shell {:?}", task.shell),
+            format!("workdir {:?}", task.workdir),
+        ];
+
+        let mut srcs = build.srcs.iter().collect::<Vec<_>>();
+        srcs.sort();
+        for src in srcs {
+            inputs.push(cache::describe_input(&step.dir.join(src))?);
+        }
+
+        for file in self.referenced_files(step) {
+            inputs.push(cache::describe_input(&file)?);
+        }
+
+        Ok(self.cache_key(&inputs.join("\n")))
+    }
+
+    fn is_up_to_date(&self, step: &Step, build: &Build) -> bool {
+        build.is_up_to_date(&self.root, &step.dir, &self.referenced_files(step))
+    }
+
+    /// Already built outputs of other targets that the step's command references.
+    fn referenced_files(&self, step: &Step) -> Vec<PathBuf> {
+        let cmd = &step.def().cmd;
+        cmd.iter()
+            .flat_map(|c| c.outputs().chain(c.fallbacks().flatten()))
+            .filter_map(|o| self.outputs.get(o).map(|f| f.clone()))
+            .collect()
+    }
+
+    fn rehearse(&self, step: &Step) -> eyre::Result<()> {
+        let task = step.def();
+        for command in self.commands(step)? {
+            let output = self.executor.execute(Execution {
+                path: &step.path,
+                command: &command,
+                dir: &self.workdir(step),
+                runs_on: task.as_build().map(|b| &b.runs_on),
+                arch: task.as_build().and_then(|b| b.arch.as_ref()),
+                env: Some(&task.env),
+                timeout: task.timeout,
+                dry_run: true,
+                shell: task.shell.as_deref(),
+                stdin: None,
+                argv: None,
+            })?;
+            self.ensure_success(step, &output)?;
+            self.rehearsed
+                .lock()
+                .unwrap()
+                .push((step.path.clone(), command));
+        }
+        Ok(())
+    }
+
+    fn touch_outputs(&self, step: &Step) -> eyre::Result<()> {
+        for out in step.def().outs.values() {
+            let file = out.file(&self.root, &step.dir);
+            if out.dir {
+                std::fs::create_dir_all(&file)?;
+                std::fs::File::open(&file)?.set_modified(SystemTime::now())?;
+                continue;
+            }
+            if let Some(parent) = file.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&file)?
+                .set_modified(SystemTime::now())?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers the outputs a group or alias exposes under its own name.
+    fn export(&self, step: &Step) -> eyre::Result<()> {
+        for (output, file) in step.exports(&self.outputs)? {
+            self.outputs.insert(output, file);
+        }
+        Ok(())
+    }
+
+    /// Runs the commands for every item, on this step's job slot and any others free when it
+    /// starts. The first item to fail stops the rest from starting.
+    fn fan_out(&self, step: &Step) -> eyre::Result<()> {
+        let scripts = self.scripts(step, None)?;
+        let queue = Mutex::new(scripts.iter());
+        let work = || loop {
+            let Some(commands) = queue.lock().unwrap().next() else {
+                return Ok(());
+            };
+            let result = self
+                .run_script(step, commands, &self.workdir(step), None)
+                .and_then(|output| self.ensure_success(step, &output));
+            if let Err(e) = result {
+                *queue.lock().unwrap() = [].iter();
+                return Err(e);
+            }
+        };
+
+        std::thread::scope(|scope| {
+            let mut helpers = Vec::new();
+            while helpers.len() + 1 < scripts.len() && self.slots.try_acquire(self.jobs) {
+                helpers.push(scope.spawn(move || {
+                    let result = work();
+                    self.slots.release();
+                    result
+                }));
+            }
+
+            let result = work();
+            helpers
+                .into_iter()
+                .map(|helper| helper.join().expect("item panicked"))
+                .fold(result, Result::and)
+        })
+    }
+
+    /// Downloads into a cache shared between runs, so each file is only fetched once.
+    fn fetch(&self, step: &Step, http: &HttpFile) -> eyre::Result<()> {
+        let key = self.cache_key(&format!("{}\n{}", http.url, http.sha256));
+        let cached = self.root.join(".ffs/downloads").join(key);
+
+        if !cached.exists() {
+            std::fs::create_dir_all(cached.parent().expect("cached is file"))?;
+
+            let partial = cached.with_extension("partial");
+            let partial_str = partial
+                .to_str()
+                .ok_or_eyre(format!("Path not utf8 {}", partial.display()))?;
+            let command = format!("curl -fsSL -o {} {}", quote(partial_str), quote(&http.url));
+
+            let output = self.spawn(
+                Execution {
+                    path: &step.path,
+                    command: &command,
+                    dir: &step.dir,
+                    runs_on: None,
+                    arch: None,
+                    env: None,
+                    timeout: None,
+                    dry_run: false,
+                    shell: None,
+                    stdin: None,
+                    argv: None,
+                },
+                || hash_existing([("default", partial.clone())]),
+            )?;
+            self.ensure_success(step, &output)?;
+
+            let actual = digest::sha256_file(&partial)?;
+            eyre::ensure!(
+                actual == http.sha256,
+                "Checksum mismatch downloading {}: expected sha256:{}, got sha256:{actual}",
+                http.url,
+                http.sha256
+            );
+            std::fs::rename(&partial, &cached)?;
+        }
+
+        let out = http.common.outs["default"].file(&self.root, &step.dir);
+        std::fs::copy(&cached, &out)?;
+
+        Ok(())
+    }
+
+    fn cache_key(&self, contents: &str) -> String {
+        match &self.cache_namespace {
+            Some(namespace) => digest::sha256(format!("{namespace}\n{contents}")),
+            None => digest::sha256(contents),
+        }
+    }
+
+    /// The shell commands a step runs, fully resolved against the outputs built so far. Empty for
+    /// steps that don't run commands of their own.
+    pub fn commands(&self, step: &Step) -> eyre::Result<Vec<String>> {
+        Ok(self.scripts(step, None)?.into_iter().flatten().collect())
+    }
+
+    /// Like [`Self::commands`], grouped into the separate runs of a step. Each run is one or more
+    /// commands to execute in order.
+    fn scripts(&self, step: &Step, sandbox: Option<&Sandbox>) -> eyre::Result<Vec<Vec<String>>> {
+        let task = step.def();
+        if task.argv {
+            let argv = self.argv_in(step, sandbox)?;
+            return Ok(vec![vec![command::render_argv(&argv)]]);
+        }
+        let staged = sandbox
+            .map(|sandbox| self.staged_references(step, sandbox))
+            .transpose()?;
+        let (outputs, sources) = match &staged {
+            Some((outputs, sources)) => (outputs, sources),
+            None => (&self.outputs, &step.sources),
+        };
+
+        let mut scripts: Vec<Vec<String>> = match task {
+            TargetDef::HttpFile(_) | TargetDef::Group(_) | TargetDef::Alias(_) => Vec::new(),
+            TargetDef::Foreach(foreach) => foreach
+                .items
+                .iter()
+                .map(|item| {
+                    task.cmd
+                        .iter()
+                        .map(|c| c.as_item_sh(outputs, sources, item))
+                        .collect()
+                })
+                .collect::<eyre::Result<_>>()?,
+            TargetDef::Build(build) => {
+                let mut outs = task.outs.iter().collect::<Vec<_>>();
+                outs.sort_by_key(|(name, _)| *name);
+                let outs = outs
+                    .into_iter()
+                    .map(|(_, out)| {
+                        let file = out.file(&self.root, &step.dir);
+                        match sandbox {
+                            Some(sandbox) => sandbox.path(&file),
+                            None => Ok(file),
+                        }
+                    })
+                    .collect::<eyre::Result<Vec<_>>>()?;
+
+                // Srcs are relative to the package, so are spelled out in full for commands
+                // running elsewhere.
+                let srcs = match &task.workdir {
+                    None => build.srcs.clone(),
+                    Some(_) => {
+                        let dir = match sandbox {
+                            Some(sandbox) => sandbox.path(&step.dir)?,
+                            None => step.dir.clone(),
+                        };
+                        build
+                            .srcs
+                            .iter()
+                            .map(|src| dir.join(src).to_string_lossy().into_owned())
+                            .collect()
+                    }
+                };
+                let srcs = srcs.iter().map(String::as_str).collect::<Vec<_>>();
+                let outs = outs
+                    .iter()
+                    .map(|o| {
+                        o.to_str()
+                            .ok_or_eyre(format!("Path not utf8 {}", o.display()))
+                    })
+                    .collect::<eyre::Result<Vec<_>>>()?;
+                vec![task
+                    .cmd
+                    .iter()
+                    .map(|c| c.as_build_sh(outputs, sources, &srcs, &outs))
+                    .collect::<eyre::Result<_>>()?]
+            }
+            TargetDef::Task(_) => vec![task
+                .cmd
+                .iter()
+                .map(|c| c.as_sh(outputs, sources))
+                .collect::<eyre::Result<_>>()?],
+        };
+
+        let extra = self.extra_args_for(&step.path);
+        if !extra.is_empty() {
+            let quoted = extra.iter().map(|a| quote(a)).collect::<Vec<_>>().join(" ");
+            for last in scripts.iter_mut().filter_map(|s| s.last_mut()) {
+                last.truncate(last.trim_end().len());
+                last.push(' ');
+                last.push_str(&quoted);
+            }
+        }
+        Ok(scripts)
+    }
+
+    /// The program and arguments of a step run without a shell, followed by any extra args.
+    pub fn argv(&self, step: &Step) -> eyre::Result<Vec<String>> {
+        self.argv_in(step, None)
+    }
+
+    /// Like [`Self::argv`], with references resolved inside `sandbox` if there is one.
+    fn argv_in(&self, step: &Step, sandbox: Option<&Sandbox>) -> eyre::Result<Vec<String>> {
+        let staged = sandbox
+            .map(|sandbox| self.staged_references(step, sandbox))
+            .transpose()?;
+        let (outputs, sources) = match &staged {
+            Some((outputs, sources)) => (outputs, sources),
+            None => (&self.outputs, &step.sources),
+        };
+        let mut args = step
+            .def()
+            .cmd
+            .iter()
+            .map(|c| c.as_arg(outputs, sources))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        args.extend(self.extra_args_for(&step.path).iter().cloned());
+        Ok(args)
+    }
+
+    /// The built output the step's commands read as stdin, if it has one.
+    pub fn stdin(&self, step: &Step) -> eyre::Result<Option<PathBuf>> {
+        step.def()
+            .stdin
+            .as_ref()
+            .map(|o| {
+                self.outputs
+                    .get(o)
+                    .map(|file| file.clone())
+                    .ok_or_eyre(format!("Missing output {o}"))
+            })
+            .transpose()
+    }
+
+    /// Where the step's commands run: its `workdir`, or else its package directory.
+    pub fn workdir(&self, step: &Step) -> PathBuf {
+        match step.def().workdir.as_deref() {
+            Some(dir) => match dir.strip_prefix("//") {
+                Some(from_root) => self.root.join(from_root),
+                None => step.dir.join(dir),
+            },
+            None => step.dir.clone(),
+        }
+    }
+
+    fn extra_args_for(&self, target: &TargetPath) -> &[String] {
+        match &self.extra_args {
+            Some((t, args)) if t == target => args,
+            _ => &[],
+        }
+    }
+
+    /// The outputs and srcs a step references, at the places [`Self::stage`] put them in
+    /// `sandbox`, so commands never touch the workspace copies.
+    fn staged_references(
+        &self,
+        step: &Step,
+        sandbox: &Sandbox,
+    ) -> eyre::Result<(DashMap<Output, PathBuf>, HashMap<Source, PathBuf>)> {
+        let outputs = DashMap::new();
+        for output in step
+            .def()
+            .cmd
+            .iter()
+            .flat_map(|c| c.outputs().chain(c.fallbacks().flatten()))
+        {
+            if let Some(file) = self.outputs.get(output) {
+                outputs.insert(output.clone(), sandbox.path(&file)?);
+            }
+        }
+        let sources = step
+            .sources
+            .iter()
+            .map(|(source, file)| Ok((source.clone(), sandbox.path(file)?)))
+            .collect::<eyre::Result<_>>()?;
+        Ok((outputs, sources))
+    }
+
+    fn run(&self, step: &Step) -> eyre::Result<std::process::Output> {
+        let task = step.def();
+        let sandbox = match task.as_build() {
+            Some(build) if self.sandbox => Some(self.stage(step, build)?),
+            _ => None,
+        };
+        let commands = self.scripts(step, sandbox.as_ref())?.remove(0);
+        let dir = match &sandbox {
+            Some(sandbox) => sandbox.path(&self.workdir(step))?,
+            None => self.workdir(step),
+        };
+        let argv = match task.argv {
+            true => Some(self.argv_in(step, sandbox.as_ref())?),
+            false => None,
+        };
+        let output = self.run_script(step, &commands, &dir, argv.as_deref())?;
+        if let Some(sandbox) = sandbox.filter(|_| output.status.success()) {
+            for file in self.out_files(step).values() {
+                sandbox.extract(file)?;
+            }
+        }
+        Ok(output)
+    }
+
+    /// Runs `commands` in order the way each of the step's runs goes, retrying as it says.
+    fn run_script(
+        &self,
+        step: &Step,
+        commands: &[String],
+        dir: &Path,
+        argv: Option<&[String]>,
+    ) -> eyre::Result<std::process::Output> {
+        let task = step.def();
+        let stdin = self.stdin(step)?;
+        let execution = Execution {
+            path: &step.path,
+            command: "",
+            dir,
+            runs_on: task.as_build().map(|b| &b.runs_on),
+            arch: task.as_build().and_then(|b| b.arch.as_ref()),
+            env: Some(&task.env),
+            timeout: task.timeout,
+            dry_run: false,
+            shell: task.shell.as_deref(),
+            stdin: stdin.as_deref(),
+            argv,
+        };
+        let lock = task
+            .lock
+            .as_ref()
+            .map(|name| Arc::clone(&self.locks.entry(name.clone()).or_default()));
+        let _held = lock.as_ref().map(|l| l.lock().unwrap());
+
+        let mut attempt = 0;
+        loop {
+            let output = in_order(commands, |command| {
+                let execution = Execution {
+                    command,
+                    ..execution
+                };
+                self.spawn(execution, || {
+                    hash_existing(
+                        task.outs
+                            .iter()
+                            .map(|(name, out)| (name.as_str(), out.file(&self.root, &step.dir))),
+                    )
+                })
+            })?;
+
+            if !task.retry.should_retry(attempt, &output.status) {
+                return Ok(output);
+            }
+            std::thread::sleep(task.retry.backoff(attempt));
+            attempt += 1;
+            self.executor.reporter().retry(&step.path, attempt);
+        }
+    }
+
+    /// A sandbox for `build` with its srcs, copies of the outputs and srcs it references, and the
+    /// directories its outputs go in.
+    fn stage(&self, step: &Step, build: &Build) -> eyre::Result<Sandbox> {
+        let sandbox = Sandbox::new(&self.root, &step.path)?;
+        for src in &build.srcs {
+            sandbox.link(&step.dir.join(src))?;
+        }
+        for file in self
+            .referenced_files(step)
+            .iter()
+            .chain(step.sources.values())
+        {
+            sandbox.copy(file)?;
+        }
+
+        std::fs::create_dir_all(sandbox.path(&step.dir)?)?;
+        // A missing workdir is left missing, so running in it fails like it would unsandboxed.
+        let workdir = self.workdir(step);
+        if workdir.is_dir() {
+            std::fs::create_dir_all(sandbox.path(&workdir)?)?;
+        }
+        for file in self.out_files(step).values() {
+            if let Some(parent) = sandbox.path(file)?.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        Ok(sandbox)
+    }
+
+    fn ensure_success(&self, step: &Step, output: &std::process::Output) -> eyre::Result<()> {
+        if output.status.success() {
+            return Ok(());
+        }
+        if step.def().tags.contains(TEST_TAG) {
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            self.failed_stderr.insert(step.path.clone(), stderr);
+        }
+
+        // Streamed output has already been shown as it was produced, possibly interleaved with
+        // other targets, so only the end of stderr is repeated with the error.
+        if self.executor.is_streaming() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let tail = stderr
+                .lines()
+                .rev()
+                .take(FAILURE_TAIL_LINES)
+                .collect::<Vec<_>>();
+            if !tail.is_empty() {
+                let tail = tail.into_iter().rev().collect::<Vec<_>>().join("\n");
+                eyre::bail!("Task failed: {}, ending with:\n{tail}", step.path);
+            }
+        } else {
+            self.executor.show_captured(output)?;
+        }
+        eyre::bail!("Task failed: {}", step.path);
+    }
+
+    /// Executes a command, recording it in the audit log along with the hashes of `outputs`.
+    fn spawn(
+        &self,
+        execution: Execution,
+        outputs: impl FnOnce() -> eyre::Result<BTreeMap<String, String>>,
+    ) -> eyre::Result<std::process::Output> {
+        let Some(log) = &self.audit_log else {
+            return self.executor.execute(execution);
+        };
+
+        let start = SystemTime::now();
+        let output = self.executor.execute(execution)?;
+        let end = SystemTime::now();
+
+        let env = self.executor.env_for(execution.env);
+        log.record(&Record {
+            target: execution.path,
+            command: execution.command,
+            workdir: execution.dir,
+            env: env.keys().map(String::as_str).collect(),
+            start_ms: audit::unix_ms(start),
+            end_ms: audit::unix_ms(end),
+            exit_status: output.status.code(),
+            outputs: outputs()?,
+        })?;
+
+        Ok(output)
+    }
+}
+
+/// Ensures every step may depend on each of its dependencies, per their `visibility`.
+fn check_visibility(plan: &Plan, index: &HashMap<&TargetPath, usize>) -> eyre::Result<()> {
+    for step in &plan.steps {
+        for dep in &step.deps {
+            let Some(&d) = index.get(dep) else { continue };
+            let def = plan.steps[d].def();
+            if def.visible_to(dep, &step.path, &step.def().tags) {
+                continue;
+            }
+            let allowed = def.visibility.iter().flatten().map(|s| s.to_string());
+            eyre::bail!(
+                "{} can't depend on {dep}, which outside its package is only visible to [{}]",
+                step.path,
+                allowed.collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Runs `commands` one after another, stopping at the first that fails. The output holds
+/// everything the commands that ran printed, and the status of the last of them.
+fn in_order(
+    commands: &[String],
+    mut run: impl FnMut(&str) -> eyre::Result<std::process::Output>,
+) -> eyre::Result<std::process::Output> {
+    let mut combined = std::process::Output {
+        status: ExitStatus::default(),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+    };
+    for command in commands {
+        let output = run(command)?;
+        combined.status = output.status;
+        combined.stdout.extend(output.stdout);
+        combined.stderr.extend(output.stderr);
+        if !combined.status.success() {
+            break;
+        }
+    }
+    Ok(combined)
+}
+
+fn hash_existing<'n>(
+    files: impl IntoIterator<Item = (&'n str, PathBuf)>,
+) -> eyre::Result<BTreeMap<String, String>> {
+    files
+        .into_iter()
+        .filter(|(_, file)| file.is_file())
+        .map(|(name, file)| Ok((name.to_string(), digest::sha256_file(&file)?)))
+        .collect()
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    std::fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+fn make_executable(file: &Path) -> eyre::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut permissions = std::fs::metadata(file)?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        std::fs::set_permissions(file, permissions)?;
+    }
+    #[cfg(not(unix))]
+    let _ = file;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        reporting::{Quiet, Reporter},
+        starlark::Reader,
+        testing::workspace,
+    };
+
+    fn build(root: &Path, targets: &[&str]) -> eyre::Result<()> {
+        build_with(root, targets, |builder| builder)
+    }
+
+    /// Like [`build`], with the builder adjusted by `configure` first.
+    fn build_with(
+        root: &Path,
+        targets: &[&str],
+        configure: impl FnOnce(Builder) -> Builder,
+    ) -> eyre::Result<()> {
+        configure(quiet_builder(root)).execute(&plan(root, targets)?)
+    }
+
+    fn plan(root: &Path, targets: &[&str]) -> eyre::Result<Plan> {
+        let targets = targets
+            .iter()
+            .map(|t| t.parse())
+            .collect::<eyre::Result<Vec<TargetPath>>>()?;
+        Plan::new(&Reader::new(root), root, &targets)
+    }
+
+    fn quiet_builder(root: &Path) -> Builder {
+        Builder::new(Arc::new(Executor::new(Arc::new(Quiet))), root)
+    }
+
+    #[test]
+    fn root_relative_output() {
+        let dir = workspace([
+            (
+                "gen/FFS",
+                r#"task("gen", "mkdir -p ../shared && echo hi > ../shared/file", outs = {"file": "//shared/file"})"#,
+            ),
+            (
+                "use/FFS",
+                r#"task("use", "cp //gen/gen:file copied", outs = {"copied": "copied"})"#,
+            ),
+        ]);
+
+        build(dir.path(), &["//use/use"]).unwrap();
+
+        let copied = std::fs::read_to_string(dir.path().join("use/copied")).unwrap();
+        assert_eq!(copied, "hi\n");
+    }
+
+    #[test]
+    fn cross_directory_output_reference() {
+        let dir = workspace([
+            (
+                "a/FFS",
+                r#"task("a", "cp //b/c:out copied", outs = {"copied": "copied"})"#,
+            ),
+            (
+                "b/FFS",
+                r#"task("c", "basename $(pwd) > out", outs = {"out": "out"})"#,
+            ),
+        ]);
+
+        build(dir.path(), &["//a/a"]).unwrap();
+
+        assert!(dir.path().join("b/out").exists());
+        assert!(!dir.path().join("a/out").exists());
+
+        let copied = std::fs::read_to_string(dir.path().join("a/copied")).unwrap();
+        assert_eq!(copied, "b\n");
+    }
+
+    #[test]
+    fn visibility_allows_listed_packages() {
+        let dir = workspace([
+            (
+                "lib/FFS",
+                r#"
+task("core", "echo core > out", outs = {"out": "out"}, visibility = ["//app/..."])
+task("local", "cat %/core:out", visibility = [])
+"#,
+            ),
+            ("app/FFS", r#"task("main", "cat //lib/core:out")"#),
+            ("other/FFS", r#"task("main", "cat //lib/core:out")"#),
+        ]);
+
+        build(dir.path(), &["//app/main", "//lib/local"]).unwrap();
+
+        let e = build(dir.path(), &["//other/main"]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "//other/main can't depend on //lib/core, which outside its package is only visible \
+             to [//app/...]"
+        );
+    }
+
+    #[test]
+    fn stdin_from_output() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+build("a", "printf 'one\ntwo\n' > a.txt", [], {"out": "a.txt"})
+task("b", "cat > seen", stdin = "//a:out")
+"#,
+        )]);
+        let root = dir.path();
+
+        build(root, &["//b"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("seen")).unwrap(),
+            "one\ntwo\n"
+        );
+    }
+
+    #[test]
+    fn argv_commands_resolve_references_without_a_shell() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+build("b", "echo b > b.txt", [], {"out": "b.txt"})
+task("a", ["sh", "-c", "printf '%s|%s' \"$1\" \"$2\" > seen", "sh", "$HOME", "//b:out"], argv = True)
+"#,
+        )]);
+        let root = dir.path();
+
+        build(root, &["//a"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("seen")).unwrap(),
+            format!("$HOME|{}", root.join("b.txt").display())
+        );
+    }
+
+    #[test]
+    fn workdir_overrides_package_dir() {
+        let dir = workspace([
+            ("FFS", ""),
+            (
+                "pkg/FFS",
+                r#"
+task("sub", "pwd > ../from_sub", workdir = "sub")
+task("root", "pwd > pkg/from_root", workdir = "//")
+task("missing", "true", workdir = "nowhere")
+"#,
+            ),
+            ("pkg/sub/keep", ""),
+        ]);
+        let root = dir.path().canonicalize().unwrap();
+        let pwd = |file: &str| std::fs::read_to_string(root.join(file)).unwrap();
+
+        build(&root, &["//pkg/sub", "//pkg/root"]).unwrap();
+        let sub = root.join("pkg/sub");
+        assert_eq!(pwd("pkg/from_sub").trim_end(), sub.to_str().unwrap());
+        assert_eq!(pwd("pkg/from_root").trim_end(), root.to_str().unwrap());
+
+        let err = build(&root, &["//pkg/missing"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Working directory {} of //pkg/missing does not exist",
+                root.join("pkg/nowhere").display()
+            )
+        );
+    }
+
+    #[test]
+    fn output_collision() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "echo a > out.txt", outs = {"out": "out.txt"})
+task("b", "echo b > out.txt", outs = {"out": "out.txt"})
+"#,
+        )]);
+
+        let e = build(dir.path(), &["//a", "//b"]).unwrap_err().to_string();
+        assert!(e.contains("Output collision: "), "{e}");
+        assert!(e.contains("//a") && e.contains("//b"), "{e}");
+        assert!(e.contains("out.txt"), "{e}");
+    }
+
+    #[test]
+    fn strict_outputs_must_be_rewritten() {
+        let dir = workspace([
+            ("FFS", r#"task("a", "true", outs = {"out": "out"})"#),
+            ("out", "stale"),
+        ]);
+        let root = dir.path();
+
+        let build = |strict| build_with(root, &["//a"], |b| b.strict_outputs(strict));
+
+        build(false).unwrap();
+        let e = build(true).unwrap_err().to_string();
+        assert!(
+            e.contains("//a succeeded without writing output out @ "),
+            "{e}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sandbox_hides_undeclared_files() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"
+build("declared", "cat input > out", ["input"], {"out": "out"})
+build("undeclared", "cat input sibling > both", ["input"], {"out": "both"})
+"#,
+            ),
+            ("input", "in\n"),
+            ("sibling", "sib\n"),
+        ]);
+        let root = dir.path();
+
+        let sandboxed = |target| build_with(root, &[target], |b| b.sandbox(true));
+
+        sandboxed("//declared").unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("out")).unwrap(), "in\n");
+
+        let e = sandboxed("//undeclared").unwrap_err().to_string();
+        assert!(e.contains("Task failed: //undeclared"), "{e}");
+        assert!(!root.join("both").exists());
+        build(root, &["//undeclared"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("both")).unwrap(),
+            "in\nsib\n"
+        );
+        assert!(!root.join(".ffs/sandbox").read_dir().unwrap().any(|_| true));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sandbox_resolves_references_inside_it() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+build("dep", "echo dep > dep.txt", [], {"out": "dep.txt"})
+build("scribble", "echo changed > //dep:out && cat //dep:out > seen.txt", [], {"out": "seen.txt"})
+"#,
+        )]);
+        let root = dir.path();
+
+        build_with(root, &["//scribble"], |b| b.sandbox(true)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("seen.txt")).unwrap(),
+            "changed\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("dep.txt")).unwrap(),
+            "dep\n"
+        );
+    }
+
+    #[test]
+    fn alias_builds_actual() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"
+alias("short", "//lib/target")
+alias("shorter", "%/short")
+task("use", "cat //shorter:out > copied", outs = {"copied": "copied"})
+"#,
+            ),
+            (
+                "lib/FFS",
+                r#"task("target", "echo built > out", outs = {"out": "out"})"#,
+            ),
+        ]);
+        let root = dir.path();
+
+        build(root, &["//shorter"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("lib/out")).unwrap(),
+            "built\n"
+        );
+
+        build(root, &["//use"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("copied")).unwrap(),
+            "built\n"
+        );
+    }
+
+    #[test]
+    fn matching_checksum() {
+        let dir = workspace([(
+            "FFS",
+            r#"task("a", "echo hi > out", outs = {"out": ("out", "sha256:98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4")})"#,
+        )]);
+
+        build(dir.path(), &["//a"]).unwrap();
+    }
+
+    #[test]
+    fn mismatching_checksum() {
+        let dir = workspace([(
+            "FFS",
+            r#"task("a", "echo bye > out", outs = {"out": ("out", "sha256:98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4")})"#,
+        )]);
+
+        let err = build(dir.path(), &["//a"]).unwrap_err();
+        assert!(
+            err.to_string().contains("Checksum mismatch for out"),
+            "{err}"
+        );
+    }
+
+    /// Serves `body` to every request, counting how many were made.
+    fn serve(body: &'static str) -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::{
+            io::{Read, Write},
+            net::TcpListener,
+            sync::atomic::Ordering,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let counter = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0; 4096]);
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[test]
+    fn http_file_downloads_once() {
+        let (url, requests) = serve("hi\n");
+        let ffs = format!(
+            r#"http_file("dl", "{url}", "sha256:98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4", "file")"#
+        );
+        let dir = workspace([("FFS", ffs.as_str())]);
+
+        build(dir.path(), &["//dl"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("file")).unwrap(),
+            "hi\n"
+        );
+
+        std::fs::remove_file(dir.path().join("file")).unwrap();
+        build(dir.path(), &["//dl"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("file")).unwrap(),
+            "hi\n"
+        );
+
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn cache_namespaces_are_independent() {
+        let (url, requests) = serve("hi\n");
+        let ffs = format!(
+            r#"http_file("dl", "{url}", "sha256:98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4", "file")"#
+        );
+        let dir = workspace([("FFS", ffs.as_str())]);
+        let root = dir.path();
+
+        let build_in = |namespace: &str| {
+            build_with(root, &["//dl"], |b| b.cache_namespace(namespace)).unwrap()
+        };
+
+        build_in("main");
+        build_in("feature");
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        build_in("main");
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn http_file_checksum_mismatch() {
+        let (url, _) = serve("bye\n");
+        let ffs = format!(
+            r#"http_file("dl", "{url}", "sha256:98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4", "file")"#
+        );
+        let dir = workspace([("FFS", ffs.as_str())]);
+
+        let err = build(dir.path(), &["//dl"]).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{err}");
+    }
+
+    #[test]
+    fn group_builds_members() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"
+group("all", ["//a/one", "//b/two"])
+task("use", "cat //all:one-out //all:two-out > both", outs = {"both": "both"})
+"#,
+            ),
+            (
+                "a/FFS",
+                r#"task("one", "echo 1 > out", outs = {"out": "out"})"#,
+            ),
+            (
+                "b/FFS",
+                r#"task("two", "echo 2 > out", outs = {"out": "out"})"#,
+            ),
+        ]);
+
+        build(dir.path(), &["//all"]).unwrap();
+        assert!(dir.path().join("a/out").exists());
+        assert!(dir.path().join("b/out").exists());
+
+        build(dir.path(), &["//use"]).unwrap();
+        let both = std::fs::read_to_string(dir.path().join("both")).unwrap();
+        assert_eq!(both, "1\n2\n");
+    }
+
+    #[test]
+    fn group_runs_members_once_and_nothing_itself() {
+        #[derive(Default)]
+        struct Began(Mutex<Vec<String>>);
+        impl Reporter for Began {
+            fn begin_execute(&self, task: &TargetPath) {
+                self.0.lock().unwrap().push(task.to_string());
+            }
+        }
+
+        let dir = workspace([(
+            "FFS",
+            r#"
+group("all", targets = ["//one", "//two", "//both"])
+group("nested", ["//all", "//one"])
+task("one", "echo x >> attempts")
+task("two", "echo x >> attempts", prereqs = ["//one"])
+task("both", "echo x >> attempts", prereqs = ["//one", "//two"])
+"#,
+        )]);
+        let root = dir.path();
+        let reporter = Arc::new(Began::default());
+
+        let executor = Arc::new(Executor::new(reporter.clone()));
+        Builder::new(executor, root)
+            .execute(&plan(root, &["//nested"]).unwrap())
+            .unwrap();
+
+        assert_eq!(attempts(root), 3);
+        let mut began = reporter.0.lock().unwrap().clone();
+        began.sort();
+        assert_eq!(began, ["//both", "//one", "//two"]);
+    }
+
+    #[test]
+    fn always_targets_are_never_skipped() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"
+build("normal", "echo x >> normal_runs; cp in normal_out", ["in"], {"out": "normal_out"})
+build("phony", "echo x >> phony_runs; cp in phony_out", ["in"], {"out": "phony_out"}, always = True)
+task("deploy", "echo x >> deploy_runs", prereqs = ["//phony"])
+"#,
+            ),
+            ("in", "in\n"),
+        ]);
+        let root = dir.path();
+        let runs = |name: &str| {
+            std::fs::read_to_string(root.join(name))
+                .unwrap_or_default()
+                .lines()
+                .count()
+        };
+
+        let targets = ["//normal", "//deploy"];
+        let plan = plan(root, &targets).unwrap();
+
+        // Built targets are remembered, like across watch cycles.
+        let watching = quiet_builder(root);
+        watching.execute(&plan).unwrap();
+        watching.execute(&plan).unwrap();
+        assert_eq!(
+            [runs("normal_runs"), runs("phony_runs"), runs("deploy_runs")],
+            [1, 2, 2]
+        );
+
+        let forecast = quiet_builder(root).forecast(&plan).unwrap();
+        assert_eq!(
+            ["//normal", "//phony"].map(|t| forecast[&t.parse().unwrap()]),
+            [Forecast::UpToDate, Forecast::Run]
+        );
+
+        build(root, &targets).unwrap();
+        assert_eq!(
+            [runs("normal_runs"), runs("phony_runs"), runs("deploy_runs")],
+            [1, 3, 3]
+        );
+    }
+
+    #[test]
+    fn after_orders_without_building() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("service", "sleep 0.2; echo service >> order")
+task("client", "echo client >> order", after = ["//service"])
+"#,
+        )]);
+        let root = dir.path();
+        let order = || std::fs::read_to_string(root.join("order")).unwrap();
+
+        build(root, &["//client"]).unwrap();
+        assert_eq!(order(), "client\n");
+
+        std::fs::remove_file(root.join("order")).unwrap();
+        build(root, &["//client", "//service"]).unwrap();
+        assert_eq!(order(), "service\nclient\n");
+    }
+
+    #[test]
+    fn audit_log_records_each_command() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "echo hi > out", outs = {"out": "out"})
+task("b", "cat //a:out && false")
+"#,
+        )]);
+        let log_path = dir.path().join("audit.jsonl");
+
+        let audit_log = AuditLog::open(&log_path).unwrap();
+        assert!(build_with(dir.path(), &["//b"], |b| b.audit_log(audit_log)).is_err());
+
+        let records = std::fs::read_to_string(&log_path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(records[0]["target"], "//a");
+        assert_eq!(records[0]["command"], "echo hi > out");
+        assert_eq!(records[0]["exit_status"], 0);
+        assert_eq!(
+            records[0]["outputs"]["out"],
+            "98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4"
+        );
+
+        assert_eq!(records[1]["target"], "//b");
+        assert_eq!(records[1]["exit_status"], 1);
+        assert!(records[1]["start_ms"].as_u64() <= records[1]["end_ms"].as_u64());
+    }
+
+    #[test]
+    fn audit_log_env_order_is_deterministic() {
+        let env = (0..16)
+            .map(|i| format!(r#""VAR_{i:02}": "{i}""#))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let ffs = format!(r#"task("t", "true", env = {{{env}}})"#);
+        let dir = workspace([("FFS", ffs.as_str())]);
+        let log_path = dir.path().join("audit.jsonl");
+
+        for _ in 0..2 {
+            let audit_log = AuditLog::open(&log_path).unwrap();
+            build_with(dir.path(), &["//t"], |b| b.audit_log(audit_log)).unwrap();
+        }
+
+        let envs = std::fs::read_to_string(&log_path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["env"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(envs.len(), 2);
+        assert_eq!(envs[0], envs[1]);
+
+        let keys = envs[0]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|k| k.as_str().unwrap())
+            .collect::<Vec<_>>();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn shared_lock_never_overlaps() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "echo start >> log; sleep 0.2; echo end >> log", lock = "db")
+task("b", "echo start >> log; sleep 0.2; echo end >> log", lock = "db")
+"#,
+        )]);
+        let root = dir.path();
+
+        let builder = quiet_builder(root);
+        std::thread::scope(|scope| {
+            for target in ["//a", "//b"] {
+                let plan = plan(root, &[target]).unwrap();
+                let builder = &builder;
+                scope.spawn(move || builder.execute(&plan).unwrap());
+            }
+        });
+
+        let log = std::fs::read_to_string(root.join("log")).unwrap();
+        assert_eq!(log, "start\nend\nstart\nend\n");
+    }
+
+    #[test]
+    fn keep_going_builds_independent_targets() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("broken", "false")
+task("after_broken", "touch after_broken", prereqs = ["//broken"])
+task("fine", "sleep 0.2; touch fine")
+"#,
+        )]);
+        let root = dir.path();
+
+        let err =
+            build_with(root, &["//after_broken", "//fine"], |b| b.keep_going(true)).unwrap_err();
+
+        assert!(root.join("fine").exists());
+        assert!(!root.join("after_broken").exists());
+
+        let failures = err.downcast_ref::<Failures>().unwrap();
+        let failed = failures
+            .failures
+            .iter()
+            .map(|(t, _)| t.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(failed, ["//broken"]);
+        assert_eq!(failures.skipped, 1);
+    }
+
+    #[test]
+    fn critical_path_follows_longest_chain() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("slow", "sleep 0.3")
+task("slower", "sleep 0.3", prereqs = ["//slow"])
+task("fast", "sleep 0.1")
+task("top", "true", prereqs = ["//slower", "//fast"])
+"#,
+        )]);
+        let root = dir.path();
+
+        let plan = plan(root, &["//top"]).unwrap();
+        let builder = quiet_builder(root);
+        builder.execute(&plan).unwrap();
+
+        let path = builder.critical_path(&plan);
+        let targets = path.iter().map(|(t, _)| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(targets, ["//slow", "//slower", "//top"]);
+        assert!(path[0].1 >= Duration::from_millis(300), "{path:?}");
+    }
+
+    #[test]
+    fn dry_run_lists_commands_without_running() {
+        let dir = workspace([
+            (
+                "lib/FFS",
+                r#"build("lib", "touch ran_lib lib.a", ["lib.c"], {"out": "lib.a"})"#,
+            ),
+            (
+                "FFS",
+                r#"
+task("app", "touch ran_app; cat //lib/lib:out", prereqs = ["//gen"])
+http_file("gen", "http://localhost:1/never", "sha256:0000000000000000000000000000000000000000000000000000000000000000", "gen.txt")
+"#,
+            ),
+        ]);
+        let root = dir.path();
+
+        let builder = quiet_builder(root).dry_run(true);
+        builder.execute(&plan(root, &["//app"]).unwrap()).unwrap();
+
+        let lib_a = root.join("lib/lib.a");
+        let rehearsed = builder
+            .rehearsed()
+            .into_iter()
+            .map(|(t, c)| format!("{t}: {c}"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rehearsed,
+            [
+                "//lib/lib: touch ran_lib lib.a".to_string(),
+                format!("//app: touch ran_app; cat {}", lib_a.display()),
+            ]
+        );
+        assert!(!root.join("ran_app").exists());
+        assert!(!root.join("lib/ran_lib").exists());
+        assert!(!root.join("gen.txt").exists());
+    }
+
+    #[test]
+    fn shell_per_target() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("bashism", "[[ -n x ]] && touch bash_ran", shell = "bash")
+task("py", "open('py_ran', 'w').write('hi')", shell = "python3 -c")
+"#,
+        )]);
+
+        build(dir.path(), &["//bashism", "//py"]).unwrap();
+        assert!(dir.path().join("bash_ran").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("py_ran")).unwrap(),
+            "hi"
+        );
+    }
+
+    #[test]
+    fn empty_shell_is_rejected() {
+        let dir = workspace([("FFS", r#"task("t", "true", shell = "  ")"#)]);
+
+        let err = build(dir.path(), &["//t"]).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("shell must not be empty"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn changing_shell_misses_the_cache() {
+        let dir = workspace([("in.txt", "in\n")]);
+        let root = dir.path();
+        let build_in = |shell: &str| {
+            std::fs::write(
+                root.join("FFS"),
+                format!(
+                    r#"build("b", "echo x >> attempts; cp in.txt out.txt", ["in.txt"], {{"out": "out.txt"}}, shell = "{shell}")"#
+                ),
+            )
+            .unwrap();
+            build_with(root, &["//b"], |b| b.check_mtime(false)).unwrap();
+        };
+
+        build_in("sh");
+        build_in("sh");
+        assert_eq!(attempts(root), 1);
+
+        build_in("bash");
+        assert_eq!(attempts(root), 2);
+    }
+
+    #[test]
+    fn basename_aliases() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("t", "mkdir -p bin && echo hi > bin/app", outs = {"bin": "bin/app"}, alias_basenames = True)
+task("use", "cmp //t:bin //t:app && echo //t:app > used", outs = {"used": "used"})
+"#,
+        )]);
+
+        build(dir.path(), &["//use"]).unwrap();
+
+        let used = std::fs::read_to_string(dir.path().join("used")).unwrap();
+        assert_eq!(
+            used.trim(),
+            dir.path().join("bin/app").display().to_string()
+        );
+    }
+
+    #[test]
+    fn conflicting_basename_aliases() {
+        let dir = workspace([(
+            "FFS",
+            r#"task("t", "true", outs = {"a": "x/app", "b": "y/app"}, alias_basenames = True)"#,
+        )]);
+
+        let err = build(dir.path(), &["//t"]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains(r#"Outputs a and b share the basename "app""#),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn executable_output_runs_as_command() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("tool", "printf 'echo ran > ran' > tool.sh", outs = {"cmd": "tool.sh"}, executable = ["cmd"])
+task("use", "//tool:cmd", outs = {"ran": "ran"})
+"#,
+        )]);
+
+        build(dir.path(), &["//use"]).unwrap();
+
+        let ran = std::fs::read_to_string(dir.path().join("ran")).unwrap();
+        assert_eq!(ran, "ran\n");
+    }
+
+    #[test]
+    fn references_named_src_of_another_target() {
+        let dir = workspace([
+            (
+                "lib/FFS",
+                r#"build("lib", "cp main.c out", {"main": "main.c"}, {"out": "out"})"#,
+            ),
+            ("lib/main.c", "int main() {}\n"),
+            (
+                "FFS",
+                r#"task("show", "echo //lib/lib:src:main > shown", outs = {"shown": "shown"})"#,
+            ),
+        ]);
+
+        build(dir.path(), &["//show"]).unwrap();
+
+        let shown = std::fs::read_to_string(dir.path().join("shown")).unwrap();
+        assert_eq!(
+            shown.trim(),
+            dir.path().join("lib/main.c").display().to_string()
+        );
+    }
+
+    fn attempts(root: &Path) -> usize {
+        std::fs::read_to_string(root.join("attempts"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    }
+
+    fn set_mtime(root: &Path, file: &str, secs: i64) {
+        let time = filetime::FileTime::from_unix_time(secs, 0);
+        filetime::set_file_mtime(root.join(file), time).unwrap();
+    }
+
+    #[test]
+    fn retries_only_listed_exit_codes() {
+        let dir = workspace([(
+            "FFS",
+            r#"task("t", "echo x >> attempts; exit 3", retries = 2, retry_on = [1, 7])"#,
+        )]);
+
+        assert!(build(dir.path(), &["//t"]).is_err());
+        assert_eq!(attempts(dir.path()), 1);
+    }
+
+    #[test]
+    fn retries_listed_exit_code() {
+        let dir = workspace([(
+            "FFS",
+            r#"task("t", "echo x >> attempts; [ $(wc -l < attempts) -ge 2 ] || exit 7", retries = 2, retry_on = [1, 7])"#,
+        )]);
+
+        build(dir.path(), &["//t"]).unwrap();
+        assert_eq!(attempts(dir.path()), 2);
+    }
+
+    #[test]
+    fn retries_are_reported() {
+        #[derive(Default)]
+        struct Retries(Mutex<Vec<(String, u32)>>);
+        impl Reporter for Retries {
+            fn retry(&self, task: &TargetPath, attempt: u32) {
+                self.0.lock().unwrap().push((task.to_string(), attempt));
+            }
+        }
+
+        let dir = workspace([(
+            "FFS",
+            r#"task("flaky", "echo x >> attempts; [ $(wc -l < attempts) -ge 2 ]", retries = 1)"#,
+        )]);
+        let root = dir.path();
+        let reporter = Arc::new(Retries::default());
+
+        let executor = Arc::new(Executor::new(reporter.clone()));
+        Builder::new(executor, root)
+            .execute(&plan(root, &["//flaky"]).unwrap())
+            .unwrap();
+
+        assert_eq!(attempts(root), 2);
+        assert_eq!(*reporter.0.lock().unwrap(), [("//flaky".to_string(), 1)]);
+    }
+
+    #[test]
+    fn build_expands_srcs_and_outs() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "cat {srcs} > {outs}", ["a.txt", "b c.txt"], {"out": "joined"})"#,
+            ),
+            ("a.txt", "a\n"),
+            ("b c.txt", "b\n"),
+        ]);
+
+        build(dir.path(), &["//b"]).unwrap();
+
+        let joined = std::fs::read_to_string(dir.path().join("joined")).unwrap();
+        assert_eq!(joined, "a\nb\n");
+    }
+
+    #[test]
+    fn command_lists_run_in_order() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("gen", ["echo step > first", "cat first > second"], outs = {"out": "second"})
+task("use", ["cp //gen:out copy", "echo done >> copy"])
+"#,
+        )]);
+
+        build(dir.path(), &["//use"]).unwrap();
+
+        let copy = std::fs::read_to_string(dir.path().join("copy")).unwrap();
+        assert_eq!(copy, "step\ndone\n");
+    }
+
+    #[test]
+    fn command_lists_stop_at_first_failure() {
+        let dir = workspace([("FFS", r#"task("t", ["false", "touch ran"])"#)]);
+
+        assert!(build(dir.path(), &["//t"]).is_err());
+        assert!(!dir.path().join("ran").exists());
+    }
+
+    #[test]
+    fn empty_command_list_is_rejected() {
+        let dir = workspace([("FFS", r#"task("t", [])"#)]);
+
+        let err = build(dir.path(), &["//t"]).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("cmd must not be empty"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn target_env_is_set() {
+        let dir = workspace([(
+            "FFS",
+            r#"task("t", "echo $GREETING > greeting", env = {"GREETING": "hello"})"#,
+        )]);
+
+        build(dir.path(), &["//t"]).unwrap();
+
+        let greeting = std::fs::read_to_string(dir.path().join("greeting")).unwrap();
+        assert_eq!(greeting, "hello\n");
+    }
+
+    #[test]
+    fn foreach_runs_once_per_item() {
+        let dir = workspace([(
+            "FFS",
+            r#"foreach("each", ["a", "b c", "d"], "echo {item} >> items")"#,
+        )]);
+
+        build(dir.path(), &["//each"]).unwrap();
+
+        let items = std::fs::read_to_string(dir.path().join("items")).unwrap();
+        let mut items = items.lines().collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(items, ["a", "b c", "d"]);
+    }
+
+    #[test]
+    fn foreach_items_run_concurrently_on_free_jobs() {
+        // Each item waits for the other to start, so they only both succeed running together.
+        let dir = workspace([(
+            "FFS",
+            r#"foreach("each", ["a", "b"], "touch {item}.started; for i in $(seq 100); do [ -e a.started ] && [ -e b.started ] && break; sleep 0.05; done; [ -e a.started ] && [ -e b.started ]")"#,
+        )]);
+
+        build_with(dir.path(), &["//each"], |b| b.jobs(2)).unwrap();
+    }
+
+    #[test]
+    fn foreach_runs_at_most_jobs_items_at_once() {
+        let dir = workspace([(
+            "FFS",
+            r#"foreach("each", ["a", "b", "c", "d"], "mkdir busy; sleep 0.05; rmdir busy")"#,
+        )]);
+
+        build_with(dir.path(), &["//each"], |b| b.jobs(1)).unwrap();
+    }
+
+    #[test]
+    fn foreach_items_share_jobs_with_other_targets() {
+        let running = "mkdir -p running; touch running/{}; sleep 0.1; [ $(ls running | wc -l) -le 2 ]; rm running/{}";
+        let ffs = format!(
+            r#"
+foreach("each", ["a", "b", "c", "d"], "{}")
+task("other", "{}")
+"#,
+            running.replace("{}", "{item}"),
+            running.replace("{}", "other"),
+        );
+        let dir = workspace([("FFS", ffs.as_str())]);
+
+        build_with(dir.path(), &["//each", "//other"], |b| b.jobs(2)).unwrap();
+    }
+
+    #[test]
+    fn output_fallback_resolves() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("t", "echo hi > other", outs = {"fallback": "other"})
+task("use", "cp //t:bin?fallback copied", outs = {"copied": "copied"})
+"#,
+        )]);
+
+        build(dir.path(), &["//use"]).unwrap();
+
+        let copied = std::fs::read_to_string(dir.path().join("copied")).unwrap();
+        assert_eq!(copied, "hi\n");
+    }
+
+    #[test]
+    fn touch_creates_outputs_without_running() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"
+task("a", "echo ran > ran; echo a > out/a", outs = {"a": "out/a"})
+task("b", "echo ran > ran", outs = {"b": "b", "sum": ("existing", "sha256:98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4")}, prereqs = ["//a"])
+build("c", "echo ran > ran; cp src c", ["src"], {"c": "c"})
+"#,
+            ),
+            ("existing", "stale\n"),
+            ("src", "src\n"),
+        ]);
+        let root = dir.path();
+
+        build_with(root, &["//b", "//c"], |b| b.touch(true)).unwrap();
+
+        assert!(!root.join("ran").exists());
+        assert_eq!(std::fs::read_to_string(root.join("out/a")).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(root.join("b")).unwrap(), "");
+        assert_eq!(
+            std::fs::read_to_string(root.join("existing")).unwrap(),
+            "stale\n"
+        );
+
+        // A normal run restores the touched output from the cache instead of running.
+        std::fs::remove_file(root.join("c")).unwrap();
+        build(root, &["//c"]).unwrap();
+        assert!(!root.join("ran").exists());
+        assert_eq!(std::fs::read_to_string(root.join("c")).unwrap(), "");
+
+        // And its fingerprint skips it once its src looks newer, even without the cache.
+        std::fs::remove_dir_all(root.join(".ffs/cache")).unwrap();
+        set_mtime(root, "src", 3_000_000_000);
+        build(root, &["//c"]).unwrap();
+        assert!(!root.join("ran").exists());
+    }
+
+    #[test]
+    fn forecast_predicts_each_build() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"
+build("a", "cp src a", ["src"], {"a": "a"})
+build("b", "cp //a:a b", ["other"], {"b": "b"})
+task("t", "true")
+"#,
+            ),
+            ("src", "x"),
+            ("other", "y"),
+        ]);
+        let root = dir.path();
+        let forecast = || {
+            let plan = plan(root, &["//b", "//t"]).unwrap();
+            let forecast = quiet_builder(root).forecast(&plan).unwrap();
+            ["//a", "//b", "//t"].map(|t| forecast.get(&t.parse().unwrap()).copied())
+        };
+
+        assert_eq!(forecast(), [Some(Forecast::Run), Some(Forecast::Run), None]);
+
+        build(root, &["//b"]).unwrap();
+        assert_eq!(
+            forecast(),
+            [Some(Forecast::UpToDate), Some(Forecast::UpToDate), None]
+        );
+
+        // A src that looks newer but hasn't changed still matches the fingerprint.
+        set_mtime(root, "src", 3_000_000_000);
+        assert_eq!(forecast()[0], Some(Forecast::UpToDate));
+
+        std::fs::remove_file(root.join("a")).unwrap();
+        assert_eq!(forecast()[0], Some(Forecast::Cached));
+
+        std::fs::write(root.join("src"), "changed").unwrap();
+        assert_eq!(forecast(), [Some(Forecast::Run), Some(Forecast::Run), None]);
+    }
+
+    #[test]
+    fn build_outputs_are_cached_by_src_contents() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "echo x >> attempts; cp in.txt out.txt", ["in.txt"], {"out": "out.txt"})"#,
+            ),
+            ("in.txt", "one\n"),
+        ]);
+        let root = dir.path();
+        let write_src = |contents: &str| std::fs::write(root.join("in.txt"), contents).unwrap();
+        let out = || std::fs::read_to_string(root.join("out.txt")).unwrap();
+
+        build(root, &["//b"]).unwrap();
+        assert_eq!((attempts(root), out()), (1, "one\n".to_string()));
+
+        write_src("two\n");
+        build(root, &["//b"]).unwrap();
+        assert_eq!((attempts(root), out()), (2, "two\n".to_string()));
+
+        write_src("one\n");
+        build(root, &["//b"]).unwrap();
+        assert_eq!((attempts(root), out()), (2, "one\n".to_string()));
+    }
+
+    #[test]
+    fn cache_hits_replay_output() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "echo x >> attempts; printf 'out\n'; printf 'err\n' >&2; cp in.txt out.txt", ["in.txt"], {"out": "out.txt"})"#,
+            ),
+            ("in.txt", "one\n"),
+        ]);
+        let root = dir.path();
+        let plan = plan(root, &["//b"]).unwrap();
+        let streamed = |replay: bool| {
+            let (stdout, stderr) = (root.join("stdout"), root.join("stderr"));
+            let executor = Executor::new(Arc::new(Quiet)).stream_to(
+                std::fs::File::create(&stdout).unwrap(),
+                std::fs::File::create(&stderr).unwrap(),
+            );
+            Builder::new(Arc::new(executor), root)
+                .replay(replay)
+                .execute(&plan)
+                .unwrap();
+            [stdout, stderr].map(|f| std::fs::read(f).unwrap())
+        };
+
+        let ran = streamed(true);
+        assert_eq!(ran, [b"out\n".to_vec(), b"err\n".to_vec()]);
+
+        std::fs::remove_file(root.join("out.txt")).unwrap();
+        assert_eq!(streamed(true), ran);
+        assert_eq!(attempts(root), 1);
+
+        std::fs::remove_file(root.join("out.txt")).unwrap();
+        assert_eq!(streamed(false), [Vec::<u8>::new(), Vec::new()]);
+    }
+
+    #[test]
+    fn directory_outputs() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"
+build("site", "echo x >> attempts; mkdir -p dist/css && cp index.html dist/ && echo body > dist/css/main.css", ["index.html"], {"site": "dist/"})
+task("check", "cat $(location //site:site)/index.html $(location //site:site)/css/main.css > seen")
+"#,
+            ),
+            ("index.html", "<html>\n"),
+        ]);
+        let root = dir.path();
+
+        build(root, &["//check"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("seen")).unwrap(),
+            "<html>\nbody\n"
+        );
+
+        std::fs::remove_dir_all(root.join("dist")).unwrap();
+        build(root, &["//site"]).unwrap();
+        assert_eq!(attempts(root), 1);
+        assert_eq!(
+            std::fs::read_to_string(root.join("dist/css/main.css")).unwrap(),
+            "body\n"
+        );
+    }
+
+    #[test]
+    fn missing_directory_output_is_an_error() {
+        let dir = workspace([("FFS", r#"task("t", "touch dist", outs = {"out": "dist/"})"#)]);
+
+        assert!(build(dir.path(), &["//t"]).is_err());
+    }
+
+    #[test]
+    fn skips_builds_newer_than_srcs() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "echo x >> attempts; cp in.txt out.txt", ["in.txt"], {"out": "out.txt"})"#,
+            ),
+            ("in.txt", "one\n"),
+            ("out.txt", "stale\n"),
+        ]);
+        let root = dir.path();
+        let run = |check_mtime: bool| {
+            build_with(root, &["//b"], |b| b.check_mtime(check_mtime)).unwrap();
+        };
+
+        set_mtime(root, "in.txt", 1_000);
+        set_mtime(root, "out.txt", 2_000);
+        run(true);
+        assert_eq!(attempts(root), 0);
+
+        run(false);
+        assert_eq!(attempts(root), 1);
+
+        std::fs::write(root.join("in.txt"), "two\n").unwrap();
+        set_mtime(root, "in.txt", 3_000);
+        set_mtime(root, "out.txt", 2_000);
+        run(true);
+        assert_eq!(attempts(root), 2);
+        assert_eq!(
+            std::fs::read_to_string(root.join("out.txt")).unwrap(),
+            "two\n"
+        );
+    }
+
+    #[test]
+    fn unchanged_fingerprints_skip_builds() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "echo x >> attempts; cp in.txt out.txt", ["in.txt"], {"out": "out.txt"})"#,
+            ),
+            ("in.txt", "one\n"),
+        ]);
+        let root = dir.path();
+        // Rewriting a src makes it newer than the output, and clearing the cache means only the
+        // fingerprint can skip the build.
+        let write_src = |contents: &str| {
+            std::fs::write(root.join("in.txt"), contents).unwrap();
+            let _ = std::fs::remove_dir_all(root.join(".ffs/cache"));
+        };
+
+        build(root, &["//b"]).unwrap();
+        assert_eq!(attempts(root), 1);
+
+        write_src("one\n");
+        build(root, &["//b"]).unwrap();
+        assert_eq!(attempts(root), 1);
+
+        write_src("two\n");
+        build(root, &["//b"]).unwrap();
+        assert_eq!(attempts(root), 2);
+        assert_eq!(
+            std::fs::read_to_string(root.join("out.txt")).unwrap(),
+            "two\n"
+        );
+    }
+
+    #[test]
+    fn directory_srcs_are_walked() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "echo x >> attempts; cat src/*/* > out.txt", ["src"], {"out": "out.txt"})"#,
+            ),
+            ("src/nested/in.txt", "one\n"),
+            ("out.txt", "stale\n"),
+        ]);
+        let root = dir.path();
+        for file in ["src", "src/nested"] {
+            set_mtime(root, file, 1_000);
+        }
+        set_mtime(root, "out.txt", 2_000);
+
+        set_mtime(root, "src/nested/in.txt", 1_000);
+        build(root, &["//b"]).unwrap();
+        assert_eq!(attempts(root), 0);
+
+        std::fs::write(root.join("src/nested/in.txt"), "two\n").unwrap();
+        set_mtime(root, "src/nested/in.txt", 3_000);
+        set_mtime(root, "out.txt", 2_000);
+        build(root, &["//b"]).unwrap();
+        assert_eq!(attempts(root), 1);
+    }
+
+    #[test]
+    fn timeout_fails_target() {
+        let dir = workspace([("FFS", r#"task("hang", "sh -c 'sleep 5'", timeout = 1)"#)]);
+
+        let start = Instant::now();
+        let err = build(dir.path(), &["//hang"]).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert!(
+            format!("{err:#}").contains("//hang timed out after 1s"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn missing_src_is_an_error() {
+        let dir = workspace([(
+            "FFS",
+            r#"build("b", "touch ran out.txt", ["missing.txt"], {"out": "out.txt"})"#,
+        )]);
+
+        let err = build(dir.path(), &["//b"]).unwrap_err();
+        assert!(format!("{err:#}").contains("Missing src"), "{err:#}");
+        assert!(!dir.path().join("ran").exists());
+    }
+
+    #[test]
+    fn shared_dependency_runs_once() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("c", "echo x >> attempts; echo c > c", outs = {"out": "c"})
+task("a", "cat //c:out > a")
+task("b", "cat //c:out > b")
+"#,
+        )]);
+        let root = dir.path();
+
+        let builder = quiet_builder(root);
+        for target in ["//a", "//b"] {
+            builder.execute(&plan(root, &[target]).unwrap()).unwrap();
+        }
+
+        assert_eq!(attempts(root), 1);
+        assert_eq!(std::fs::read_to_string(root.join("b")).unwrap(), "c\n");
+    }
+
+    #[test]
+    fn independent_targets_run_concurrently() {
+        // Each side waits for the other to start, so they only both succeed running together.
+        let meet = |me: &str, other: &str| {
+            format!(
+                "echo {me} >> log; touch {me}.started; \
+                 for i in $(seq 100); do [ -e {other}.started ] && break; sleep 0.05; done; \
+                 [ -e {other}.started ]"
+            )
+        };
+        let ffs = format!(
+            r#"
+task("bottom", "echo bottom >> log")
+task("left", "{}", prereqs = ["//bottom"])
+task("right", "{}", prereqs = ["//bottom"])
+task("top", "echo top >> log", prereqs = ["//left", "//right"])
+"#,
+            meet("left", "right"),
+            meet("right", "left"),
+        );
+        let dir = workspace([("FFS", ffs.as_str())]);
+        let root = dir.path();
+
+        build_with(root, &["//top"], |b| b.jobs(4)).unwrap();
+
+        let log = std::fs::read_to_string(root.join("log")).unwrap();
+        let mut order = log.lines().collect::<Vec<_>>();
+        assert_eq!(order.first(), Some(&"bottom"), "{log}");
+        assert_eq!(order.last(), Some(&"top"), "{log}");
+        order[1..3].sort();
+        assert_eq!(order, ["bottom", "left", "right", "top"]);
+    }
+
+    #[test]
+    fn failure_lets_started_targets_finish() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("fails", "false")
+task("slow", "sleep 0.3; touch finished")
+task("after", "touch never", prereqs = ["//fails"])
+"#,
+        )]);
+        let root = dir.path();
+
+        assert!(build_with(root, &["//slow", "//after"], |b| b.jobs(4)).is_err());
+        assert!(root.join("finished").exists());
+        assert!(!root.join("never").exists());
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::digest;
+
+/// Content-addressed store of build outputs, keyed on everything that went into producing them.
+///
+/// Each entry is a directory `<key>/` holding one file (or directory) per output name, plus a
+/// `manifest.json` mapping output names to their [`hash`] and the build's [`Logs`] in `.stdout`
+/// and `.stderr`. Entries are written to a temporary directory and renamed into place, so a crash
+/// never leaves a partial entry under its key.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// Outputs found in the cache, ready to be copied into place.
+#[derive(Debug)]
+pub struct CachedOutputs {
+    files: BTreeMap<String, PathBuf>,
+    pub logs: Logs,
+}
+
+/// What a build's commands printed, kept with its outputs so a cache hit can show it again.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Logs {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+const MANIFEST: &str = "manifest.json";
+const STDOUT: &str = ".stdout";
+const STDERR: &str = ".stderr";
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Finds the outputs stored under `key`. Missing or corrupt entries are misses.
+    pub fn lookup(&self, key: &str) -> Option<CachedOutputs> {
+        let entry = self.dir.join(key);
+        let manifest = std::fs::read(entry.join(MANIFEST)).ok()?;
+        let manifest: BTreeMap<String, String> = serde_json::from_slice(&manifest).ok()?;
+
+        let mut files = BTreeMap::new();
+        for (name, expected) in manifest {
+            let file = entry.join(&name);
+            if hash(&file).ok()? != expected {
+                return None;
+            }
+            files.insert(name, file);
+        }
+
+        // Entries stored before logs were kept have none to show.
+        let logs = Logs {
+            stdout: std::fs::read(entry.join(STDOUT)).unwrap_or_default(),
+            stderr: std::fs::read(entry.join(STDERR)).unwrap_or_default(),
+        };
+        Some(CachedOutputs { files, logs })
+    }
+
+    /// Stores copies of `outs`, a map from output name to produced file, and `logs` under `key`.
+    pub fn store(
+        &self,
+        key: &str,
+        outs: &BTreeMap<String, PathBuf>,
+        logs: &Logs,
+    ) -> eyre::Result<()> {
+        let entry = self.dir.join(key);
+        let partial = self.dir.join(format!("{key}.partial"));
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        std::fs::create_dir_all(&partial)?;
+
+        let mut manifest = BTreeMap::new();
+        for (name, file) in outs {
+            copy(file, &partial.join(name))?;
+            manifest.insert(name, hash(file)?);
+        }
+        std::fs::write(partial.join(MANIFEST), serde_json::to_vec(&manifest)?)?;
+        std::fs::write(partial.join(STDOUT), &logs.stdout)?;
+        std::fs::write(partial.join(STDERR), &logs.stderr)?;
+
+        if entry.exists() {
+            std::fs::remove_dir_all(&entry)?;
+        }
+        std::fs::rename(&partial, &entry)?;
+        Ok(())
+    }
+}
+
+impl CachedOutputs {
+    /// Copies the cached files to `outs`, a map from output name to destination. Directories
+    /// replace whatever is already at their destination.
+    pub fn restore(&self, outs: &BTreeMap<String, PathBuf>) -> eyre::Result<()> {
+        for (name, dest) in outs {
+            let cached = self
+                .files
+                .get(name)
+                .ok_or_else(|| eyre::eyre!("Cache entry is missing output {name}"))?;
+
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if cached.is_dir() && dest.exists() {
+                std::fs::remove_dir_all(dest)?;
+            }
+            copy(cached, dest)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sha256 of a file, or of a directory's relative paths and file contents.
+fn hash(path: &Path) -> eyre::Result<String> {
+    if !path.is_dir() {
+        return digest::sha256_file(path);
+    }
+
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    let listing = entries
+        .iter()
+        .map(|entry| {
+            let name = entry.file_name().expect("entry has name").to_string_lossy();
+            Ok(format!("{name} {}", hash(entry)?))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    Ok(digest::sha256(format!("dir\n{}", listing.join("\n"))))
+}
+
+/// Copies a file, or a directory and everything in it.
+pub fn copy(from: &Path, to: &Path) -> eyre::Result<()> {
+    if !from.is_dir() {
+        std::fs::copy(from, to)?;
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        copy(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// Describes a file, or every file in a directory, for inclusion in a cache key, failing if it
+/// doesn't exist.
+pub fn describe_input(path: &Path) -> eyre::Result<String> {
+    eyre::ensure!(path.exists(), "Missing src {}", path.display());
+    if !path.is_dir() {
+        return Ok(format!("{} {}", path.display(), digest::sha256_file(path)?));
+    }
+
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    entries
+        .iter()
+        .map(|entry| describe_input(entry))
+        .collect::<eyre::Result<Vec<_>>>()
+        .map(|described| described.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_then_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+
+        let produced = dir.path().join("produced");
+        std::fs::write(&produced, "contents").unwrap();
+        let outs = BTreeMap::from([("out".to_string(), produced)]);
+
+        let logs = Logs {
+            stdout: b"printed\n".to_vec(),
+            stderr: Vec::new(),
+        };
+
+        assert!(cache.lookup("key").is_none());
+        cache.store("key", &outs, &logs).unwrap();
+
+        let restored = dir.path().join("restored");
+        let hit = cache.lookup("key").unwrap();
+        assert_eq!(hit.logs, logs);
+        hit.restore(&BTreeMap::from([("out".to_string(), restored.clone())]))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(restored).unwrap(), "contents");
+    }
+
+    #[test]
+    fn corrupt_entry_is_a_miss() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+
+        let produced = dir.path().join("produced");
+        std::fs::write(&produced, "contents").unwrap();
+        cache
+            .store(
+                "key",
+                &BTreeMap::from([("out".to_string(), produced)]),
+                &Logs::default(),
+            )
+            .unwrap();
+
+        std::fs::write(dir.path().join("cache/key/out"), "tampered").unwrap();
+        assert!(cache.lookup("key").is_none());
+    }
+
+    #[test]
+    fn directories_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+
+        let produced = dir.path().join("produced");
+        std::fs::create_dir_all(produced.join("sub")).unwrap();
+        std::fs::write(produced.join("a"), "a").unwrap();
+        std::fs::write(produced.join("sub/b"), "b").unwrap();
+        cache
+            .store(
+                "key",
+                &BTreeMap::from([("out".to_string(), produced)]),
+                &Logs::default(),
+            )
+            .unwrap();
+
+        let restored = dir.path().join("restored");
+        std::fs::create_dir_all(&restored).unwrap();
+        std::fs::write(restored.join("stale"), "").unwrap();
+        let hit = cache.lookup("key").unwrap();
+        hit.restore(&BTreeMap::from([("out".to_string(), restored.clone())]))
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(restored.join("sub/b")).unwrap(),
+            "b"
+        );
+        assert!(!restored.join("stale").exists());
+
+        std::fs::write(dir.path().join("cache/key/out/sub/b"), "tampered").unwrap();
+        assert!(cache.lookup("key").is_none());
+    }
+}
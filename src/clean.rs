@@ -0,0 +1,110 @@
+use std::{collections::BTreeSet, path::Path};
+
+use crate::{reporting::Reporter, starlark::Reader, target::TargetPath};
+
+/// Removes every declared output of `targets`, reporting each one that existed. Directory outputs
+/// are removed along with their contents.
+pub fn clean(
+    reader: &Reader,
+    root: &Path,
+    targets: &[TargetPath],
+    reporter: &dyn Reporter,
+) -> eyre::Result<()> {
+    let mut files = BTreeSet::new();
+    for target in targets {
+        let definition = root.join(target.definition());
+        let dir = definition.parent().expect("definition is file");
+        let set = reader.read(&definition)?;
+        let def = set
+            .targets
+            .get(target.name())
+            .ok_or_else(|| eyre::eyre!("Unknown task: {target}"))?;
+
+        files.extend(def.outs.values().map(|out| out.file(root, dir)));
+    }
+
+    for file in files {
+        if remove(&file)? {
+            reporter.note(&format!("Removed {}", file.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Removes the output cache shared by every target.
+pub fn clean_cache(root: &Path, reporter: &dyn Reporter) -> eyre::Result<()> {
+    let cache = root.join(".ffs/cache");
+    if remove(&cache)? {
+        reporter.note(&format!("Removed {}", cache.display()));
+    }
+    Ok(())
+}
+
+/// Removes a file or directory, returning whether it existed.
+fn remove(path: &Path) -> eyre::Result<bool> {
+    let result = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(eyre::eyre!(e).wrap_err(format!("Removing {}", path.display()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::{
+        builder::Builder, executor::Executor, plan::Plan, reporting::Quiet, testing::workspace,
+    };
+
+    #[test]
+    fn removes_outputs_and_keeps_srcs() {
+        let dir = workspace([
+            (
+                "lib/FFS",
+                r#"task("lib", "mkdir -p gen && cp lib.c gen/lib.o && cp lib.c lib.a", outs = {"out": "lib.a", "obj": "gen"})"#,
+            ),
+            ("lib/lib.c", "int x;\n"),
+            (
+                "FFS",
+                r#"task("app", "cat //lib/lib:out > app", outs = {"bin": "app", "never": "never_built"})"#,
+            ),
+        ]);
+        let root = dir.path();
+        std::fs::write(root.join("never_built"), "").unwrap();
+
+        let reader = Reader::new(root);
+        let targets = ["//app", "//lib/lib"].map(|t| t.parse().unwrap());
+        let plan = Plan::new(&reader, root, &targets).unwrap();
+        let executor = Arc::new(Executor::new(Arc::new(Quiet)));
+        Builder::new(executor, root).execute(&plan).unwrap();
+        std::fs::remove_file(root.join("never_built")).unwrap();
+
+        clean(&reader, root, &targets, &Quiet).unwrap();
+        std::fs::create_dir_all(root.join(".ffs/cache/key")).unwrap();
+        clean_cache(root, &Quiet).unwrap();
+        // Recorded durations aren't cached outputs, so they stay.
+        std::fs::remove_file(root.join(".ffs/timings.json")).unwrap();
+        std::fs::remove_dir(root.join(".ffs")).unwrap();
+
+        let mut remaining = ignore::WalkBuilder::new(root)
+            .hidden(false)
+            .build()
+            .map(|e| e.unwrap().path().strip_prefix(root).unwrap().to_path_buf())
+            .filter(|p| p.is_relative() && !p.as_os_str().is_empty())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            ["FFS", "lib", "lib/FFS", "lib/lib.c"].map(std::path::PathBuf::from)
+        );
+    }
+}
@@ -1,9 +1,15 @@
-use std::{borrow::Borrow, path::PathBuf, str::FromStr};
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use dashmap::DashMap;
 use eyre::OptionExt;
 
-use crate::target::{Output, TargetPath};
+use crate::target::{ident, Output, RelativeTarget, Source, TargetPath};
 
 #[derive(Debug)]
 pub struct Command {
@@ -12,66 +18,486 @@ pub struct Command {
 
 impl Command {
     pub fn targets(&self) -> impl Iterator<Item = impl Borrow<TargetPath> + '_> {
-        self.words
+        let fallback_targets = self.fallbacks().map(|candidates| candidates[0].target());
+        self.outputs().map(|o| o.target()).chain(fallback_targets)
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = &Output> {
+        self.references().filter_map(|s| match s {
+            Word::Output(o) => Some(o),
+            _ => None,
+        })
+    }
+
+    /// References like `//target:bin?fallback`, which resolve to the first declared candidate.
+    /// Candidates are all outputs of the same target.
+    pub fn fallbacks(&self) -> impl Iterator<Item = &[Output]> {
+        self.references().filter_map(|s| match s {
+            Word::Fallback(candidates) => Some(candidates.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// Srcs of other targets referenced by this command.
+    pub fn sources(&self) -> impl Iterator<Item = &Source> {
+        self.references().filter_map(|s| match s {
+            Word::Source(s) => Some(s),
+            _ => None,
+        })
+    }
+
+    /// Every word, with `$(location ...)` replaced by the reference inside it.
+    fn references(&self) -> impl Iterator<Item = &Word> {
+        self.words.iter().map(|w| match w {
+            Word::Location { reference, .. } => reference,
+            w => w,
+        })
+    }
+
+    pub fn as_sh(
+        &self,
+        outputs: &DashMap<Output, PathBuf>,
+        sources: &HashMap<Source, PathBuf>,
+    ) -> eyre::Result<String> {
+        self.render_sh(outputs, sources, |_| None)
+    }
+
+    /// Like [`Command::as_sh`], also expanding `{srcs}` and `{outs}` to the given lists.
+    pub fn as_build_sh(
+        &self,
+        outputs: &DashMap<Output, PathBuf>,
+        sources: &HashMap<Source, PathBuf>,
+        srcs: &[&str],
+        outs: &[&str],
+    ) -> eyre::Result<String> {
+        self.render_sh(outputs, sources, |list| match list {
+            List::Srcs => Some(srcs),
+            List::Outs => Some(outs),
+            List::Item => None,
+        })
+    }
+
+    /// Like [`Command::as_sh`], also expanding `{item}` to `item`.
+    pub fn as_item_sh(
+        &self,
+        outputs: &DashMap<Output, PathBuf>,
+        sources: &HashMap<Source, PathBuf>,
+        item: &str,
+    ) -> eyre::Result<String> {
+        self.render_sh(outputs, sources, |list| {
+            (list == List::Item).then_some(std::slice::from_ref(&item))
+        })
+    }
+
+    /// Renders the command, expanding each list to what `lists` gives for it. Lists it gives
+    /// nothing for are left as written.
+    fn render_sh<'l>(
+        &self,
+        outputs: &DashMap<Output, PathBuf>,
+        sources: &HashMap<Source, PathBuf>,
+        lists: impl Fn(List) -> Option<&'l [&'l str]>,
+    ) -> eyre::Result<String> {
+        Ok(self
+            .words
             .iter()
-            .filter_map(|s| match s {
-                Word::Output(o) => Some(o),
-                _ => None,
+            .map(|w| match w {
+                Word::Lit(s) => Ok(s.to_string()),
+                Word::List {
+                    list,
+                    double_quoted,
+                } => Ok(match lists(*list) {
+                    None => list.placeholder().to_string(),
+                    Some(items) => {
+                        let escape = match double_quoted {
+                            true => escape_double_quoted,
+                            false => shell_word,
+                        };
+                        items
+                            .iter()
+                            .map(|s| escape(s))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    }
+                }),
+                Word::Location {
+                    reference,
+                    double_quoted: true,
+                } => Ok(escape_double_quoted(&resolve(reference, outputs, sources)?)),
+                Word::Location { reference, .. } => {
+                    Ok(shell_word(&resolve(reference, outputs, sources)?))
+                }
+                w => Ok(shell_word(&resolve(w, outputs, sources)?)),
             })
-            .map(|o| o.target())
+            .collect::<eyre::Result<Vec<_>>>()?
+            .join(""))
     }
 
-    pub fn as_sh(&self, outputs: &DashMap<Output, PathBuf>) -> eyre::Result<String> {
+    /// The command as one argument of a program run without a shell: references are resolved,
+    /// and nothing is quoted or expanded.
+    pub fn as_arg(
+        &self,
+        outputs: &DashMap<Output, PathBuf>,
+        sources: &HashMap<Source, PathBuf>,
+    ) -> eyre::Result<String> {
         Ok(self
             .words
             .iter()
-            .map(|w| {
-                let output = match w {
-                    Word::Lit(s) => return Ok(s.to_string()),
-                    Word::Output(o) => o,
-                };
-
-                let path = outputs
-                    .get(&output)
-                    .ok_or_eyre(format!("Missing output {output}"))?;
-
-                Ok(path
-                    .to_str()
-                    .ok_or_eyre(format!("Path not utf8 {}", path.display()))?
-                    .to_string())
+            .map(|w| match w {
+                Word::Lit(s) => Ok(s.to_string()),
+                Word::Location { reference, .. } => resolve(reference, outputs, sources),
+                w => resolve(w, outputs, sources),
             })
             .collect::<eyre::Result<Vec<_>>>()?
             .join(""))
     }
 }
 
+/// `args` as a `sh` command line, to show a program run without a shell.
+pub fn render_argv(args: &[String]) -> String {
+    args.iter()
+        .map(|a| shell_word(a))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Command {
+    /// Parses `s` as written in `defining_file`, relative to the root, so references like
+    /// `%/target:out` resolve to targets near it.
+    pub fn parse_in(s: &str, defining_file: &Path) -> eyre::Result<Command> {
+        Self::parse(s, Some(defining_file))
+    }
+
+    fn parse(s: &str, defining_file: Option<&Path>) -> eyre::Result<Command> {
+        let words = tokenize(s)
+            .into_iter()
+            .map(|token| match token {
+                Token::Bare(word) => Ok(reference(&word, defining_file).unwrap_or(Word::Lit(word))),
+                Token::Space(s) | Token::Quoted(s) => Ok(Word::Lit(s)),
+                Token::Location {
+                    reference: r,
+                    double_quoted,
+                } => Ok(Word::Location {
+                    reference: Box::new(
+                        reference(&r, defining_file)
+                            .ok_or_eyre(format!("Not a reference: $(location {r})"))?,
+                    ),
+                    double_quoted,
+                }),
+                Token::List {
+                    list,
+                    double_quoted,
+                } => Ok(Word::List {
+                    list,
+                    double_quoted,
+                }),
+            })
+            .collect::<eyre::Result<_>>()?;
+
+        Ok(Command { words })
+    }
+}
+
 impl FromStr for Command {
     type Err = eyre::Report;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut words = Vec::new();
+        Command::parse(s, None)
+    }
+}
+
+/// The path a reference word stands for.
+fn resolve(
+    word: &Word,
+    outputs: &DashMap<Output, PathBuf>,
+    sources: &HashMap<Source, PathBuf>,
+) -> eyre::Result<String> {
+    let path = match word {
+        Word::Output(o) => outputs
+            .get(o)
+            .ok_or_eyre(format!("Missing output {o}"))?
+            .clone(),
+        Word::Fallback(candidates) => candidates
+            .iter()
+            .find_map(|o| outputs.get(o))
+            .ok_or_eyre(format!("Missing all of outputs {}", word.display()))?
+            .clone(),
+        Word::Source(s) => sources
+            .get(s)
+            .ok_or_eyre(format!("Missing source {s}"))?
+            .clone(),
+        Word::Lit(_) | Word::Location { .. } | Word::List { .. } => {
+            unreachable!("{word:?} is not a reference")
+        }
+    };
 
-        let pat = &[' ', '\n'];
+    Ok(path
+        .to_str()
+        .ok_or_eyre(format!("Path not utf8 {}", path.display()))?
+        .to_string())
+}
+
+/// A reference to another target's output or src, if `word` is one.
+fn reference(word: &str, defining_file: Option<&Path>) -> Option<Word> {
+    let resolved = defining_file.and_then(|file| resolve_relative(word, file));
+    let word = resolved.as_deref().unwrap_or(word);
+
+    if let Ok(source) = word.parse() {
+        Some(Word::Source(source))
+    } else if let Ok(candidates) = parse_fallback(word) {
+        Some(Word::Fallback(candidates))
+    } else {
+        word.parse().ok().map(Word::Output)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Space(String),
+    /// A word without quotes or escapes, which may be a reference.
+    Bare(String),
+    /// A word containing quotes or escapes, kept exactly as written.
+    Quoted(String),
+    /// `$(location <reference>)`, spliced into whatever word surrounds it.
+    Location {
+        reference: String,
+        double_quoted: bool,
+    },
+    /// `{srcs}`, `{outs}` or `{item}`, also spliced into the surrounding word.
+    List {
+        list: List,
+        double_quoted: bool,
+    },
+}
+
+const LOCATION: &str = "$(location ";
+
+/// Splits `$(location <reference>)` from the start of `s`, returning the reference and the rest.
+fn location(s: &str) -> Option<(&str, &str)> {
+    let inner = s.strip_prefix(LOCATION)?;
+    let (reference, rest) = inner.split_once(')')?;
+    Some((reference.trim(), rest))
+}
+
+/// Splits `{srcs}`, `{outs}` or `{item}` from the start of `s`, returning the list and the rest.
+fn list(s: &str) -> Option<(List, &str)> {
+    [List::Srcs, List::Outs, List::Item]
+        .into_iter()
+        .find_map(|list| Some((list, s.strip_prefix(list.placeholder())?)))
+}
 
-        for s in s.split_inclusive(pat) {
-            let trimmed = s.trim_end_matches(pat);
-            match trimmed.parse() {
-                Ok(o) => {
-                    words.push(Word::Output(o));
-                    words.push(Word::Lit(s[trimmed.len()..].to_string()));
+/// Splits a command into whitespace and words the way `sh` does, so quoted text is never
+/// mistaken for a reference. Unterminated quotes run to the end of the command.
+fn tokenize(s: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+
+    let flush = |tokens: &mut Vec<Token>, word: &mut String, quoted: &mut bool| {
+        let quoted = std::mem::take(quoted);
+        if word.is_empty() {
+            return;
+        }
+        let word = std::mem::take(word);
+        tokens.push(match quoted {
+            true => Token::Quoted(word),
+            false => Token::Bare(word),
+        });
+    };
+
+    let mut rest = s;
+    let mut double_quoted = false;
+    while let Some(c) = rest.chars().next() {
+        if let Some((reference, after)) = location(rest) {
+            // The location is only part of the surrounding word, so neither side of it can be a
+            // bare reference.
+            quoted = true;
+            flush(&mut tokens, &mut word, &mut quoted);
+            tokens.push(Token::Location {
+                reference: reference.to_string(),
+                double_quoted,
+            });
+            quoted = true;
+            rest = after;
+            continue;
+        }
+        // `${srcs}` is a shell expansion, not a list.
+        if let Some((list, after)) = list(rest).filter(|_| !word.ends_with('$')) {
+            quoted = true;
+            flush(&mut tokens, &mut word, &mut quoted);
+            tokens.push(Token::List {
+                list,
+                double_quoted,
+            });
+            quoted = true;
+            rest = after;
+            continue;
+        }
+
+        let mut chars = rest[c.len_utf8()..].chars();
+        if double_quoted {
+            word.push(c);
+            match c {
+                '\\' => word.extend(chars.next()),
+                '"' => double_quoted = false,
+                _ => {}
+            }
+            rest = chars.as_str();
+            continue;
+        }
+
+        match c {
+            c if c.is_whitespace() => {
+                flush(&mut tokens, &mut word, &mut quoted);
+                match tokens.last_mut() {
+                    Some(Token::Space(space)) => space.push(c),
+                    _ => tokens.push(Token::Space(c.to_string())),
+                }
+            }
+            '\\' => {
+                quoted = true;
+                word.push(c);
+                word.extend(chars.next());
+            }
+            '\'' => {
+                quoted = true;
+                word.push(c);
+                for c in chars.by_ref() {
+                    word.push(c);
+                    if c == '\'' {
+                        break;
+                    }
                 }
-                Err(_) => words.push(Word::Lit(s.to_string())),
             }
+            '"' => {
+                quoted = true;
+                double_quoted = true;
+                word.push(c);
+            }
+            c => word.push(c),
         }
+        rest = chars.as_str();
+    }
+    flush(&mut tokens, &mut word, &mut quoted);
 
-        Ok(Command { words })
+    tokens
+}
+
+/// Rewrites a word starting with a relative target, like `%/lib:out`, to its absolute form.
+fn resolve_relative(word: &str, defining_file: &Path) -> Option<String> {
+    let end = word.find([':', '?']).unwrap_or(word.len());
+    let target = word[..end].parse::<RelativeTarget>().ok()?;
+    let target = target.resolve(defining_file).ok()?;
+    Some(format!("{target}{}", &word[end..]))
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for word in &self.words {
+            write!(f, "{}", word.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `//target:name?other?...` into the outputs to try, in order.
+fn parse_fallback(s: &str) -> eyre::Result<Vec<Output>> {
+    let Some((first, rest)) = s.split_once('?') else {
+        eyre::bail!("Not a fallback reference");
+    };
+
+    let first = first.parse::<Output>()?;
+    let target = first.target().clone();
+    let rest = rest
+        .split('?')
+        .map(|name| {
+            eyre::ensure!(!name.is_empty(), "Empty fallback output name");
+            Ok(target.output(ident(name)?))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    Ok(std::iter::once(first).chain(rest).collect())
+}
+
+/// `s` as a single `sh` word, quoted only if it needs to be.
+fn shell_word(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./+=:,@%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        s.to_string()
+    } else {
+        quote(s)
+    }
+}
+
+/// Escapes `s` for use inside double quotes.
+fn escape_double_quoted(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
+}
+
+/// Quotes `s` so `sh` treats it as a single literal word.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
 }
 
 #[derive(Debug)]
 enum Word {
     Lit(String),
     Output(Output),
+    Fallback(Vec<Output>),
+    Source(Source),
+    /// A reference written as `$(location <reference>)`.
+    Location {
+        reference: Box<Word>,
+        double_quoted: bool,
+    },
+    /// Expanded to a build's srcs or outs or a foreach's item, or kept as written anywhere else.
+    List {
+        list: List,
+        double_quoted: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum List {
+    Srcs,
+    Outs,
+    Item,
+}
+
+impl List {
+    fn placeholder(self) -> &'static str {
+        match self {
+            List::Srcs => "{srcs}",
+            List::Outs => "{outs}",
+            List::Item => "{item}",
+        }
+    }
+}
+
+impl Word {
+    fn display(&self) -> String {
+        match self {
+            Word::Lit(s) => s.clone(),
+            Word::Output(o) => o.to_string(),
+            Word::Fallback(candidates) => {
+                let mut names = candidates.iter().map(|o| o.name());
+                let first = names.next().expect("fallback has candidates");
+                let rest = names.map(|n| format!("?{n}")).collect::<String>();
+                format!("{}:{first}{rest}", candidates[0].target())
+            }
+            Word::Source(s) => s.to_string(),
+            Word::List { list, .. } => list.placeholder().to_string(),
+            Word::Location { reference, .. } => {
+                format!("{LOCATION}{})", reference.display())
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -93,7 +519,7 @@ mod tests {
         let c = "echo 'foo'".parse::<Command>().unwrap();
 
         assert_eq!(target_strings(&c), &[] as &[&str]);
-        assert_eq!(c.as_sh(&map([])).unwrap(), "echo 'foo'");
+        assert_eq!(c.as_sh(&map([]), &HashMap::new()).unwrap(), "echo 'foo'");
     }
 
     #[test]
@@ -102,8 +528,11 @@ mod tests {
 
         assert_eq!(target_strings(&c), &["//path/to/target"]);
         assert_eq!(
-            c.as_sh(&map([("//path/to/target:output", "path/to/file")]))
-                .unwrap(),
+            c.as_sh(
+                &map([("//path/to/target:output", "path/to/file")]),
+                &HashMap::new()
+            )
+            .unwrap(),
             "cat path/to/file",
         );
     }
@@ -114,19 +543,227 @@ mod tests {
 
         assert_eq!(target_strings(&c), &["//path/to/target"]);
         assert_eq!(
-            c.as_sh(&map([("//path/to/target:cmd", "path/to/file")]))
-                .unwrap(),
+            c.as_sh(
+                &map([("//path/to/target:cmd", "path/to/file")]),
+                &HashMap::new()
+            )
+            .unwrap(),
             "path/to/file arg1 arg2",
         );
     }
 
+    #[test]
+    fn source_reference() {
+        let c = "cat //lib/target:src:main".parse::<Command>().unwrap();
+
+        assert_eq!(target_strings(&c), &[] as &[&str]);
+        let sources = HashMap::from([(
+            "//lib/target:src:main".parse().unwrap(),
+            PathBuf::from("lib/main.c"),
+        )]);
+        assert_eq!(c.as_sh(&map([]), &sources).unwrap(), "cat lib/main.c");
+    }
+
+    fn build_sh(command: &str, srcs: &[&str], outs: &[&str]) -> String {
+        let c = command.parse::<Command>().unwrap();
+        c.as_build_sh(&map([]), &HashMap::new(), srcs, outs)
+            .unwrap()
+    }
+
+    #[test]
+    fn expands_srcs_and_outs() {
+        assert_eq!(
+            build_sh("cc {srcs} -o {outs}", &["a.c", "b c.c"], &["out"]),
+            "cc a.c 'b c.c' -o out"
+        );
+        assert_eq!(
+            build_sh("cp {srcs} dir", &["it's"], &[]),
+            r"cp 'it'\''s' dir"
+        );
+        assert_eq!(
+            build_sh("echo ${srcs} {outs}", &["a"], &[]),
+            "echo ${srcs} "
+        );
+        assert_eq!(
+            "cc {srcs}"
+                .parse::<Command>()
+                .unwrap()
+                .as_sh(&map([]), &HashMap::new())
+                .unwrap(),
+            "cc {srcs}"
+        );
+    }
+
+    #[test]
+    fn expands_item() {
+        let c = "echo {item}.txt {srcs}".parse::<Command>().unwrap();
+        assert_eq!(
+            c.as_item_sh(&map([]), &HashMap::new(), "a b").unwrap(),
+            "echo 'a b'.txt {srcs}"
+        );
+    }
+
+    #[test]
+    fn lists_in_quotes() {
+        assert_eq!(
+            build_sh(r#"echo "in: {srcs}" '{outs}'"#, &["a b", "$c"], &["out"]),
+            r#"echo "in: a b \$c" '{outs}'"#
+        );
+    }
+
+    #[test]
+    fn lists_are_not_expanded_in_resolved_paths() {
+        let c = "cp //a:out {outs}".parse::<Command>().unwrap();
+        assert_eq!(
+            c.as_build_sh(
+                &map([("//a:out", "{srcs}")]),
+                &HashMap::new(),
+                &["src"],
+                &["o"]
+            )
+            .unwrap(),
+            "cp '{srcs}' o"
+        );
+    }
+
+    #[test]
+    fn output_fallback() {
+        let c = "cat //t:bin?fallback".parse::<Command>().unwrap();
+
+        assert_eq!(target_strings(&c), &["//t"]);
+        assert_eq!(c.to_string(), "cat //t:bin?fallback");
+        assert_eq!(
+            c.as_sh(&map([("//t:fallback", "t/fallback")]), &HashMap::new())
+                .unwrap(),
+            "cat t/fallback",
+        );
+        assert_eq!(
+            c.as_sh(
+                &map([("//t:bin", "t/bin"), ("//t:fallback", "t/fallback")]),
+                &HashMap::new()
+            )
+            .unwrap(),
+            "cat t/bin",
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("simple"), "'simple'");
+        assert_eq!(quote("with space"), "'with space'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
     #[test]
     fn multiple_lines() {
         let c = "echo foo\n//some/target bar".parse::<Command>().unwrap();
 
         assert_eq!(
-            c.as_sh(&map([("//some/target", "some/target")])).unwrap(),
+            c.as_sh(&map([("//some/target", "some/target")]), &HashMap::new())
+                .unwrap(),
             "echo foo\nsome/target bar",
         );
     }
+
+    #[test]
+    fn relative_references() {
+        let c = Command::parse_in("cp %/dep:out %/dep printf %s/%d", Path::new("lib/FFS")).unwrap();
+
+        assert_eq!(target_strings(&c), ["//lib/dep", "//lib/dep"]);
+        assert_eq!(
+            c.to_string(),
+            "cp //lib/dep:out //lib/dep:default printf %s/%d"
+        );
+    }
+
+    #[test]
+    fn quoted_text_is_literal() {
+        let c = r#"echo "//not a target" '//a:out' //b:out"#.parse::<Command>().unwrap();
+
+        assert_eq!(target_strings(&c), ["//b"]);
+        assert_eq!(
+            c.as_sh(&map([("//b:out", "b.txt")]), &HashMap::new())
+                .unwrap(),
+            r#"echo "//not a target" '//a:out' b.txt"#
+        );
+    }
+
+    #[test]
+    fn escaped_quotes() {
+        let c = r#"echo "say \"//a:out\" now" \//a:out it\'s"#.parse::<Command>().unwrap();
+
+        assert_eq!(target_strings(&c), &[] as &[&str]);
+        assert_eq!(
+            c.to_string(),
+            r#"echo "say \"//a:out\" now" \//a:out it\'s"#
+        );
+    }
+
+    #[test]
+    fn output_path_with_space_is_quoted() {
+        let c = "cat //a:out\t//b:out".parse::<Command>().unwrap();
+
+        assert_eq!(
+            c.as_sh(
+                &map([("//a:out", "dir with space/a"), ("//b:out", "b")]),
+                &HashMap::new()
+            )
+            .unwrap(),
+            "cat 'dir with space/a'\tb"
+        );
+    }
+
+    #[test]
+    fn location_within_word() {
+        let c = "cp --input=$(location //a:out).bak $(location //b:out)$(location //a:out) x"
+            .parse::<Command>()
+            .unwrap();
+
+        assert_eq!(target_strings(&c), ["//a", "//b", "//a"]);
+        assert_eq!(
+            c.as_sh(
+                &map([("//a:out", "a.txt"), ("//b:out", "b dir/b")]),
+                &HashMap::new()
+            )
+            .unwrap(),
+            "cp --input=a.txt.bak 'b dir/b'a.txt x"
+        );
+        assert_eq!(
+            c.to_string(),
+            "cp --input=$(location //a:out).bak $(location //b:out)$(location //a:out) x"
+        );
+    }
+
+    #[test]
+    fn location_in_double_quotes() {
+        let c = r#"echo "path: $(location //a:out)" '$(location //b:out)'"#
+            .parse::<Command>()
+            .unwrap();
+
+        assert_eq!(target_strings(&c), ["//a"]);
+        assert_eq!(
+            c.as_sh(&map([("//a:out", "a \"dir\"/$x")]), &HashMap::new())
+                .unwrap(),
+            r#"echo "path: a \"dir\"/\$x" '$(location //b:out)'"#
+        );
+    }
+
+    #[test]
+    fn bare_reference_after_location() {
+        let c = "cat $(location //a:out) //b:out"
+            .parse::<Command>()
+            .unwrap();
+
+        assert_eq!(target_strings(&c), ["//a", "//b"]);
+        assert_eq!(
+            c.as_sh(&map([("//a:out", "a"), ("//b:out", "b")]), &HashMap::new())
+                .unwrap(),
+            "cat a b"
+        );
+    }
+
+    #[test]
+    fn location_must_be_a_reference() {
+        assert!("cat $(location not-a-target)".parse::<Command>().is_err());
+    }
 }
@@ -0,0 +1,127 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Serialize;
+
+use crate::{builder::Builder, executor::Executor, plan::Plan, reporting::Quiet};
+
+/// Builds tagged with this are included in the export.
+pub const TAG: &str = "compdb";
+
+/// An entry of a clang `compile_commands.json`.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub directory: PathBuf,
+    pub command: String,
+    pub file: PathBuf,
+}
+
+/// Resolves the commands of every build in `plan` tagged [`TAG`] the way they'd run, without
+/// running anything. Each entry is for the build's [`Build::primary_src`], so builds without
+/// one are left out.
+///
+/// [`Build::primary_src`]: crate::target::Build::primary_src
+pub fn entries(plan: &Plan, root: &Path) -> eyre::Result<Vec<Entry>> {
+    let builder = Builder::new(Arc::new(Executor::new(Arc::new(Quiet))), root);
+
+    let mut entries = Vec::new();
+    for step in &plan.steps {
+        builder.assume_built(step)?;
+
+        let def = step.def();
+        let Some(build) = def.as_build() else {
+            continue;
+        };
+        if !def.tags.contains(TAG) {
+            continue;
+        }
+        let Some(primary) = build.primary_src() else {
+            continue;
+        };
+
+        entries.push(Entry {
+            directory: builder.workdir(step),
+            command: builder.commands(step)?.join(" && "),
+            file: step.dir.join(primary),
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{starlark::Reader, testing::workspace};
+
+    #[test]
+    fn tagged_builds_are_exported() {
+        let dir = workspace([
+            (
+                "lib/FFS",
+                r#"
+build("util", "cc -c util.c -o util.o", ["util.c"], {"obj": "util.o"}, tags = ["compdb"])
+build("skipped", "cc -c other.c -o other.o", ["other.c"], {"obj": "other.o"})
+"#,
+            ),
+            (
+                "FFS",
+                r#"build("app", "cc main.c //lib/util:obj -o app", ["main.c", "extra.h"], {"bin": "app"}, tags = ["compdb"])"#,
+            ),
+        ]);
+        let root = dir.path();
+
+        let reader = Reader::new(root);
+        let targets = ["//app", "//lib/skipped"].map(|t| t.parse().unwrap());
+        let plan = Plan::new(&reader, root, &targets).unwrap();
+
+        let entries = entries(&plan, root).unwrap();
+        let json = serde_json::to_value(&entries).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "directory": root.join("lib"),
+                    "command": "cc -c util.c -o util.o",
+                    "file": root.join("lib/util.c"),
+                },
+                {
+                    "directory": root,
+                    "command": format!("cc main.c {} -o app", root.join("lib/util.o").display()),
+                    "file": root.join("main.c"),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn entries_match_how_builds_run() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+build("named", "cc -c {srcs} -o {outs}", {"hdr": "a.h", "src": "b.c"}, {"obj": "b.o"}, workdir = "out", tags = ["compdb"])
+build("unnamed", "cc -c c.h", {"hdr": "c.h"}, {"obj": "c.o"}, tags = ["compdb"])
+"#,
+        )]);
+        let root = dir.path();
+
+        let reader = Reader::new(root);
+        let targets = ["//named", "//unnamed"].map(|t| t.parse().unwrap());
+        let plan = Plan::new(&reader, root, &targets).unwrap();
+
+        let entries = entries(&plan, root).unwrap();
+        let json = serde_json::to_value(&entries).unwrap();
+        let path = |file: &str| root.join(file).display().to_string();
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "directory": root.join("out"),
+                "command": format!("cc -c {} {} -o {}", path("a.h"), path("b.c"), path("b.o")),
+                "file": root.join("b.c"),
+            }])
+        );
+    }
+}
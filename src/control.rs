@@ -0,0 +1,136 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use eyre::WrapErr;
+use serde::Serialize;
+
+use crate::executor::Executor;
+
+/// A unix socket accepting line-based commands from tools driving ffs.
+///
+/// * `status` - Responds with the currently running targets.
+/// * `cancel` - Kills in-flight commands and aborts the run.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn listen(path: &Path, executor: Arc<Executor>) -> eyre::Result<ControlSocket> {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .wrap_err_with(|| format!("Listening on {}", path.display()))?;
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let executor = Arc::clone(&executor);
+                std::thread::spawn(move || {
+                    let _ = serve(stream, &executor);
+                });
+            }
+        });
+
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Response {
+    Status { running: Vec<String> },
+    Ok { ok: bool },
+    Error { error: String },
+}
+
+fn serve(stream: UnixStream, executor: &Executor) -> eyre::Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let response = match line?.trim() {
+            "status" => Response::Status {
+                running: executor.running().iter().map(|t| t.to_string()).collect(),
+            },
+            "cancel" => {
+                executor.cancel();
+                Response::Ok { ok: true }
+            }
+            other => Response::Error {
+                error: format!("Unknown command: {other:?}"),
+            },
+        };
+
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        builder::Builder, plan::Plan, reporting::Quiet, starlark::Reader, testing::workspace,
+    };
+
+    fn request(socket: &Path, command: &str) -> serde_json::Value {
+        let mut stream = UnixStream::connect(socket).unwrap();
+        writeln!(stream, "{command}").unwrap();
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn status_then_cancel() {
+        let dir = workspace([("FFS", r#"task("slow", "sleep 30")"#)]);
+        let root = dir.path().to_path_buf();
+        let socket = root.join("control.sock");
+
+        let executor = Arc::new(Executor::new(Arc::new(Quiet)));
+        let _control = ControlSocket::listen(&socket, Arc::clone(&executor)).unwrap();
+
+        let start = Instant::now();
+        let build = std::thread::spawn({
+            let executor = Arc::clone(&executor);
+            move || {
+                let reader = Reader::new(&root);
+                let plan = Plan::new(&reader, &root, [&"//slow".parse().unwrap()])?;
+                Builder::new(executor, &root).execute(&plan)
+            }
+        });
+
+        let status = loop {
+            let status = request(&socket, "status");
+            if status["running"] != serde_json::json!([]) {
+                break status;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(status, serde_json::json!({"running": ["//slow"]}));
+
+        assert_eq!(request(&socket, "cancel"), serde_json::json!({"ok": true}));
+
+        assert!(build.join().unwrap().is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
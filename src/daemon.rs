@@ -0,0 +1,321 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::Duration,
+};
+
+use eyre::WrapErr;
+use serde::Serialize;
+
+use crate::{
+    builder::Builder,
+    executor::Executor,
+    reporting::Reporter,
+    starlark::Reader,
+    target::TargetPath,
+    watch::{Changes, Watched},
+};
+
+/// How long FFS file changes must settle before they're parsed again.
+const REFRESH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Serves `run <selector>` requests on `socket` until the process is killed, each connection on
+/// its own thread. Each request gets a stream of JSON events, ending with a `done` event.
+pub fn serve(root: &Path, socket: &Path) -> eyre::Result<()> {
+    if socket.exists() {
+        std::fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)
+        .wrap_err_with(|| format!("Listening on {}", socket.display()))?;
+
+    let daemon = Arc::new(Daemon::new(root));
+    let changes = Changes::new(root)?;
+    std::thread::spawn({
+        let daemon = Arc::clone(&daemon);
+        move || daemon.refresh(&changes)
+    });
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let daemon = Arc::clone(&daemon);
+        std::thread::spawn(move || {
+            if let Err(e) = daemon.handle(stream) {
+                eprintln!("Connection failed: {e:#}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+struct Daemon {
+    root: PathBuf,
+    /// Locked while planning, so each request counts only the files it parsed itself.
+    reader: Mutex<Reader>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Begin {
+        target: String,
+    },
+    Finish {
+        target: String,
+        ms: u128,
+    },
+    Done {
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        parsed: usize,
+    },
+}
+
+impl Daemon {
+    fn new(root: &Path) -> Self {
+        Daemon {
+            root: root.to_path_buf(),
+            reader: Mutex::new(Reader::new(root)),
+        }
+    }
+
+    /// Parses FFS files again as soon as they change, so requests find them ready.
+    fn refresh(&self, changes: &Changes) {
+        let never = AtomicBool::new(false);
+        loop {
+            let files = match changes.next(&Watched::default(), REFRESH_DEBOUNCE, &never) {
+                Ok(Some(files)) => files,
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Stopped watching FFS files: {e:#}");
+                    return;
+                }
+            };
+
+            let reader = self.reader.lock().unwrap();
+            reader.invalidate_stale();
+            for file in files
+                .iter()
+                .map(|f| self.root.join(f))
+                .filter(|f| f.exists())
+            {
+                // A broken file is reported to the next request that reads it.
+                let _ = reader.read(file);
+            }
+        }
+    }
+
+    fn handle(&self, stream: UnixStream) -> eyre::Result<()> {
+        let events = Arc::new(Events(Mutex::new(stream.try_clone()?)));
+
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let result = match line.trim().split_once(' ') {
+                Some(("run", selector)) => self.run(selector, Arc::clone(&events)),
+                _ => Err(eyre::eyre!("Unknown request: {line:?}")),
+            };
+
+            let (result, parsed) = match result {
+                Ok(parsed) => (Ok(()), parsed),
+                Err(e) => (Err(e), 0),
+            };
+            events.send(&Event::Done {
+                ok: result.is_ok(),
+                error: result.err().map(|e| format!("{e:#}")),
+                parsed,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns how many FFS files had to be parsed for this request.
+    fn run(&self, selector: &str, events: Arc<Events>) -> eyre::Result<usize> {
+        let (plan, parsed) = {
+            let reader = self.reader.lock().unwrap();
+            reader.invalidate_stale();
+            let loads_before = reader.loads();
+
+            let plan = crate::plan_selection(&reader, &self.root, &selector.parse()?)?;
+            (plan, reader.loads() - loads_before)
+        };
+
+        let executor = Arc::new(Executor::new(events));
+        Builder::new(executor, &self.root).execute(&plan)?;
+
+        Ok(parsed)
+    }
+}
+
+struct Events(Mutex<UnixStream>);
+
+impl Events {
+    fn send(&self, event: &Event) {
+        let mut stream = self.0.lock().unwrap();
+        let _ = serde_json::to_writer(&mut *stream, event);
+        let _ = stream.write_all(b"\n");
+    }
+}
+
+impl Reporter for Events {
+    fn begin_execute(&self, task: &TargetPath) {
+        self.send(&Event::Begin {
+            target: task.to_string(),
+        });
+    }
+
+    fn finish_execute(&self, task: &TargetPath, took: Duration) {
+        self.send(&Event::Finish {
+            target: task.to_string(),
+            ms: took.as_millis(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::workspace;
+
+    fn request(stream: &mut BufReader<UnixStream>, line: &str) -> Vec<serde_json::Value> {
+        writeln!(stream.get_mut(), "{line}").unwrap();
+
+        let mut events = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+            let done = event["event"] == "done";
+            events.push(event);
+            if done {
+                return events;
+            }
+        }
+    }
+
+    fn connect(dir: &Path) -> BufReader<UnixStream> {
+        let socket = dir.join("daemon.sock");
+        let root = dir.to_path_buf();
+        std::thread::spawn({
+            let socket = socket.clone();
+            move || serve(&root, &socket)
+        });
+
+        open(&socket)
+    }
+
+    /// Another connection to a daemon already started by [`connect`].
+    fn open(socket: &Path) -> BufReader<UnixStream> {
+        loop {
+            if let Ok(stream) = UnixStream::connect(socket) {
+                return BufReader::new(stream);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn second_request_reuses_parsed_files() {
+        let dir = workspace([("FFS", r#"task("a", "true")"#)]);
+        let mut stream = connect(dir.path());
+
+        let first = request(&mut stream, "run //a");
+        assert_eq!(first[0]["event"], "begin");
+        assert_eq!(first[0]["target"], "//a");
+        assert_eq!(first.last().unwrap()["ok"], true);
+        assert_eq!(first.last().unwrap()["parsed"], 1);
+
+        let second = request(&mut stream, "run //a");
+        assert_eq!(second.last().unwrap()["ok"], true);
+        assert_eq!(second.last().unwrap()["parsed"], 0);
+    }
+
+    #[test]
+    fn changed_files_are_reparsed() {
+        let dir = workspace([("FFS", r#"task("a", "true")"#)]);
+        let mut stream = connect(dir.path());
+
+        request(&mut stream, "run //a");
+
+        std::thread::sleep(Duration::from_millis(10));
+        std::fs::write(dir.path().join("FFS"), r#"task("b", "true")"#).unwrap();
+
+        let events = request(&mut stream, "run //b");
+        assert_eq!(events.last().unwrap()["ok"], true);
+        assert_eq!(events.last().unwrap()["parsed"], 1);
+    }
+
+    #[test]
+    fn failures_are_reported() {
+        let dir = workspace([("FFS", r#"task("a", "false")"#)]);
+        let mut stream = connect(dir.path());
+
+        let events = request(&mut stream, "run //a");
+        let done = events.last().unwrap();
+        assert_eq!(done["ok"], false);
+        assert!(done["error"].as_str().unwrap().contains("Task failed: //a"));
+    }
+
+    #[test]
+    fn connections_are_served_concurrently() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("wait", "for i in $(seq 500); do [ -e go ] && exit 0; sleep 0.01; done; exit 1")
+task("go", "touch go")
+"#,
+        )]);
+        let mut waiting = connect(dir.path());
+        let mut going = open(&dir.path().join("daemon.sock"));
+
+        writeln!(waiting.get_mut(), "run //wait").unwrap();
+        assert_eq!(request(&mut going, "run //go").last().unwrap()["ok"], true);
+
+        let mut done = String::new();
+        while !done.contains("done") {
+            done.clear();
+            waiting.read_line(&mut done).unwrap();
+        }
+        assert!(done.contains(r#""ok":true"#), "{done}");
+    }
+
+    #[test]
+    fn changed_ffs_files_are_parsed_ahead_of_requests() {
+        let dir = workspace([("FFS", r#"task("a", "true")"#)]);
+        let root = dir.path();
+        let daemon = Arc::new(Daemon::new(root));
+        let changes = Changes::new(root).unwrap();
+        std::thread::spawn({
+            let daemon = Arc::clone(&daemon);
+            move || daemon.refresh(&changes)
+        });
+
+        let loads = || daemon.reader.lock().unwrap().loads();
+        daemon
+            .reader
+            .lock()
+            .unwrap()
+            .read(root.join("FFS"))
+            .unwrap();
+        assert_eq!(loads(), 1);
+
+        std::thread::sleep(Duration::from_millis(10));
+        std::fs::write(root.join("FFS"), r#"task("b", "true")"#).unwrap();
+
+        let start = std::time::Instant::now();
+        while loads() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10), "never reparsed");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::{io::Read, path::Path};
+
+use sha2::{Digest, Sha256};
+
+/// Parses a `sha256:<hex>` checksum into its lowercase hex digest.
+pub fn parse_sha256(s: &str) -> eyre::Result<String> {
+    let Some(hex) = s.strip_prefix("sha256:") else {
+        eyre::bail!("Checksum must start with sha256: {s:?}");
+    };
+    eyre::ensure!(
+        hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        "Invalid sha256 digest: {hex:?}"
+    );
+
+    Ok(hex.to_ascii_lowercase())
+}
+
+pub fn sha256(bytes: impl AsRef<[u8]>) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+pub fn sha256_file(path: &Path) -> eyre::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+
+    let mut buf = [0; 8192];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hex(&hasher.finalize()))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sha256() {
+        let digest = "98EA6E4F216F2FB4B69FFF9B3A44842C38686CA685F3F55DC48C5D3FB1107BE4";
+        assert_eq!(
+            parse_sha256(&format!("sha256:{digest}")).unwrap(),
+            digest.to_ascii_lowercase()
+        );
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let cases = ["98ea6e4f", "md5:98ea6e4f", "sha256:xyz", "sha256:98ea6e4f"];
+
+        for c in cases {
+            assert!(parse_sha256(c).is_err(), "{c:?} parsed as sha256");
+        }
+    }
+}
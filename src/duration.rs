@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid duration {0:?}, expected a number with an optional ms/s/m/h suffix")]
+pub struct InvalidDuration(String);
+
+/// Parses durations like `500ms`, `30s`, `10m`, or `2h`. Bare numbers are seconds.
+pub fn parse(s: &str) -> Result<Duration, InvalidDuration> {
+    let invalid = || InvalidDuration(s.to_string());
+
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+
+    Ok(match unit {
+        "ms" => Duration::from_millis(number),
+        "" | "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number * 60),
+        "h" => Duration::from_secs(number * 60 * 60),
+        _ => return Err(invalid()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        assert_eq!(parse("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse("2h").unwrap(), Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn invalid() {
+        for s in ["", "m", "10x", "-1s", "1.5s"] {
+            assert!(parse(s).is_err(), "{s:?} parsed as duration");
+        }
+    }
+}
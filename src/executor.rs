@@ -1,42 +1,839 @@
-use std::{path::Path, process::Output, sync::Arc, time::Instant};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io::{IsTerminal, Read, Write},
+    path::Path,
+    process::{Child, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
-use crate::{os::Os, reporting::Reporter, target::TargetPath};
+use crate::{
+    arch::Arch,
+    os::Os,
+    reporting::{ChildrenColor, Reporter},
+    target::TargetPath,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How much of each stream is kept once it has been forwarded live. Everything was already shown,
+/// so only enough to explain a failure is held in memory.
+const STREAMED_TAIL: usize = 64 * 1024;
 
 pub struct Executor {
     reporter: Arc<dyn Reporter>,
+
+    cancelled: AtomicBool,
+    /// Set from outside, e.g. by a Ctrl-C handler, to cancel like [`Executor::cancel`].
+    interrupted: Option<&'static AtomicBool>,
+    running: Mutex<BTreeSet<TargetPath>>,
+
+    /// Size of the terminal ffs is attached to, passed on to children as `COLUMNS`/`LINES` since
+    /// their output is captured instead.
+    terminal: Option<(u16, u16)>,
+
+    /// Where child output is forwarded as it arrives, on top of being captured.
+    stream: Option<Streams>,
+    /// How escape codes in child output are treated wherever it is shown.
+    children_color: ChildrenColor,
+    /// Whether each forwarded line starts with the target that printed it.
+    prefix_lines: bool,
+}
+
+/// Whether child output is forwarded live, or only captured until the command finishes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputMode {
+    /// Stream when stdout is a terminal, otherwise buffer.
+    #[default]
+    Auto,
+    Stream,
+    Buffer,
+}
+
+impl OutputMode {
+    fn streams(self) -> bool {
+        match self {
+            OutputMode::Auto => std::io::stdout().is_terminal(),
+            OutputMode::Stream => true,
+            OutputMode::Buffer => false,
+        }
+    }
+}
+
+type Sink = Arc<Mutex<dyn Write + Send>>;
+
+struct Streams {
+    stdout: Sink,
+    stderr: Sink,
 }
 
 impl Executor {
     pub(crate) fn new(reporter: Arc<dyn Reporter>) -> Self {
-        Self { reporter }
+        Self {
+            reporter,
+
+            cancelled: AtomicBool::new(false),
+            interrupted: None,
+            running: Default::default(),
+
+            terminal: terminal_size::terminal_size().map(|(w, h)| (w.0, h.0)),
+
+            stream: None,
+            children_color: ChildrenColor::default(),
+            prefix_lines: false,
+        }
+    }
+
+    pub fn output_mode(self, mode: OutputMode) -> Self {
+        if !mode.streams() {
+            return self;
+        }
+        if self.reporter.owns_stdout() {
+            return self.stream_to(std::io::stderr(), std::io::stderr());
+        }
+        self.stream_to(std::io::stdout(), std::io::stderr())
+    }
+
+    pub(crate) fn stream_to(
+        mut self,
+        stdout: impl Write + Send + 'static,
+        stderr: impl Write + Send + 'static,
+    ) -> Self {
+        self.stream = Some(Streams {
+            stdout: Arc::new(Mutex::new(stdout)),
+            stderr: Arc::new(Mutex::new(stderr)),
+        });
+        self
+    }
+
+    pub fn children_color(mut self, color: ChildrenColor) -> Self {
+        self.children_color = color;
+        self
+    }
+
+    /// Cancel once `flag` is set, killing in-flight commands along with their subprocesses.
+    pub fn cancel_on(mut self, flag: &'static AtomicBool) -> Self {
+        self.interrupted = Some(flag);
+        self
+    }
+
+    /// Start each forwarded line with `[<target>] `, so concurrent commands can be told apart.
+    pub fn prefix_lines(mut self, prefix: bool) -> Self {
+        self.prefix_lines = prefix;
+        self
+    }
+
+    /// Whether child output has already been shown as it was produced.
+    pub fn is_streaming(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Shows output that was only captured, for a command that failed without streaming.
+    pub fn show_captured(&self, output: &Output) -> std::io::Result<()> {
+        let color = self.children_color;
+        let stdout = color.apply(&output.stdout);
+        if self.reporter.owns_stdout() {
+            std::io::stderr().lock().write_all(&stdout)?;
+        } else {
+            std::io::stdout().lock().write_all(&stdout)?;
+        }
+        std::io::stderr()
+            .lock()
+            .write_all(&color.apply(&output.stderr))
+    }
+
+    /// Shows output captured from an earlier run of `path`'s commands the way it was shown
+    /// then: forwarded when streaming, otherwise not at all.
+    pub fn replay(&self, path: &TargetPath, stdout: &[u8], stderr: &[u8]) -> eyre::Result<()> {
+        let Some(streams) = &self.stream else {
+            return Ok(());
+        };
+        self.forward(path, &streams.stdout)
+            .write(stdout, &mut true)?;
+        self.forward(path, &streams.stderr)
+            .write(stderr, &mut true)?;
+        Ok(())
+    }
+
+    fn forward(&self, path: &TargetPath, sink: &Sink) -> Forward {
+        Forward {
+            sink: Arc::clone(sink),
+            reporter: Arc::clone(&self.reporter),
+            prefix: self.prefix_lines.then(|| format!("[{path}] ")),
+            color: self.children_color,
+        }
     }
 
     pub fn execute(&self, e: Execution) -> eyre::Result<Output> {
         if let Some(runs_on) = e.runs_on {
             let host = crate::os::host();
             eyre::ensure!(
-                runs_on == &host,
-                "Cannot run job requiring {runs_on:?} on {host:?}"
+                crate::os::allows(runs_on, host),
+                "Cannot run job requiring {} on {host:?}",
+                crate::os::describe(runs_on)
             );
         }
+        if let Some(arch) = e.arch {
+            let host = crate::arch::host();
+            eyre::ensure!(
+                arch == &host,
+                "Cannot run job requiring {arch:?} on {host:?}"
+            );
+        }
+        eyre::ensure!(!self.is_cancelled(), "Cancelled before running {}", e.path);
+        if e.dry_run {
+            return Ok(Output {
+                status: ExitStatus::default(),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        }
 
         self.reporter.begin_execute(e.path);
+        self.reporter
+            .command(e.path, &redact(e.command, &self.env_for(e.env)));
+        self.running.lock().unwrap().insert(e.path.clone());
         let start = Instant::now();
-        let output = std::process::Command::new("sh")
-            .current_dir(e.dir)
-            .arg("-e")
-            .arg("-c")
-            .arg(e.command)
-            .output()?;
+
+        let output = self.run_to_completion(&e);
+
+        self.running.lock().unwrap().remove(e.path);
         self.reporter.finish_execute(e.path, start.elapsed());
 
-        Ok(output)
+        output
+    }
+
+    pub fn reporter(&self) -> &dyn Reporter {
+        &*self.reporter
+    }
+
+    /// Kills any in-flight commands and prevents new ones from starting.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Cancels execution once `budget` has elapsed, unless the returned [`Deadline`] is dropped
+    /// first.
+    pub fn cancel_after(self: &Arc<Self>, budget: Duration) -> Deadline {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let expired = Arc::new(AtomicBool::new(false));
+
+        std::thread::spawn({
+            let executor = Arc::clone(self);
+            let expired = Arc::clone(&expired);
+            move || {
+                if let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(budget) {
+                    expired.store(true, Ordering::SeqCst);
+                    executor.cancel();
+                }
+            }
+        });
+
+        Deadline {
+            expired,
+            _stop: stop,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self.interrupted.is_some_and(|i| i.load(Ordering::SeqCst))
+    }
+
+    pub fn running(&self) -> Vec<TargetPath> {
+        self.running.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Environment variables set for every child, on top of the inherited environment.
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        match self.terminal {
+            Some((columns, lines)) => vec![
+                ("COLUMNS", columns.to_string()),
+                ("LINES", lines.to_string()),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    /// Variables set on top of the inherited environment: ffs's own, overridden by the
+    /// target's. Sorted, so commands and audit records see a deterministic order.
+    pub fn env_for(
+        &self,
+        target_env: Option<&BTreeMap<String, String>>,
+    ) -> BTreeMap<String, String> {
+        let own = self.env().into_iter().map(|(k, v)| (k.to_string(), v));
+        let target = target_env
+            .into_iter()
+            .flatten()
+            .map(|(k, v)| (k.clone(), v.clone()));
+        own.chain(target).collect()
+    }
+
+    /// The complete environment a command would run with.
+    pub fn resolved_env(
+        &self,
+        target_env: Option<&BTreeMap<String, String>>,
+    ) -> BTreeMap<String, String> {
+        std::env::vars().chain(self.env_for(target_env)).collect()
+    }
+
+    fn run_to_completion(&self, e: &Execution) -> eyre::Result<Output> {
+        let mut command = match (e.argv, e.shell) {
+            (Some([program, args @ ..]), _) | (None, Some([program, args @ ..])) => {
+                let mut command = std::process::Command::new(program);
+                command.args(args);
+                command
+            }
+            _ => {
+                let (program, args) = shell_for(crate::os::host());
+                let mut command = std::process::Command::new(program);
+                command.args(args);
+                command
+            }
+        };
+        if e.argv.is_none() {
+            let script = match e.shell {
+                Some(_) => Cow::Borrowed(e.command),
+                None => default_shell_script(crate::os::host(), e.command),
+            };
+            command.arg(&*script);
+        }
+        eyre::ensure!(
+            e.dir.is_dir(),
+            "Working directory {} of {} does not exist",
+            e.dir.display(),
+            e.path
+        );
+        let stdin = match e.stdin {
+            Some(file) => std::fs::File::open(file)
+                .map_err(|err| eyre::eyre!("Cannot open stdin {}: {err}", file.display()))?
+                .into(),
+            None => Stdio::null(),
+        };
+        command
+            .current_dir(e.dir)
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        command.envs(self.env_for(e.env));
+
+        let mut child = command.spawn()?;
+        let forward = |sink: fn(&Streams) -> &Sink| {
+            self.stream.as_ref().map(|s| self.forward(e.path, sink(s)))
+        };
+        let stdout = read_all(child.stdout.take(), forward(|s| &s.stdout));
+        let stderr = read_all(child.stderr.take(), forward(|s| &s.stderr));
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if self.is_cancelled() {
+                kill(&mut child)?;
+                eyre::bail!("Cancelled {}", e.path);
+            }
+            if let Some(timeout) = e.timeout.filter(|t| start.elapsed() >= *t) {
+                kill(&mut child)?;
+                let stdout = stdout.join().expect("reader panicked")?;
+                let stderr = stderr.join().expect("reader panicked")?;
+                eyre::bail!(
+                    "{} timed out after {}s\nstdout:\n{}\nstderr:\n{}",
+                    e.path,
+                    timeout.as_secs_f64(),
+                    String::from_utf8_lossy(&stdout),
+                    String::from_utf8_lossy(&stderr),
+                );
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().expect("reader panicked")?,
+            stderr: stderr.join().expect("reader panicked")?,
+        })
+    }
+}
+
+pub struct Deadline {
+    expired: Arc<AtomicBool>,
+    _stop: mpsc::Sender<()>,
+}
+
+impl Deadline {
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
+
+struct Forward {
+    sink: Sink,
+    /// Told before each write, so it can keep what it draws out of the way.
+    reporter: Arc<dyn Reporter>,
+    prefix: Option<String>,
+    color: ChildrenColor,
+}
+
+impl Forward {
+    /// Writes `bytes`, prefixing each line when `line_start` says the last write ended one.
+    fn write(&self, bytes: &[u8], line_start: &mut bool) -> std::io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let bytes = self.color.apply(bytes);
+        let mut result = Ok(());
+        self.reporter.interleave(bytes.ends_with(b"\n"), &mut || {
+            result = self.write_now(&bytes, line_start)
+        });
+        result
+    }
+
+    fn write_now(&self, bytes: &[u8], line_start: &mut bool) -> std::io::Result<()> {
+        let mut sink = self.sink.lock().unwrap();
+        match &self.prefix {
+            Some(prefix) => {
+                for line in bytes.split_inclusive(|b| *b == b'\n') {
+                    if *line_start {
+                        sink.write_all(prefix.as_bytes())?;
+                    }
+                    sink.write_all(line)?;
+                    *line_start = line.ends_with(b"\n");
+                }
+            }
+            None => sink.write_all(bytes)?,
+        }
+        sink.flush()
+    }
+}
+
+/// The default interpreter on `host`, and the arguments that come before the command.
+pub fn shell_for(host: Os) -> (&'static str, &'static [&'static str]) {
+    match host {
+        Os::Linux | Os::Mac | Os::FreeBSD => ("sh", &["-e", "-c"]),
+        Os::Windows => ("cmd", &["/C"]),
+    }
+}
+
+/// Adapts `command` for the default shell on `host`. `cmd` has no `-e`, so on Windows each line
+/// is chained with `&&` to stop at the first failure.
+fn default_shell_script(host: Os, command: &str) -> Cow<'_, str> {
+    match host {
+        Os::Linux | Os::Mac | Os::FreeBSD => Cow::Borrowed(command),
+        Os::Windows => Cow::Owned(
+            command
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" && "),
+        ),
+    }
+}
+
+/// Captures everything from `pipe`, also forwarding each chunk to `forward` as it arrives. Once
+/// forwarded, only the last [`STREAMED_TAIL`] bytes are kept.
+fn read_all(
+    pipe: Option<impl Read + Send + 'static>,
+    forward: Option<Forward>,
+) -> JoinHandle<std::io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let Some(mut pipe) = pipe else {
+            return Ok(buf);
+        };
+
+        let mut chunk = [0; 4096];
+        let mut line_start = true;
+        loop {
+            let n = pipe.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+
+            let Some(forward) = &forward else {
+                continue;
+            };
+            forward.write(&chunk[..n], &mut line_start)?;
+
+            if buf.len() > 2 * STREAMED_TAIL {
+                buf.drain(..buf.len() - STREAMED_TAIL);
+            }
+        }
+
+        if forward.is_some() && buf.len() > STREAMED_TAIL {
+            buf.drain(..buf.len() - STREAMED_TAIL);
+        }
+        Ok(buf)
+    })
+}
+
+/// Kills the child along with any processes it spawned.
+fn kill(child: &mut Child) -> std::io::Result<()> {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
     }
+    #[cfg(not(unix))]
+    child.kill()?;
+
+    child.wait()?;
+    Ok(())
+}
+
+/// Whether the variable `key` likely holds a credential, and so shouldn't be shown.
+pub fn is_secret(key: &str) -> bool {
+    ["SECRET", "TOKEN", "PASSWORD", "KEY"]
+        .iter()
+        .any(|s| key.to_uppercase().contains(s))
 }
 
+/// `command` with the values of secret variables in `env` replaced by `<redacted>`.
+fn redact<'c>(command: &'c str, env: &BTreeMap<String, String>) -> Cow<'c, str> {
+    env.iter()
+        .filter(|(key, value)| is_secret(key) && !value.is_empty())
+        .fold(Cow::Borrowed(command), |command, (_, value)| {
+            if command.contains(value.as_str()) {
+                Cow::Owned(command.replace(value.as_str(), "<redacted>"))
+            } else {
+                command
+            }
+        })
+}
+
+#[derive(Clone, Copy)]
 pub struct Execution<'l> {
     pub path: &'l TargetPath,
     pub command: &'l str,
     pub dir: &'l Path,
-    pub runs_on: Option<&'l Os>,
+    pub runs_on: Option<&'l HashSet<Os>>,
+    pub arch: Option<&'l Arch>,
+    pub env: Option<&'l BTreeMap<String, String>>,
+    /// Kill the command once it has run this long.
+    pub timeout: Option<Duration>,
+    /// Succeed without running anything.
+    pub dry_run: bool,
+    /// Interpreter the command is passed to as its last argument, or the host's [`shell_for`].
+    pub shell: Option<&'l [String]>,
+    /// File the command reads as stdin, instead of nothing.
+    pub stdin: Option<&'l Path>,
+    /// Program and arguments run directly, without a shell. `command` is then only shown.
+    pub argv: Option<&'l [String]>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{reporting::Quiet, testing::execution};
+
+    fn run(executor: &Executor, command: &str) -> String {
+        let output = executor
+            .execute(execution(&"//test".parse().unwrap(), command))
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn buffer_mode_captures_fully() {
+        let executor = Executor::new(Arc::new(Quiet)).output_mode(OutputMode::Buffer);
+
+        assert!(!executor.is_streaming());
+        assert_eq!(run(&executor, "echo first; echo second"), "first\nsecond\n");
+    }
+
+    #[test]
+    fn stream_mode_forwards_incrementally() {
+        use std::io::{BufRead, BufReader};
+
+        let dir = tempfile::tempdir().unwrap();
+        let go = dir.path().join("go");
+
+        let (reader, writer) = std::io::pipe().unwrap();
+        let executor = Arc::new(Executor::new(Arc::new(Quiet)).stream_to(writer, std::io::sink()));
+
+        let command = format!(
+            "echo first; while [ ! -e {} ]; do sleep 0.01; done; echo second",
+            go.display()
+        );
+        let running = std::thread::spawn({
+            let executor = Arc::clone(&executor);
+            move || run(&executor, &command)
+        });
+
+        // The command blocks until `go` exists, so this line must have been forwarded live.
+        let mut lines = BufReader::new(reader);
+        let mut line = String::new();
+        lines.read_line(&mut line).unwrap();
+        assert_eq!(line, "first\n");
+
+        std::fs::write(&go, "").unwrap();
+        assert_eq!(running.join().unwrap(), "first\nsecond\n");
+    }
+
+    #[test]
+    fn streamed_output_strips_color() {
+        let dir = tempfile::tempdir().unwrap();
+        let streamed = dir.path().join("streamed");
+        let file = std::fs::File::create(&streamed).unwrap();
+        let executor = Executor::new(Arc::new(Quiet))
+            .children_color(ChildrenColor::Strip)
+            .stream_to(file, std::io::sink());
+
+        let captured = run(&executor, "printf '\\033[31mred\\033[0m\\n'");
+
+        assert_eq!(captured, "\x1b[31mred\x1b[0m\n");
+        assert_eq!(std::fs::read_to_string(streamed).unwrap(), "red\n");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn terminal_size_from_pty() {
+        let (mut master, mut slave) = (0, 0);
+        let size = libc::winsize {
+            ws_row: 45,
+            ws_col: 123,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        assert_eq!(result, 0);
+
+        let mut executor = Executor::new(Arc::new(Quiet));
+        executor.terminal = terminal_size::terminal_size_using_fd(slave).map(|(w, h)| (w.0, h.0));
+        unsafe {
+            libc::close(master);
+            libc::close(slave);
+        }
+
+        assert_eq!(run(&executor, "echo $COLUMNS $LINES"), "123 45\n");
+    }
+
+    #[test]
+    fn timeout_kills_command() {
+        let executor = Executor::new(Arc::new(Quiet));
+
+        let start = Instant::now();
+        let err = executor
+            .execute(Execution {
+                timeout: Some(Duration::from_secs(1)),
+                ..execution(&"//slow".parse().unwrap(), "echo started; sh -c 'sleep 5'")
+            })
+            .unwrap_err();
+
+        assert!(start.elapsed() < Duration::from_secs(3));
+        let message = err.to_string();
+        assert!(
+            message.starts_with("//slow timed out after 1s"),
+            "{message}"
+        );
+        assert!(message.contains("started"), "{message}");
+    }
+
+    #[test]
+    fn runs_on_any_os_in_set() {
+        let executor = Executor::new(Arc::new(Quiet));
+        let execute = |runs_on: &HashSet<Os>| {
+            executor.execute(Execution {
+                runs_on: Some(runs_on),
+                ..execution(&"//test".parse().unwrap(), "true")
+            })
+        };
+        let host = crate::os::host();
+        let others = [Os::Linux, Os::Mac, Os::Windows, Os::FreeBSD]
+            .into_iter()
+            .filter(|os| *os != host)
+            .collect::<HashSet<_>>();
+
+        execute(&HashSet::new()).unwrap();
+        execute(&HashSet::from([host])).unwrap();
+        execute(&others.iter().copied().chain([host]).collect()).unwrap();
+
+        let err = execute(&others).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Cannot run job requiring {} on {host:?}",
+                crate::os::describe(&others)
+            )
+        );
+    }
+
+    #[test]
+    fn other_arch_is_rejected() {
+        let executor = Executor::new(Arc::new(Quiet));
+        let other = match crate::arch::host() {
+            Arch::X86_64 => Arch::Aarch64,
+            Arch::Aarch64 => Arch::X86_64,
+        };
+
+        let err = executor
+            .execute(Execution {
+                arch: Some(&other),
+                ..execution(&"//test".parse().unwrap(), "true")
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Cannot run job requiring {other:?} on {:?}",
+                crate::arch::host()
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn interrupt_kills_subprocesses() {
+        static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+        let dir = tempfile::tempdir().unwrap();
+        let executor = Executor::new(Arc::new(Quiet)).cancel_on(&INTERRUPTED);
+        let wait_until = |done: &dyn Fn() -> bool, what: &str| {
+            let start = Instant::now();
+            while !done() {
+                assert!(start.elapsed() < Duration::from_secs(5), "{what}");
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        };
+
+        std::thread::scope(|s| {
+            let running = s.spawn(|| {
+                executor.execute(Execution {
+                    dir: dir.path(),
+                    ..execution(
+                        &"//test".parse().unwrap(),
+                        "sleep 30 & echo $! > sleeper; wait",
+                    )
+                })
+            });
+
+            let sleeper = || {
+                std::fs::read_to_string(dir.path().join("sleeper"))
+                    .ok()?
+                    .trim()
+                    .parse::<libc::pid_t>()
+                    .ok()
+            };
+            wait_until(&|| sleeper().is_some(), "sleeper never started");
+            let pid = sleeper().unwrap();
+
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            let err = running.join().unwrap().unwrap_err();
+            assert_eq!(err.to_string(), "Cancelled //test");
+
+            // Orphans may linger as zombies until their new parent reaps them.
+            let alive = || {
+                let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"));
+                let zombie = stat.is_ok_and(|s| s.contains(") Z "));
+                unsafe { libc::kill(pid, 0) == 0 && !zombie }
+            };
+            wait_until(&|| !alive(), "sleeper outlived the interrupt");
+        });
+    }
+
+    #[test]
+    fn streamed_lines_are_prefixed() {
+        let dir = tempfile::tempdir().unwrap();
+        let streamed = dir.path().join("streamed");
+        let file = std::fs::File::create(&streamed).unwrap();
+        let executor = Executor::new(Arc::new(Quiet))
+            .stream_to(file, std::io::sink())
+            .prefix_lines(true);
+
+        let captured = run(
+            &executor,
+            "printf 'one\\ntw'; sleep 0.05; printf 'o\\nthree\\n'",
+        );
+
+        assert_eq!(captured, "one\ntwo\nthree\n");
+        assert_eq!(
+            std::fs::read_to_string(streamed).unwrap(),
+            "[//test] one\n[//test] two\n[//test] three\n"
+        );
+    }
+
+    #[test]
+    fn streamed_output_keeps_only_tail() {
+        let executor = Executor::new(Arc::new(Quiet)).stream_to(std::io::sink(), std::io::sink());
+
+        let captured = run(
+            &executor,
+            "head -c 1000000 /dev/zero | tr '\\0' x; echo end",
+        );
+
+        assert_eq!(captured.len(), STREAMED_TAIL);
+        assert!(captured.ends_with("xend\n"));
+    }
+
+    #[test]
+    fn argv_runs_without_shell() {
+        let executor = Executor::new(Arc::new(Quiet));
+        let argv = ["printf", "%s", "$HOME; echo injected"].map(String::from);
+
+        let output = executor
+            .execute(Execution {
+                argv: Some(&argv),
+                ..execution(
+                    &"//test".parse().unwrap(),
+                    "printf %s '$HOME; echo injected'",
+                )
+            })
+            .unwrap();
+
+        assert_eq!(output.stdout, b"$HOME; echo injected");
+    }
+
+    #[test]
+    fn missing_stdin_fails_before_spawning() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = Executor::new(Arc::new(Quiet));
+
+        let err = executor
+            .execute(Execution {
+                dir: dir.path(),
+                stdin: Some(&dir.path().join("missing")),
+                ..execution(&"//test".parse().unwrap(), "touch ran")
+            })
+            .unwrap_err();
+
+        assert!(err.to_string().starts_with("Cannot open stdin "), "{err}");
+        assert!(!dir.path().join("ran").exists());
+    }
+
+    #[test]
+    fn shell_selection() {
+        assert_eq!(shell_for(Os::Linux), ("sh", &["-e", "-c"][..]));
+        assert_eq!(shell_for(Os::Mac), ("sh", &["-e", "-c"][..]));
+        assert_eq!(shell_for(Os::FreeBSD), ("sh", &["-e", "-c"][..]));
+        assert_eq!(shell_for(Os::Windows), ("cmd", &["/C"][..]));
+    }
+
+    #[test]
+    fn windows_commands_stop_at_first_failure() {
+        let command = "mkdir out\n\n  copy a out\n";
+
+        assert_eq!(default_shell_script(Os::Linux, command), command);
+        assert_eq!(
+            default_shell_script(Os::Windows, command),
+            "mkdir out && copy a out"
+        );
+    }
 }
@@ -0,0 +1,125 @@
+use std::{collections::BTreeMap, path::PathBuf, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::target::TargetPath;
+
+/// Bumped whenever what a fingerprint covers changes, so older files are discarded.
+const VERSION: u32 = 1;
+
+/// The inputs each build last succeeded with, persisted across runs so unchanged builds can be
+/// skipped.
+///
+/// Stored as JSON, written to a temporary file and renamed into place so a crash never leaves a
+/// partial file.
+pub struct Fingerprints {
+    file: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    targets: BTreeMap<String, String>,
+    changed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    version: u32,
+    targets: BTreeMap<String, String>,
+}
+
+impl Fingerprints {
+    /// Reads `file`, starting empty if it is missing, corrupt, or from another version.
+    pub fn load(file: impl Into<PathBuf>) -> Self {
+        let file = file.into();
+        let targets = std::fs::read(&file)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Stored>(&bytes).ok())
+            .filter(|stored| stored.version == VERSION)
+            .map(|stored| stored.targets)
+            .unwrap_or_default();
+
+        Self {
+            file,
+            state: Mutex::new(State {
+                targets,
+                changed: false,
+            }),
+        }
+    }
+
+    /// Whether `target` last succeeded with exactly this fingerprint.
+    pub fn matches(&self, target: &TargetPath, fingerprint: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .targets
+            .get(&target.to_string())
+            .is_some_and(|f| f == fingerprint)
+    }
+
+    pub fn record(&self, target: &TargetPath, fingerprint: String) {
+        let mut state = self.state.lock().unwrap();
+        let previous = state
+            .targets
+            .insert(target.to_string(), fingerprint.clone());
+        state.changed |= previous.as_ref() != Some(&fingerprint);
+    }
+
+    /// Writes the fingerprints, if any were recorded since loading.
+    pub fn save(&self) -> eyre::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.changed {
+            return Ok(());
+        }
+
+        let stored = Stored {
+            version: VERSION,
+            targets: state.targets.clone(),
+        };
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let partial = self.file.with_extension("json.partial");
+        std::fs::write(&partial, serde_json::to_vec_pretty(&stored)?)?;
+        std::fs::rename(&partial, &self.file)?;
+
+        state.changed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(".ffs/fingerprints.json");
+        let target = "//a".parse().unwrap();
+
+        let fingerprints = Fingerprints::load(&file);
+        assert!(!fingerprints.matches(&target, "abc"));
+        fingerprints.record(&target, "abc".to_string());
+        fingerprints.save().unwrap();
+
+        let fingerprints = Fingerprints::load(&file);
+        assert!(fingerprints.matches(&target, "abc"));
+        assert!(!fingerprints.matches(&target, "def"));
+    }
+
+    #[test]
+    fn corrupt_and_old_files_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("fingerprints.json");
+        let target = "//a".parse().unwrap();
+
+        std::fs::write(&file, "{not json").unwrap();
+        assert!(!Fingerprints::load(&file).matches(&target, "abc"));
+
+        let old = serde_json::json!({"version": VERSION + 1, "targets": {"//a": "abc"}});
+        std::fs::write(&file, old.to_string()).unwrap();
+        assert!(!Fingerprints::load(&file).matches(&target, "abc"));
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use starlark::syntax::{AstModule, Dialect};
+
+use crate::{starlark::located, target::Selector};
+
+/// Calls up to this long are kept on one line.
+const MAX_WIDTH: usize = 100;
+
+/// Spaces per level of indentation.
+const INDENT: usize = 4;
+
+/// Formats every FFS file matching `selector`, returning those that weren't already formatted,
+/// relative to `root`. With `check`, files are only compared, never rewritten.
+pub fn fmt(root: &Path, selector: &Selector, check: bool) -> eyre::Result<Vec<PathBuf>> {
+    let mut unformatted = Vec::new();
+
+    for entry in ignore::Walk::new(root) {
+        let entry = entry?;
+
+        let is_ffs_file = entry.path().file_name().is_some_and(|f| f == "FFS");
+        if !is_ffs_file {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(root)?;
+        if !selector.matches_file(relative) {
+            continue;
+        }
+
+        let source = std::fs::read_to_string(entry.path())?;
+        let formatted = format(&format!("//{}", relative.display()), &source)?;
+        if formatted == source {
+            continue;
+        }
+        if !check {
+            std::fs::write(entry.path(), &formatted)?;
+        }
+        unformatted.push(relative.to_path_buf());
+    }
+
+    unformatted.sort();
+    Ok(unformatted)
+}
+
+/// `source` of the FFS file at `path` in canonical style: blocks indented four spaces, no
+/// trailing whitespace or runs of blank lines, and top-level `task()` and `build()` calls laid
+/// out with their keyword arguments in declaration order. Comments are kept, and calls
+/// containing them are left as written.
+pub fn format(path: &str, source: &str) -> eyre::Result<String> {
+    AstModule::parse(path, source.to_string(), &Dialect::Standard)
+        .map_err(|e| eyre::eyre!(located(e)))?;
+
+    let reindented = reindent(source);
+    if reindented.is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut formatted = String::new();
+    for statement in statements(&reindented.join("\n")) {
+        match call(&statement) {
+            Some(call) => formatted.push_str(&call),
+            None => formatted.push_str(&statement),
+        }
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+/// Lines of `source` with blocks indented [`INDENT`] spaces per level, continuation lines moved
+/// along with their statement, and without trailing whitespace or repeated blank lines. Lines
+/// inside string literals are kept as they are.
+fn reindent(source: &str) -> Vec<String> {
+    let mut reindented = Vec::<String>::new();
+    // Original indentation of each enclosing block.
+    let mut blocks = vec![0];
+    // How far the current statement's first line moved.
+    let mut shift = 0;
+    let mut blank = true;
+
+    for line in lines(source) {
+        let text = match line.open_string {
+            true => line.text,
+            false => line.text.trim_end(),
+        };
+        if line.in_string {
+            reindented.push(text.to_string());
+            blank = false;
+            continue;
+        }
+
+        let code = text.trim_start();
+        if code.is_empty() {
+            if !blank {
+                reindented.push(String::new());
+            }
+            blank = true;
+            continue;
+        }
+        blank = false;
+
+        let width = text.len() - code.len();
+        if line.continued {
+            let width = (width as isize + shift).max(0) as usize;
+            reindented.push(format!("{}{code}", " ".repeat(width)));
+            continue;
+        }
+
+        if !code.starts_with('#') {
+            while blocks.last().is_some_and(|&w| w > width) {
+                blocks.pop();
+            }
+            if blocks.last() != Some(&width) {
+                blocks.push(width);
+            }
+        }
+        let level = blocks.iter().filter(|&&w| w < width).count();
+        shift = (level * INDENT) as isize - width as isize;
+        reindented.push(format!("{}{code}", " ".repeat(level * INDENT)));
+    }
+
+    if blank {
+        reindented.pop();
+    }
+    reindented
+}
+
+/// Each statement of `source`, along with any lines it continues onto.
+fn statements(source: &str) -> Vec<String> {
+    let mut statements = Vec::<String>::new();
+    for line in lines(source) {
+        match statements.last_mut() {
+            Some(statement) if line.in_string || line.continued => {
+                statement.push('\n');
+                statement.push_str(line.text);
+            }
+            _ => statements.push(line.text.to_string()),
+        }
+    }
+    statements
+}
+
+/// `statement` laid out canonically, if it's a top-level `task()` or `build()` call without
+/// comments, multi-line strings, or unpacked arguments.
+fn call(statement: &str) -> Option<String> {
+    let (function, rest) = statement.split_once('(')?;
+    let function = function.trim_end();
+    if !matches!(function, "task" | "build") {
+        return None;
+    }
+    let params = crate::starlark::params(function);
+
+    let mut args = split_args(rest.strip_suffix(')')?)?;
+    if args.last() == Some(&"") {
+        args.pop();
+    }
+    if args.iter().any(|a| a.is_empty() || a.starts_with('*')) {
+        return None;
+    }
+
+    let mut keyword = args
+        .iter()
+        .filter_map(|arg| {
+            let (name, value) = arg.split_once('=')?;
+            let name = name.trim_end();
+            let is_ident = !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+            (is_ident && !value.starts_with('=')).then_some((name, value.trim_start()))
+        })
+        .collect::<Vec<_>>();
+    keyword.sort_by_key(|(name, _)| {
+        params
+            .iter()
+            .position(|p| p.as_str() == *name)
+            .unwrap_or(params.len())
+    });
+    let positional = &args[..args.len() - keyword.len()];
+
+    let args = positional
+        .iter()
+        .map(|value| indent_value(value))
+        .chain(
+            keyword
+                .iter()
+                .map(|(name, value)| format!("{name} = {}", indent_value(value))),
+        )
+        .collect::<Vec<_>>();
+
+    let one_line = format!("{function}({})", args.join(", "));
+    if !one_line.contains('\n') && one_line.len() <= MAX_WIDTH {
+        return Some(one_line);
+    }
+
+    let mut laid_out = format!("{function}(\n");
+    for arg in args {
+        laid_out.push_str(&format!("{}{arg},\n", " ".repeat(INDENT)));
+    }
+    laid_out.push(')');
+    Some(laid_out)
+}
+
+/// The comma-separated arguments of a call, trimmed, or `None` if they contain comments,
+/// multi-line strings, or close the call early.
+fn split_args(inner: &str) -> Option<Vec<&str>> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (kind, range) in spans(inner) {
+        match kind {
+            Kind::Comment => return None,
+            Kind::Str if inner[range.clone()].contains('\n') => return None,
+            Kind::Str => {}
+            Kind::Code => {
+                for (i, c) in inner[range.clone()].char_indices() {
+                    match c {
+                        '(' | '[' | '{' => depth += 1,
+                        ')' | ']' | '}' if depth == 0 => return None,
+                        ')' | ']' | '}' => depth -= 1,
+                        ',' if depth == 0 => {
+                            args.push(inner[start..range.start + i].trim());
+                            start = range.start + i + 1;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    args.push(inner[start..].trim());
+
+    Some(args)
+}
+
+/// `value` with its lines after the first indented one level into a call, keeping how much
+/// deeper than the shallowest of them each was.
+fn indent_value(value: &str) -> String {
+    let mut lines = value.lines();
+    let mut indented = lines.next().unwrap_or_default().to_string();
+    let rest = lines.collect::<Vec<_>>();
+    let shallowest = rest
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    for line in rest {
+        indented.push('\n');
+        if !line.trim().is_empty() {
+            indented.push_str(&" ".repeat(INDENT));
+            indented.push_str(&line[shallowest..]);
+        }
+    }
+    indented
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Code,
+    Str,
+    Comment,
+}
+
+/// Splits `s` into runs of code, string literals, and comments.
+fn spans(s: &str) -> Vec<(Kind, Range<usize>)> {
+    let mut spans = Vec::new();
+    let mut code = 0;
+    let mut i = 0;
+
+    while let Some(c) = s[i..].chars().next() {
+        let (kind, end) = match c {
+            '#' => (Kind::Comment, s[i..].find('\n').map_or(s.len(), |n| i + n)),
+            '"' | '\'' => (Kind::Str, string_end(s, i)),
+            _ => {
+                i += c.len_utf8();
+                continue;
+            }
+        };
+        if code < i {
+            spans.push((Kind::Code, code..i));
+        }
+        spans.push((kind, i..end));
+        i = end;
+        code = end;
+    }
+    if code < s.len() {
+        spans.push((Kind::Code, code..s.len()));
+    }
+
+    spans
+}
+
+/// Where the string literal whose opening quote is at `start` ends.
+fn string_end(s: &str, start: usize) -> usize {
+    let quote = &s[start..start + 1];
+    let triple = quote.repeat(3);
+    let delimiter = match s[start..].starts_with(&triple) {
+        true => triple.as_str(),
+        false => quote,
+    };
+
+    let mut i = start + delimiter.len();
+    while let Some(c) = s[i..].chars().next() {
+        if s[i..].starts_with(delimiter) {
+            return i + delimiter.len();
+        }
+        i += c.len_utf8();
+        if c == '\\' {
+            i += s[i..].chars().next().map_or(0, char::len_utf8);
+        }
+    }
+    s.len()
+}
+
+/// A line of source, and how it relates to those around it.
+struct Line<'s> {
+    text: &'s str,
+    /// Starts inside a string literal, so all of it is part of the string.
+    in_string: bool,
+    /// Continues the statement of the line before, inside brackets or after a `\`.
+    continued: bool,
+    /// Ends inside a string literal, so its trailing whitespace is part of the string.
+    open_string: bool,
+}
+
+/// Splits `s` into lines, noting which are inside strings or continue a statement.
+fn lines(s: &str) -> Vec<Line<'_>> {
+    // For each line break, whether it is in a string, and otherwise whether the next line
+    // continues a statement.
+    let mut breaks = Vec::new();
+    let mut depth = 0;
+    for (kind, range) in spans(s) {
+        match kind {
+            Kind::Comment => {}
+            Kind::Str => breaks.extend(s[range].matches('\n').map(|_| (true, false))),
+            Kind::Code => {
+                let mut previous = None;
+                for c in s[range].chars() {
+                    match c {
+                        '(' | '[' | '{' => depth += 1,
+                        ')' | ']' | '}' => depth -= 1,
+                        '\n' => breaks.push((false, depth > 0 || previous == Some('\\'))),
+                        _ => {}
+                    }
+                    previous = Some(c);
+                }
+            }
+        }
+    }
+
+    s.split('\n')
+        .enumerate()
+        .map(|(i, text)| {
+            let before = i.checked_sub(1).map(|b| breaks[b]);
+            Line {
+                text,
+                in_string: before.is_some_and(|(in_string, _)| in_string),
+                continued: before.is_some_and(|(_, continued)| continued),
+                open_string: breaks.get(i).is_some_and(|&(in_string, _)| in_string),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::workspace;
+
+    /// Formats `source`, checking that formatting again changes nothing.
+    fn formatted(source: &str) -> String {
+        let formatted = format("//FFS", source).unwrap();
+        assert_eq!(format("//FFS", &formatted).unwrap(), formatted);
+        formatted
+    }
+
+    const MESSY: &str = r#"
+# Shared setup.
+load("//helpers.star", "mk")
+
+
+def helper(name):
+  task(name, "true")
+  if name:
+        print(name)
+build("lib",   "cc -c {srcs} -o lib.o",["a.c"], tags=["c"], outs = {"obj": "lib.o"})
+task("a", "echo hi",
+   visibility = ["//..."], prereqs=["//lib"],
+  )
+task("deploy", "./deploy.sh //lib:obj", env = {"TARGET": "production", "REGION": "us-east-1"}, always=True)
+task("b", "echo b", # why b
+  tags = ["x"])
+"#;
+
+    const CANONICAL: &str = r#"# Shared setup.
+load("//helpers.star", "mk")
+
+def helper(name):
+    task(name, "true")
+    if name:
+        print(name)
+build("lib", "cc -c {srcs} -o lib.o", ["a.c"], outs = {"obj": "lib.o"}, tags = ["c"])
+task("a", "echo hi", prereqs = ["//lib"], visibility = ["//..."])
+task(
+    "deploy",
+    "./deploy.sh //lib:obj",
+    env = {"TARGET": "production", "REGION": "us-east-1"},
+    always = True,
+)
+task("b", "echo b", # why b
+  tags = ["x"])
+"#;
+
+    #[test]
+    fn messy_file_is_made_canonical() {
+        assert_eq!(formatted(MESSY), CANONICAL);
+    }
+
+    #[test]
+    fn multi_line_values_move_with_their_argument() {
+        let source = r#"task("a", "true", outs = {
+    "x": "x",
+}, tags = ["t"])
+"#;
+
+        assert_eq!(
+            formatted(source),
+            r#"task(
+    "a",
+    "true",
+    tags = ["t"],
+    outs = {
+        "x": "x",
+    },
+)
+"#
+        );
+    }
+
+    #[test]
+    fn whitespace_inside_strings_is_kept() {
+        let source =
+            "x = \"\"\"a  \n  b\"\"\"  \ntask(\"a\", \"true\")   \n\n\n\ntask(\"b\", \"true\")\n\n";
+
+        assert_eq!(
+            formatted(source),
+            "x = \"\"\"a  \n  b\"\"\"\ntask(\"a\", \"true\")\n\ntask(\"b\", \"true\")\n"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_are_located() {
+        let e = format("//FFS", "task(\"a\",\n").unwrap_err();
+        assert!(e.to_string().starts_with("//FFS:"), "{e}");
+    }
+
+    #[test]
+    fn check_lists_unformatted_files_without_writing() {
+        let dir = workspace([("FFS", MESSY), ("lib/FFS", CANONICAL)]);
+        let root = dir.path();
+        let all = "*".parse().unwrap();
+
+        assert_eq!(fmt(root, &all, true).unwrap(), [PathBuf::from("FFS")]);
+        assert_eq!(std::fs::read_to_string(root.join("FFS")).unwrap(), MESSY);
+
+        assert_eq!(fmt(root, &all, false).unwrap(), [PathBuf::from("FFS")]);
+        assert_eq!(
+            std::fs::read_to_string(root.join("FFS")).unwrap(),
+            CANONICAL
+        );
+        assert!(fmt(root, &all, true).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Shell-style patterns selecting files under a directory. `*` stays within one path segment,
+/// while `**` spans any number of them.
+#[derive(Debug, Default)]
+pub struct Patterns {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Skip files ignored by `.gitignore` and friends, and hidden files.
+    pub respect_gitignore: bool,
+    /// Whether an include pattern may match no files.
+    pub allow_empty: bool,
+}
+
+impl Patterns {
+    /// The matching files under `dir`, relative to it with `/` separators, sorted.
+    pub fn expand(&self, dir: &Path) -> eyre::Result<Vec<String>> {
+        let include = set(&self.include)?;
+        let exclude = set(&self.exclude)?;
+
+        let mut matched = vec![false; self.include.len()];
+        let mut files = Vec::new();
+        let walk = ignore::WalkBuilder::new(dir)
+            .standard_filters(self.respect_gitignore)
+            .require_git(false)
+            .build();
+        for entry in walk {
+            let entry = entry?;
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(dir)?;
+            if exclude.is_match(relative) {
+                continue;
+            }
+
+            let matches = include.matches(relative);
+            if matches.is_empty() {
+                continue;
+            }
+            for i in matches {
+                matched[i] = true;
+            }
+
+            let relative = relative
+                .to_str()
+                .ok_or_else(|| eyre::eyre!("Path not utf8 {}", relative.display()))?;
+            files.push(relative.replace(std::path::MAIN_SEPARATOR, "/"));
+        }
+
+        if !self.allow_empty {
+            if let Some(i) = matched.iter().position(|m| !m) {
+                eyre::bail!("glob pattern {:?} matched no files", self.include[i]);
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+}
+
+fn set(patterns: &[String]) -> eyre::Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        set.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    Ok(set.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::workspace;
+
+    fn patterns(include: &[&str], exclude: &[&str]) -> Patterns {
+        Patterns {
+            include: include.iter().map(ToString::to_string).collect(),
+            exclude: exclude.iter().map(ToString::to_string).collect(),
+            respect_gitignore: true,
+            allow_empty: true,
+        }
+    }
+
+    #[test]
+    fn recursive_patterns() {
+        let dir = workspace([
+            ("pkg/main.rs", ""),
+            ("pkg/src/lib.rs", ""),
+            ("pkg/src/deep/mod.rs", ""),
+            ("pkg/src/deep/mod_test.rs", ""),
+            ("pkg/README.md", ""),
+            ("other.rs", ""),
+        ]);
+        let pkg = dir.path().join("pkg");
+
+        assert_eq!(
+            patterns(&["**/*.rs"], &[]).expand(&pkg).unwrap(),
+            [
+                "main.rs",
+                "src/deep/mod.rs",
+                "src/deep/mod_test.rs",
+                "src/lib.rs"
+            ]
+        );
+        assert_eq!(patterns(&["*.rs"], &[]).expand(&pkg).unwrap(), ["main.rs"]);
+        assert_eq!(
+            patterns(&["**/*.rs"], &["**/*_test.rs"])
+                .expand(&pkg)
+                .unwrap(),
+            ["main.rs", "src/deep/mod.rs", "src/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn gitignore_is_optional() {
+        let dir = workspace([(".gitignore", "gen/\n"), ("a.rs", ""), ("gen/b.rs", "")]);
+
+        let mut p = patterns(&["**/*.rs"], &[]);
+        assert_eq!(p.expand(dir.path()).unwrap(), ["a.rs"]);
+
+        p.respect_gitignore = false;
+        assert_eq!(p.expand(dir.path()).unwrap(), ["a.rs", "gen/b.rs"]);
+    }
+
+    #[test]
+    fn empty_matches() {
+        let dir = workspace([("a.rs", "")]);
+
+        let mut p = patterns(&["*.rs", "*.c"], &[]);
+        assert_eq!(p.expand(dir.path()).unwrap(), ["a.rs"]);
+
+        p.allow_empty = false;
+        assert!(p.expand(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use std::{collections::BTreeSet, fmt::Write};
+
+use crate::plan::Step;
+
+/// Renders `steps` as a Graphviz digraph, with an edge from each target to each dependency.
+pub fn dot(steps: &[Step]) -> String {
+    let mut out = String::from("digraph ffs {\n");
+
+    for step in steps {
+        let kind = step.def().kind();
+        writeln!(out, "  \"{}\" [{}];", step.path, node_style(kind)).unwrap();
+    }
+
+    for step in steps {
+        let deps = step.deps.iter().collect::<BTreeSet<_>>();
+        for dep in deps {
+            writeln!(out, "  \"{}\" -> \"{dep}\";", step.path).unwrap();
+        }
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+fn node_style(kind: &str) -> &'static str {
+    match kind {
+        "build" => "shape=box, style=filled, fillcolor=lightblue",
+        "task" => "shape=ellipse, style=filled, fillcolor=lightyellow",
+        _ => "shape=octagon",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{plan, starlark::Reader, testing::workspace};
+
+    fn edges(dot: &str) -> BTreeSet<(String, String)> {
+        dot.lines()
+            .filter_map(|line| {
+                let (from, to) = line.trim().trim_end_matches(';').split_once(" -> ")?;
+                Some((from.trim_matches('"').into(), to.trim_matches('"').into()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edges_include_prereqs_and_references() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+build("lib", "touch lib.a", [], {"out": "lib.a"})
+task("app", "cat //lib:out", prereqs = ["//setup"])
+task("setup", "true", prereqs = ["//app"])
+"#,
+        )]);
+        let root = dir.path();
+
+        let steps = plan::reachable(&Reader::new(root), root, [&"//app".parse().unwrap()]).unwrap();
+        let dot = dot(&steps);
+
+        let expected = [
+            ("//app", "//lib"),
+            ("//app", "//setup"),
+            ("//setup", "//app"),
+        ]
+        .map(|(a, b)| (a.to_string(), b.to_string()));
+        assert_eq!(edges(&dot), BTreeSet::from(expected));
+        assert!(dot.contains("\"//lib\" [shape=box"), "{dot}");
+        assert!(dot.contains("\"//app\" [shape=ellipse"), "{dot}");
+    }
+}
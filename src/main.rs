@@ -1,179 +1,1265 @@
 use std::{
-    borrow::Borrow,
-    io::Write,
+    collections::HashSet,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+use audit::AuditLog;
+use builder::{Builder, Forecast};
 use clap::{Parser, Subcommand};
-use dashmap::DashMap;
-use executor::{Execution, Executor};
-use eyre::OptionExt;
-use reporting::{build_reporter, Reporter};
+use executor::{Executor, OutputMode};
+use os::Os;
+use plan::Plan;
+use reporting::{build_reporter, ChildrenColor, Reporter};
 use starlark::Reader;
-use target::{Output, Selector, TargetDef, TargetPath};
+use stats::Stats;
+use target::{Selector, TargetPath};
 
+mod affected;
+mod arch;
+mod audit;
+mod builder;
+mod cache;
+mod clean;
 mod command;
+mod compdb;
+#[cfg(unix)]
+mod control;
+#[cfg(unix)]
+mod daemon;
+mod digest;
+mod duration;
 mod executor;
+mod fingerprint;
+mod fmt;
+mod glob;
+mod graph;
 mod os;
+mod plan;
+mod query;
+mod record;
 mod reporting;
+mod sandbox;
 mod starlark;
+mod stats;
 mod target;
+#[cfg(test)]
+mod testing;
+mod timings;
+mod watch;
 
 #[derive(Parser, Debug)]
-struct Options {
+struct Cli {
     #[command(flatten)]
     reporting: reporting::Options,
 
+    /// The workspace root. Defaults to the nearest ancestor of the current directory containing
+    /// a `.ffsroot` file, otherwise the top-most one containing an FFS file.
+    #[arg(long, global = true)]
+    root: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    Run { selector: Selector },
+    Run(RunArgs),
+
+    /// Print the targets whose srcs changed since a git revision, and everything depending on
+    /// them.
+    Affected {
+        #[arg(long)]
+        since: String,
+    },
+
+    /// Re-run a build captured with `run --record` in the workspace root.
+    Replay {
+        bundle: PathBuf,
+    },
+
+    /// Print the environment a target's command would run with, without running it.
+    DumpEnv {
+        target: TargetPath,
+
+        /// Hide values of variables that look like secrets.
+        #[arg(long)]
+        redact: bool,
+    },
+
+    /// Print the targets matching a selector, or every target, with their kind and tags.
+    List {
+        selector: Option<Selector>,
+    },
+
+    /// Remove the declared outputs of matching targets.
+    Clean {
+        selector: Selector,
+
+        /// Also remove the output cache shared by every target.
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Print the dependency graph of matching targets in Graphviz DOT format.
+    Graph {
+        selector: Selector,
+    },
+
+    /// Print the shortest chain of dependencies from one target to another.
+    Why {
+        from: TargetPath,
+        to: TargetPath,
+    },
+
+    /// Print the transitive dependencies or dependents of a target, one per line.
+    Query {
+        #[command(subcommand)]
+        query: Query,
+    },
+
+    /// Write a `compile_commands.json` for matching builds tagged `compdb`.
+    ExportCompileCommands {
+        selector: Selector,
+
+        #[arg(long, default_value = "compile_commands.json")]
+        output: PathBuf,
+    },
+
+    /// Rewrite matching FFS files, or every one, in canonical style.
+    Fmt {
+        selector: Option<Selector>,
+
+        /// List files that aren't formatted and fail, without rewriting them.
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Serve run requests over a unix socket, keeping parsed FFS files warm between them.
+    Daemon {
+        socket: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum Query {
+    /// Everything the target depends on.
+    Deps { target: TargetPath },
+
+    /// Everything depending on the target.
+    Rdeps { target: TargetPath },
+}
+
+#[derive(clap::Args, Clone, Debug)]
+struct RunArgs {
+    /// Targets matching any of these are run. Without any, runs the `default()` of the nearest
+    /// package declaring one.
+    selectors: Vec<Selector>,
+
+    /// Appended, quoted, to the command of the selected target, which must be the only one.
+    #[arg(last = true)]
+    args: Vec<String>,
+
+    /// After building, keep watching srcs and FFS files, rebuilding whatever they affect when
+    /// they change.
+    #[arg(long)]
+    watch: bool,
+
+    /// Only run selected targets affected by files changed since this git revision: builds with a
+    /// changed src, targets in packages with other changed files, and their dependents.
+    #[arg(long, conflicts_with = "watch")]
+    since: Option<String>,
+
+    /// Print each command that would run, in order, without running any.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the ordered execution plan instead of running it, marking each build as cached, up
+    /// to date, or will run.
+    #[arg(long)]
+    dump_plan: bool,
+
+    /// Listen on a unix socket for `status` and `cancel` commands while running.
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Append a JSON record of every executed command to this file.
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Whether a selector matching no targets is an error.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    fail_if_no_targets: bool,
+
+    /// Skip builds whose outputs are all newer than their srcs.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    check_mtime: bool,
+
+    /// Rebuild everything, even when outputs are newer than their srcs.
+    #[arg(long)]
+    always: bool,
+
+    /// Neither restore builds from the output cache nor store them in it.
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Run the selection this many times in a row, reporting how long each run took. Implies
+    /// `--always` and `--no-cache`, so every run executes everything.
+    #[arg(long, conflicts_with_all = ["watch", "dry_run", "touch"])]
+    repeat: Option<usize>,
+
+    /// Abort the run once it has taken longer than this, e.g. `10m`.
+    #[arg(long, value_parser = duration::parse)]
+    max_runtime: Option<Duration>,
+
+    /// Whether ANSI color codes in output from commands are kept when it is printed.
+    #[arg(long, value_enum, default_value_t)]
+    children_color: ChildrenColor,
+
+    /// Whether command output is shown live or once each command finishes.
+    #[arg(long, value_enum, default_value_t)]
+    output_mode: OutputMode,
+
+    /// Same as `--output-mode stream`.
+    #[arg(long, conflicts_with = "output_mode")]
+    stream: bool,
+
+    /// After a failure, keep building targets that don't depend on it, then report every
+    /// failure.
+    #[arg(long, short = 'k')]
+    keep_going: bool,
+
+    /// Fail builds whose command succeeds without rewriting output files left over from a
+    /// previous run.
+    #[arg(long)]
+    strict_outputs: bool,
+
+    /// Run each build in a scratch directory holding only its declared srcs and the outputs it
+    /// references, so reading anything undeclared fails.
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Don't show the output of builds restored from the cache.
+    #[arg(long)]
+    no_replay: bool,
+
+    /// How long targets without a recorded duration are assumed to take when estimating how long
+    /// a build has left, e.g. `30s`.
+    #[arg(long, value_parser = duration::parse, default_value = "10s")]
+    eta_default: Duration,
+
+    /// How many targets may build at once. Defaults to the number of CPUs.
+    #[arg(long, short)]
+    jobs: Option<usize>,
+
+    /// Mark declared outputs as up to date by creating or touching them, without running
+    /// any commands.
+    #[arg(long)]
+    touch: bool,
+
+    /// Capture the plan, commands, env, and srcs into a bundle directory that `ffs replay` can
+    /// re-run.
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Prefix for cache keys, so runs under different namespaces never share cache entries.
+    #[arg(long)]
+    cache_namespace: Option<String>,
+}
+
+/// Exit code when a run is aborted by `--max-runtime`, matching `timeout(1)`.
+const MAX_RUNTIME_EXIT_CODE: i32 = 124;
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Exceeded --max-runtime of {budget:?}\n  completed: {}\n  cut: {}",
+    list(completed),
+    list(cut)
+)]
+struct MaxRuntimeExceeded {
+    budget: Duration,
+    completed: Vec<TargetPath>,
+    cut: Vec<TargetPath>,
+}
+
+/// Exit code after Ctrl-C, matching shells.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Set on Ctrl-C, cancelling running commands.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Interrupted")]
+struct Interrupted;
+
+/// Cancels running commands on Ctrl-C instead of exiting and leaving them orphaned. A second
+/// Ctrl-C exits immediately.
+fn handle_interrupts() -> eyre::Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    })?;
+    Ok(())
+}
+
+fn list(targets: &[TargetPath]) -> String {
+    if targets.is_empty() {
+        return "none".to_string();
+    }
+    targets
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-fn main() -> eyre::Result<()> {
-    let options = Options::parse();
+fn main() {
+    if let Err(e) = try_main() {
+        eprintln!("Error: {e:?}");
+
+        let code = if e.downcast_ref::<MaxRuntimeExceeded>().is_some() {
+            MAX_RUNTIME_EXIT_CODE
+        } else if e.downcast_ref::<Interrupted>().is_some() {
+            INTERRUPTED_EXIT_CODE
+        } else {
+            1
+        };
+        std::process::exit(code);
+    }
+}
+
+fn try_main() -> eyre::Result<()> {
+    let options = Cli::parse();
+    let (root, invoked_from) = locate(&std::env::current_dir()?, options.root.as_deref())?;
+    let resolve = |selector: &Selector| selector.relative_to(&invoked_from);
 
     match &options.command {
-        Command::Run { selector } => {
+        Command::Run(args) => {
+            let reporter = build_reporter(&options.reporting);
+
+            let selectors = match args.selectors.as_slice() {
+                [] => vec![default_target(&root, &invoked_from)?.to_string().parse()?],
+                selectors => selectors.iter().map(resolve).collect::<eyre::Result<_>>()?,
+            };
+            let args = RunArgs {
+                selectors,
+                ..args.clone()
+            };
+            handle_interrupts()?;
+            run(&root, &args, reporter)?;
+        }
+        Command::Affected { since } => {
+            let reader = Reader::new(&root);
+
+            let targets = matching_targets(&reader, &root, &"*".parse()?)?;
+            let plan = Plan::new(&reader, &root, &targets)?;
+            let changed = affected::changed_files(&root, since)?;
+
+            for target in affected::affected(&plan, &root, &changed) {
+                println!("{target}");
+            }
+        }
+        Command::Replay { bundle } => {
             let reporter = build_reporter(&options.reporting);
-            run(&selector, reporter)?;
+
+            handle_interrupts()?;
+            let executor = Executor::new(reporter).cancel_on(&INTERRUPTED);
+            record::replay(bundle, &root, &executor)?;
+        }
+        Command::DumpEnv { target, redact } => {
+            print!("{}", dump_env(&root, target, *redact)?);
+        }
+        Command::List { selector } => {
+            let selector = match selector {
+                Some(s) => resolve(s)?,
+                None => "*".parse()?,
+            };
+            print!("{}", list_targets(&root, &selector)?);
+        }
+        Command::Clean { selector, all } => {
+            let reporter = build_reporter(&options.reporting);
+            let reader = Reader::new(&root).reporter(Arc::clone(&reporter));
+
+            let targets = matching_targets(&reader, &root, &resolve(selector)?)?;
+            clean::clean(&reader, &root, &targets, &*reporter)?;
+            if *all {
+                clean::clean_cache(&root, &*reporter)?;
+            }
+        }
+        Command::Graph { selector } => {
+            let reader = Reader::new(&root);
+
+            let targets = matching_targets(&reader, &root, &resolve(selector)?)?;
+            print!(
+                "{}",
+                graph::dot(&plan::reachable(&reader, &root, &targets)?)
+            );
+        }
+        Command::Why { from, to } => {
+            let reader = Reader::new(&root);
+
+            let plan = Plan::new(&reader, &root, [from])?;
+            match plan.path(from, to) {
+                Some(path) => {
+                    let path = path.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+                    println!("{}", path.join(" -> "));
+                }
+                None => println!("{from} does not depend on {to}"),
+            }
+        }
+        Command::Query { query } => {
+            let reader = Reader::new(&root);
+
+            let targets = matching_targets(&reader, &root, &"*".parse()?)?;
+            let steps = plan::reachable(&reader, &root, &targets)?;
+            let found = match query {
+                Query::Deps { target } => query::deps(&steps, target)?,
+                Query::Rdeps { target } => query::rdeps(&steps, target)?,
+            };
+            for target in found {
+                println!("{target}");
+            }
+        }
+        Command::ExportCompileCommands { selector, output } => {
+            let reader = Reader::new(&root);
+
+            let plan = plan_selection(&reader, &root, &resolve(selector)?)?;
+            let entries = compdb::entries(&plan, &root)?;
+            std::fs::write(output, serde_json::to_vec_pretty(&entries)?)?;
+        }
+        Command::Fmt { selector, check } => {
+            let selector = match selector {
+                Some(s) => resolve(s)?,
+                None => "*".parse()?,
+            };
+            let unformatted = fmt::fmt(&root, &selector, *check)?;
+            if *check {
+                for file in &unformatted {
+                    println!("{}", file.display());
+                }
+                eyre::ensure!(
+                    unformatted.is_empty(),
+                    "{} FFS files aren't formatted",
+                    unformatted.len()
+                );
+            }
+        }
+        Command::Daemon { socket } => {
+            #[cfg(unix)]
+            daemon::serve(&root, socket)?;
+            #[cfg(not(unix))]
+            eyre::bail!("daemon mode is only supported on unix");
         }
     }
 
     Ok(())
 }
 
-fn run(selector: &Selector, reporter: Arc<dyn Reporter>) -> eyre::Result<()> {
-    let executor = Arc::new(Executor::new(Arc::clone(&reporter)));
+/// A file marking the workspace root, for workspaces with FFS files above it.
+const ROOT_MARKER: &str = ".ffsroot";
 
-    // TODO(shelbyd): Search for root.
-    let root = std::env::current_dir()?;
-    let reader = Arc::new(Reader::new(&root));
+/// The workspace root, and `cwd` relative to it.
+fn locate(cwd: &Path, root: Option<&Path>) -> eyre::Result<(PathBuf, PathBuf)> {
+    let cwd = cwd.canonicalize()?;
+    let root = match root {
+        Some(root) => cwd.join(root).canonicalize()?,
+        None => find_root(&cwd),
+    };
+    let invoked_from = cwd
+        .strip_prefix(&root)
+        .unwrap_or(Path::new(""))
+        .to_path_buf();
+    Ok((root, invoked_from))
+}
 
-    let mut builder = Builder::new(Arc::clone(&reader), Arc::clone(&executor), &root);
+/// The nearest ancestor of `dir` with a [`ROOT_MARKER`], otherwise the top-most ancestor with an
+/// FFS file, otherwise `dir` itself.
+fn find_root(dir: &Path) -> PathBuf {
+    if let Some(marked) = dir.ancestors().find(|d| d.join(ROOT_MARKER).is_file()) {
+        return marked.to_path_buf();
+    }
+    dir.ancestors()
+        .filter(|d| d.join("FFS").is_file())
+        .last()
+        .unwrap_or(dir)
+        .to_path_buf()
+}
 
-    let mut count = 0;
-    for entry in ignore::Walk::new(".") {
+/// The `default()` of the nearest package at or above `dir`, relative to the root, declaring one.
+fn default_target(root: &Path, dir: &Path) -> eyre::Result<TargetPath> {
+    let reader = Reader::new(root);
+    for package in dir.ancestors() {
+        let file = root.join(package).join("FFS");
+        if !file.is_file() {
+            continue;
+        }
+        if let Some(name) = &reader.read(&file)?.default {
+            return TargetPath::from_path_name(package, name);
+        }
+    }
+    eyre::bail!("No selector given, and no package declares a default()")
+}
+
+/// How long file changes must settle before a watched build reruns.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+fn run(root: &Path, args: &RunArgs, reporter: Arc<dyn Reporter>) -> eyre::Result<()> {
+    let result = if args.watch {
+        watch(root, args, Arc::clone(&reporter), &INTERRUPTED)
+    } else if let Some(times) = args.repeat {
+        repeat(root, args, &reporter, times)
+    } else {
+        let reader = Reader::new(root).reporter(Arc::clone(&reporter));
+        let changed = args
+            .since
+            .as_deref()
+            .map(|since| affected::changed_files(root, since))
+            .transpose()?;
+        run_once(
+            &reader,
+            root,
+            args,
+            &reporter,
+            changed.as_deref(),
+            &mut |_| {},
+        )
+    };
+
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        reporter.note("Interrupted, killed running commands");
+        return Err(Interrupted.into());
+    }
+    result
+}
+
+/// Runs the selection `times` times from scratch, reporting how long each run took.
+fn repeat(
+    root: &Path,
+    args: &RunArgs,
+    reporter: &Arc<dyn Reporter>,
+    times: usize,
+) -> eyre::Result<()> {
+    let reader = Reader::new(root).reporter(Arc::clone(reporter));
+    let args = RunArgs {
+        always: true,
+        no_cache: true,
+        ..args.clone()
+    };
+
+    let mut took = Vec::with_capacity(times);
+    for iteration in 1..=times {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            break;
+        }
+        let start = Instant::now();
+        run_once(&reader, root, &args, reporter, None, &mut |_| {})?;
+        let elapsed = start.elapsed();
+        reporter.finish_iteration(iteration, times, elapsed);
+        took.push(elapsed);
+    }
+
+    if let Some(stats) = Stats::of(&took) {
+        reporter.repeat_stats(&stats);
+    }
+    Ok(())
+}
+
+/// Runs the selection, then again each time a watched file changes, until `stop` is set.
+fn watch(
+    root: &Path,
+    args: &RunArgs,
+    reporter: Arc<dyn Reporter>,
+    stop: &AtomicBool,
+) -> eyre::Result<()> {
+    let reader = Reader::new(root).reporter(Arc::clone(&reporter));
+    let changes = watch::Changes::new(root)?;
+    let mut watched = watch::Watched::default();
+    let mut changed = None::<Vec<PathBuf>>;
+
+    loop {
+        // The watched set comes from the plan, so it's kept even when the build itself fails.
+        let replan = changed.is_none();
+        let result = run_once(
+            &reader,
+            root,
+            args,
+            &reporter,
+            changed.as_deref(),
+            &mut |plan| {
+                if replan {
+                    watched = watch::Watched::new(plan, root);
+                }
+            },
+        );
+        if let Err(e) = result {
+            reporter.note(&format!("Build failed: {e:?}"));
+        }
+
+        let Some(files) = changes.next(&watched, WATCH_DEBOUNCE, stop)? else {
+            return Ok(());
+        };
+        reporter.begin_cycle(&files);
+        reader.invalidate_stale();
+
+        // Any FFS file change may add or remove targets, so the whole selection is re-planned.
+        changed = Some(files).filter(|f| !f.iter().any(|f| watch::is_ffs_file(f)));
+    }
+}
+
+/// Builds the selection, or only the selected targets affected by `changed` files. The plan for
+/// the full selection, if anything was selected, is passed to `on_plan` before anything runs.
+fn run_once(
+    reader: &Reader,
+    root: &Path,
+    args: &RunArgs,
+    reporter: &Arc<dyn Reporter>,
+    changed: Option<&[PathBuf]>,
+    on_plan: &mut dyn FnMut(&Plan),
+) -> eyre::Result<()> {
+    let output_mode = if args.stream {
+        OutputMode::Stream
+    } else {
+        args.output_mode
+    };
+    let executor = Executor::new(Arc::clone(reporter))
+        .children_color(args.children_color)
+        .cancel_on(&INTERRUPTED)
+        .output_mode(output_mode)
+        .prefix_lines(args.jobs != Some(1));
+    let executor = Arc::new(executor);
+
+    let mut targets = Vec::new();
+    // Targets named by a non-wildcard selector, which run even when they require another OS.
+    let mut named = HashSet::new();
+    for selector in &args.selectors {
+        for target in matching_targets(reader, root, selector)? {
+            if !selector.is_wildcard() {
+                named.insert(target.clone());
+            }
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+    let host = os::host();
+    for target in std::mem::take(&mut targets) {
+        let runs_on = required_os(reader, root, &target)?;
+        if os::allows(&runs_on, host) || named.contains(&target) {
+            targets.push(target);
+        } else {
+            let required = os::describe(&runs_on);
+            reporter.note(&format!("Skipping {target}, which requires {required}"));
+        }
+    }
+    if targets.is_empty() {
+        let selectors = args
+            .selectors
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let message = format!("No targets found matching {}", selectors.join(", "));
+        eyre::ensure!(!args.fail_if_no_targets, message);
+
+        reporter.note(&message);
+        return Ok(());
+    }
+
+    let extra_args = match args.args.as_slice() {
+        [] => None,
+        extra => {
+            eyre::ensure!(
+                targets.len() == 1,
+                "Arguments after -- need exactly one target, but {} matched: {}",
+                targets.len(),
+                list(&targets)
+            );
+            Some((targets[0].clone(), extra.to_vec()))
+        }
+    };
+
+    let full = Plan::new(reader, root, &targets)?;
+    on_plan(&full);
+    let affected = match changed {
+        Some(changed) => {
+            let affected = affected::affected(&full, root, changed);
+            targets.retain(|t| affected.contains(t));
+            Some(Plan::new(reader, root, &targets)?)
+        }
+        None => None,
+    };
+    let plan = affected.as_ref().unwrap_or(&full);
+
+    let mut builder = Builder::new(Arc::clone(&executor), root)
+        .touch(args.touch)
+        .check_mtime(args.check_mtime && !args.always)
+        .use_cache(!args.no_cache)
+        .keep_going(args.keep_going)
+        .strict_outputs(args.strict_outputs)
+        .sandbox(args.sandbox)
+        .replay(!args.no_replay)
+        .eta_default(args.eta_default)
+        .dry_run(args.dry_run);
+    if let Some(jobs) = args.jobs {
+        builder = builder.jobs(jobs);
+    }
+    if let Some(path) = &args.audit_log {
+        builder = builder.audit_log(AuditLog::open(path)?);
+    }
+    if let Some(namespace) = &args.cache_namespace {
+        builder = builder.cache_namespace(namespace);
+    }
+    if let Some((target, extra)) = extra_args {
+        builder = builder.extra_args(target, extra);
+    }
+    if args.dump_plan {
+        let forecast = builder.forecast(plan)?;
+        print!(
+            "{}",
+            plan.dump(|target| forecast.get(target).map(forecast_label))
+        );
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    let _control = args
+        .control_socket
+        .as_deref()
+        .map(|path| control::ControlSocket::listen(path, Arc::clone(&executor)))
+        .transpose()?;
+    #[cfg(not(unix))]
+    eyre::ensure!(
+        args.control_socket.is_none(),
+        "--control-socket is only supported on unix"
+    );
+
+    let deadline = args
+        .max_runtime
+        .map(|budget| (budget, executor.cancel_after(budget)));
+
+    let result = builder.execute(plan);
+    if result.is_err() {
+        reporter.finish_top_level();
+    }
+
+    if let Some((budget, deadline)) = deadline {
+        if deadline.expired() {
+            let completed = builder.built();
+            let cut = plan
+                .steps
+                .iter()
+                .map(|s| s.path.clone())
+                .filter(|p| !completed.contains(p))
+                .collect();
+            return Err(MaxRuntimeExceeded {
+                budget,
+                completed,
+                cut,
+            }
+            .into());
+        }
+    }
+    result?;
+
+    if args.dry_run {
+        for (target, command) in builder.rehearsed() {
+            println!("{target}\n    {command}");
+        }
+        return Ok(());
+    }
+
+    if let Some(bundle) = &args.record {
+        std::fs::create_dir_all(bundle)?;
+        record::record(bundle, root, plan, &builder)?;
+    }
+
+    reporter.critical_path(&builder.critical_path(plan));
+    reporter.finish_top_level();
+
+    Ok(())
+}
+
+/// How `--dump-plan` describes what a build will do.
+fn forecast_label(forecast: &Forecast) -> &'static str {
+    match forecast {
+        Forecast::Cached => "cached",
+        Forecast::UpToDate => "up to date",
+        Forecast::Run => "will run",
+    }
+}
+
+fn dump_env(root: &Path, target: &TargetPath, redact: bool) -> eyre::Result<String> {
+    let reader = Reader::new(root);
+    let set = reader.read(root.join(target.definition()))?;
+    let def = set
+        .targets
+        .get(target.name())
+        .ok_or_else(|| eyre::eyre!("Unknown task: {target}"))?;
+
+    let executor = Executor::new(Arc::new(reporting::Quiet));
+    let mut dump = String::new();
+    for (key, value) in executor.resolved_env(Some(&def.env)) {
+        let value = if redact && executor::is_secret(&key) {
+            "<redacted>"
+        } else {
+            &value
+        };
+        dump.push_str(&format!("{key}={value}\n"));
+    }
+    Ok(dump)
+}
+
+/// One line per matching target: its path, kind, and comma-separated tags.
+fn list_targets(root: &Path, selector: &Selector) -> eyre::Result<String> {
+    let reader = Reader::new(root);
+    let mut targets = matching_targets(&reader, root, selector)?;
+    targets.sort();
+
+    let mut listing = String::new();
+    for target in targets {
+        let info = reader
+            .describe(&target)?
+            .ok_or_else(|| eyre::eyre!("Unknown task: {target}"))?;
+        listing.push_str(&format!(
+            "{target}\t{}\t{}\n",
+            info.kind,
+            info.tags.join(",")
+        ));
+    }
+    Ok(listing)
+}
+
+fn required_os(reader: &Reader, root: &Path, target: &TargetPath) -> eyre::Result<HashSet<Os>> {
+    let set = reader.read(root.join(target.definition()))?;
+    Ok(set
+        .targets
+        .get(target.name())
+        .and_then(|def| def.as_build())
+        .map(|build| build.runs_on.clone())
+        .unwrap_or_default())
+}
+
+fn plan_selection(reader: &Reader, root: &Path, selector: &Selector) -> eyre::Result<Plan> {
+    let targets = matching_targets(reader, root, selector)?;
+    eyre::ensure!(!targets.is_empty(), "No targets found matching {selector}");
+
+    Plan::new(reader, root, &targets)
+}
+
+fn matching_targets(
+    reader: &Reader,
+    root: &Path,
+    selector: &Selector,
+) -> eyre::Result<Vec<TargetPath>> {
+    let mut targets = Vec::new();
+
+    for entry in ignore::Walk::new(root) {
         let entry = entry?;
 
         let is_ffs_file = entry.path().file_name().is_some_and(|f| f == "FFS");
         if !is_ffs_file {
             continue;
         }
-        if !selector.matches_file(&entry.path()) {
+
+        let relative = entry.path().strip_prefix(root)?;
+        if !selector.matches_file(relative) {
             continue;
         }
 
         let file = reader.read(entry.path())?;
         for (name, task) in file.targets() {
-            let task_path = TargetPath::from_path_name(entry.path(), name)?;
+            let task_path = TargetPath::from_path_name(relative, name)?;
 
             if !selector.matches(&task_path, &task.tags) {
                 continue;
             }
 
-            let output = builder.execute(
-                &task_path,
-                task,
-                entry.path().parent().expect("entry is file"),
-            )?;
-
-            if !output.status.success() {
-                std::io::stdout().lock().write_all(&output.stdout)?;
-                std::io::stderr().lock().write_all(&output.stderr)?;
-                eyre::bail!("Task failed: {task_path}");
-            }
-            count += 1;
+            targets.push(task_path);
         }
     }
 
-    eyre::ensure!(count > 0, "No targets found matching {selector}");
-    reporter.finish_top_level();
-
-    Ok(())
+    Ok(targets)
 }
 
-struct Builder {
-    reader: Arc<Reader>,
-    executor: Arc<Executor>,
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    root: PathBuf,
-    outputs: DashMap<Output, PathBuf>,
-}
+    use clap::CommandFactory;
+
+    use crate::{reporting::Quiet, testing::workspace};
+
+    fn run_args(args: &[&str]) -> RunArgs {
+        let cli = Cli::try_parse_from(["ffs", "run"].iter().chain(args)).unwrap();
+        match cli.command {
+            Command::Run(args) => args,
+            c => panic!("Expected run, got {c:?}"),
+        }
+    }
 
-impl Builder {
-    fn new(reader: Arc<Reader>, executor: Arc<Executor>, root: impl AsRef<Path>) -> Self {
-        Self {
-            reader,
-            executor,
+    /// How many times a test build appended to its `attempts` file.
+    fn attempts(root: &Path) -> usize {
+        std::fs::read_to_string(root.join("attempts"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    }
 
-            root: root.as_ref().to_path_buf(),
-            outputs: Default::default(),
+    fn wait_for_attempts(root: &Path, n: usize) {
+        let start = std::time::Instant::now();
+        while attempts(root) < n {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "never ran {n} times"
+            );
+            std::thread::sleep(Duration::from_millis(20));
         }
     }
 
-    #[context_attr::eyre(format!("Building {target}"))]
-    fn build(&mut self, target: &TargetPath) -> eyre::Result<()> {
-        let definition = self.root.join(target.definition());
-        let targets = self.reader.read(&definition)?;
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+    }
 
-        let name = target.name();
+    #[test]
+    fn root_is_found_from_nested_dir() {
+        let dir = workspace([
+            ("FFS", r#"task("a", "true")"#),
+            ("lib/FFS", r#"task("b", "true")"#),
+            ("lib/sub/data.txt", ""),
+        ]);
+        let root = dir.path().canonicalize().unwrap();
 
-        let task = targets
-            .targets
-            .get(name)
-            .ok_or_eyre(format!("Unknown task: {target}"))?;
+        let (found, invoked_from) = locate(&root.join("lib/sub"), None).unwrap();
+        assert_eq!(found, root);
+        assert_eq!(invoked_from, Path::new("lib/sub"));
 
-        let dir = definition.parent().unwrap();
-        let relative_dir = dir.strip_prefix(&self.root).unwrap();
+        let (_, invoked_from) = locate(&root.join("lib"), None).unwrap();
+        let selector = "%/...".parse::<Selector>().unwrap();
+        let selector = selector.relative_to(&invoked_from).unwrap();
+        let list = list_targets(&found, &selector).unwrap();
+        assert_eq!(list.lines().collect::<Vec<_>>(), ["//lib/b\ttask\t"]);
+    }
 
-        let task_path = TargetPath::from_path_name(&relative_dir, name)?;
+    #[test]
+    fn root_marker_and_override() {
+        let dir = workspace([
+            ("FFS", r#"task("a", "true")"#),
+            ("lib/.ffsroot", ""),
+            ("lib/sub/FFS", r#"task("b", "true")"#),
+        ]);
+        let root = dir.path().canonicalize().unwrap();
+        let nested = root.join("lib/sub");
 
-        let output = self.execute(&task_path, task, &dir)?;
+        let (found, invoked_from) = locate(&nested, None).unwrap();
+        assert_eq!(found, root.join("lib"));
+        assert_eq!(invoked_from, Path::new("sub"));
 
-        if !output.status.success() {
-            eyre::bail!("Command exited with code: {:?}", output.status.code())
-        }
+        let (found, invoked_from) = locate(&nested, Some(Path::new("../.."))).unwrap();
+        assert_eq!(found, root);
+        assert_eq!(invoked_from, Path::new("lib/sub"));
+    }
 
-        for (name, path) in &task.outs {
-            let file = dir.join(path);
-            eyre::ensure!(
-                file.exists(),
-                "Missing output file: {name} @ {}",
-                file.display()
-            );
+    #[test]
+    fn runs_nearest_default() {
+        let dir = workspace([
+            ("FFS", "task(\"a\", \"touch a\")\ndefault(\"a\")\n"),
+            ("lib/FFS", "task(\"b\", \"touch b\")\ndefault(\"b\")\n"),
+            ("lib/sub/FFS", "task(\"c\", \"touch c\")\n"),
+            ("other/data.txt", ""),
+        ]);
+        let root = dir.path();
+        let default = |dir: &str| default_target(root, Path::new(dir)).unwrap().to_string();
 
-            self.outputs.insert(task_path.output(name), file);
-        }
+        assert_eq!(default(""), "//a");
+        assert_eq!(default("lib"), "//lib/b");
+        assert_eq!(default("lib/sub"), "//lib/b");
+        assert_eq!(default("other"), "//a");
 
-        Ok(())
+        let args = RunArgs {
+            selectors: vec![default("lib/sub").parse().unwrap()],
+            ..run_args(&[])
+        };
+        run(root, &args, Arc::new(Quiet)).unwrap();
+        assert!(root.join("lib/b").exists());
+        assert!(!root.join("a").exists());
     }
 
-    fn execute(
-        &mut self,
-        path: &TargetPath,
-        task: &TargetDef,
-        dir: &Path,
-    ) -> eyre::Result<std::process::Output> {
-        for prereq in &task.prereqs {
-            self.build(&prereq)?;
-        }
-        for target in task.cmd.targets() {
-            self.build(target.borrow())?;
+    #[test]
+    fn ambiguous_default_is_an_error() {
+        let dir = workspace([(
+            "FFS",
+            "task(\"a\", \"true\")\ntask(\"b\", \"true\")\ndefault(\"a\")\ndefault(\"b\")\n",
+        )]);
+
+        let err = default_target(dir.path(), Path::new("")).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("declares more than one default: \"a\" and \"b\""),
+            "{err:?}"
+        );
+
+        let empty = workspace([("FFS", r#"task("a", "true")"#)]);
+        let err = default_target(empty.path(), Path::new("")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No selector given, and no package declares a default()"
+        );
+    }
+
+    #[test]
+    fn no_targets_is_an_error() {
+        let dir = workspace([("FFS", r#"task("a", "true")"#)]);
+
+        let err = run(dir.path(), &run_args(&["//missing"]), Arc::new(Quiet)).unwrap_err();
+        assert_eq!(err.to_string(), "No targets found matching //missing");
+    }
+
+    #[test]
+    fn no_targets_allowed() {
+        let dir = workspace([("FFS", r#"task("a", "true")"#)]);
+
+        let args = run_args(&["//missing", "--fail-if-no-targets=false"]);
+        run(dir.path(), &args, Arc::new(Quiet)).unwrap();
+    }
+
+    #[test]
+    fn selectors_build_union_once() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "echo a >> ran", tags = ["fast"])
+task("b", "echo b >> ran", tags = ["fast"])
+task("c", "echo c >> ran")
+"#,
+        )]);
+
+        let args = run_args(&["//a", "//...@fast"]);
+        run(dir.path(), &args, Arc::new(Quiet)).unwrap();
+
+        let ran = std::fs::read_to_string(dir.path().join("ran")).unwrap();
+        let mut ran = ran.lines().collect::<Vec<_>>();
+        ran.sort();
+        assert_eq!(ran, ["a", "b"]);
+    }
+
+    #[test]
+    fn no_targets_lists_every_selector() {
+        let dir = workspace([("FFS", r#"task("a", "true")"#)]);
+
+        let args = run_args(&["//missing", "//...@none"]);
+        let err = run(dir.path(), &args, Arc::new(Quiet)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No targets found matching //missing, //...@none"
+        );
+    }
+
+    #[test]
+    fn list_prints_matching_targets() {
+        let dir = workspace([
+            ("FFS", r#"task("a", "true", tags = ["ci", "fast"])"#),
+            (
+                "lib/FFS",
+                r#"
+build("b", "true", [], {}, tags = ["ci"])
+task("c", "true")
+"#,
+            ),
+        ]);
+        let list = |selector: &str| list_targets(dir.path(), &selector.parse().unwrap()).unwrap();
+
+        assert_eq!(
+            list("*").lines().collect::<Vec<_>>(),
+            [
+                "//a\ttask\tci,fast",
+                "//lib/b\tbuild\tci",
+                "//lib/c\ttask\t"
+            ]
+        );
+        assert_eq!(
+            list("*@ci").lines().collect::<Vec<_>>(),
+            ["//a\ttask\tci,fast", "//lib/b\tbuild\tci"]
+        );
+        assert_eq!(list("//lib/...").lines().count(), 2);
+    }
+
+    #[test]
+    fn dump_env_includes_target_env() {
+        let dir = workspace([(
+            "FFS",
+            r#"task("a", "true", env = {"GREETING": "hello", "API_TOKEN": "hunter2"})"#,
+        )]);
+        let target = "//a".parse().unwrap();
+
+        let dump = dump_env(dir.path(), &target, false).unwrap();
+        let lines = dump.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"GREETING=hello"), "{dump}");
+        assert!(lines.contains(&"API_TOKEN=hunter2"), "{dump}");
+
+        let redacted = dump_env(dir.path(), &target, true).unwrap();
+        assert!(
+            redacted.lines().any(|l| l == "API_TOKEN=<redacted>"),
+            "{redacted}"
+        );
+        assert!(
+            redacted.lines().any(|l| l == "GREETING=hello"),
+            "{redacted}"
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn wildcard_skips_other_hosts() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "true")
+build("mac_only", "false", [], {}, runs_on = "mac")
+"#,
+        )]);
+
+        run(dir.path(), &run_args(&["//..."]), Arc::new(Quiet)).unwrap();
+
+        let err = run(dir.path(), &run_args(&["//mac_only"]), Arc::new(Quiet)).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Cannot run job requiring Mac on Linux"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn watch_rebuilds_on_src_change() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "echo x >> attempts; cp in.txt out.txt", ["in.txt"], {"out": "out.txt"})"#,
+            ),
+            ("in.txt", "one\n"),
+        ]);
+        let root = dir.path();
+
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let watching = s.spawn(|| watch(root, &run_args(&["//b"]), Arc::new(Quiet), &stop));
+
+            wait_for_attempts(root, 1);
+            std::fs::write(root.join("in.txt"), "two\n").unwrap();
+            wait_for_attempts(root, 2);
+
+            stop.store(true, std::sync::atomic::Ordering::Relaxed);
+            watching.join().unwrap().unwrap();
+        });
+        assert_eq!(
+            std::fs::read_to_string(root.join("out.txt")).unwrap(),
+            "two\n"
+        );
+    }
+
+    #[test]
+    fn watch_rebuilds_after_failed_first_build() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "echo x >> attempts; grep -q two in.txt; cp in.txt out.txt", ["in.txt"], {"out": "out.txt"})"#,
+            ),
+            ("in.txt", "one\n"),
+        ]);
+        let root = dir.path();
+
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let watching = s.spawn(|| watch(root, &run_args(&["//b"]), Arc::new(Quiet), &stop));
+
+            wait_for_attempts(root, 1);
+            std::fs::write(root.join("in.txt"), "two\n").unwrap();
+            wait_for_attempts(root, 2);
+
+            stop.store(true, std::sync::atomic::Ordering::Relaxed);
+            watching.join().unwrap().unwrap();
+        });
+        assert_eq!(
+            std::fs::read_to_string(root.join("out.txt")).unwrap(),
+            "two\n"
+        );
+    }
+
+    #[test]
+    fn max_runtime_cuts_slow_targets() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("fast", "true")
+task("slow", "sleep 30", prereqs = ["//fast"])
+task("slower", "sleep 30", prereqs = ["//slow"])
+"#,
+        )]);
+
+        let start = std::time::Instant::now();
+        let args = run_args(&["//slower", "--max-runtime", "200ms"]);
+        let err = run(dir.path(), &args, Arc::new(Quiet)).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let exceeded = err.downcast_ref::<MaxRuntimeExceeded>().unwrap();
+        assert_eq!(exceeded.completed, vec!["//fast".parse().unwrap()]);
+        assert_eq!(
+            exceeded.cut,
+            vec!["//slow".parse().unwrap(), "//slower".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn repeat_runs_every_iteration() {
+        #[derive(Default)]
+        struct Iterations(std::sync::Mutex<Vec<(usize, usize)>>);
+
+        impl Reporter for Iterations {
+            fn finish_iteration(&self, iteration: usize, of: usize, _: Duration) {
+                self.0.lock().unwrap().push((iteration, of));
+            }
         }
 
-        let sh_command = task.cmd.as_sh(&self.outputs)?;
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "echo x >> attempts; cp in.txt out.txt", ["in.txt"], {"out": "out.txt"})"#,
+            ),
+            ("in.txt", ""),
+        ]);
+        let root = dir.path();
 
-        let execution = Execution {
-            path,
-            command: &sh_command,
-            dir,
-            runs_on: task.as_build().and_then(|b| b.runs_on.as_ref()),
-        };
-        Ok(self.executor.execute(execution)?)
+        let iterations = Arc::new(Iterations::default());
+        let args = run_args(&["//b", "--repeat", "3"]);
+        run(root, &args, Arc::clone(&iterations) as Arc<dyn Reporter>).unwrap();
+
+        // Neither up-to-date outputs nor the cache stop later runs from executing.
+        let attempts = std::fs::read_to_string(root.join("attempts")).unwrap();
+        assert_eq!(attempts, "x\nx\nx\n");
+        assert_eq!(*iterations.0.lock().unwrap(), [(1, 3), (2, 3), (3, 3)]);
+    }
+
+    #[test]
+    fn trailing_args_reach_the_command() {
+        let dir = workspace([(
+            "FFS",
+            "task(\"script\", \"printf '[%s]' > argv\")\ntask(\"other\", \"true\")\n",
+        )]);
+        let root = dir.path();
+
+        let args = run_args(&["//script", "--", "--flag", "two words", "it's"]);
+        run(root, &args, Arc::new(Quiet)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("argv")).unwrap(),
+            "[--flag][two words][it's]"
+        );
+
+        let err = run(root, &run_args(&["//...", "--", "x"]), Arc::new(Quiet)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Arguments after -- need exactly one target, but 2 matched: //other, //script"
+        );
     }
 }
@@ -1,31 +1,62 @@
-use std::str::FromStr;
+use std::{collections::HashSet, fmt::Display, str::FromStr};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Os {
     Linux,
     Mac,
     Windows,
+    FreeBSD,
 }
 
 impl FromStr for Os {
     type Err = eyre::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
         let s = None
-            .or(s.strip_suffix("_os"))
-            .or(s.strip_suffix("os"))
-            .unwrap_or(s);
+            .or(lower.strip_suffix("_os"))
+            .or(lower.strip_suffix("os"))
+            .unwrap_or(&lower);
 
         Ok(match s {
             "linux" => Os::Linux,
-            "mac" => Os::Mac,
-            "windows" => Os::Windows,
+            "mac" | "darwin" | "osx" => Os::Mac,
+            "windows" | "win" | "win32" => Os::Windows,
+            "freebsd" | "bsd" => Os::FreeBSD,
 
             s => eyre::bail!("Unknown OS: {s:?}"),
         })
     }
 }
 
+impl Display for Os {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Os::Linux => "linux",
+            Os::Mac => "mac",
+            Os::Windows => "windows",
+            Os::FreeBSD => "freebsd",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Whether a target restricted to `runs_on` can run on `host`. An empty set allows any OS.
+pub fn allows(runs_on: &HashSet<Os>, host: Os) -> bool {
+    runs_on.is_empty() || runs_on.contains(&host)
+}
+
+/// The OSes in `runs_on`, in a stable order, like `Linux or Mac`.
+pub fn describe(runs_on: &HashSet<Os>) -> String {
+    let mut names = runs_on.iter().collect::<Vec<_>>();
+    names.sort();
+    names
+        .iter()
+        .map(|os| format!("{os:?}"))
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
 #[cfg(target_os = "linux")]
 pub fn host() -> Os {
     Os::Linux
@@ -40,3 +71,43 @@ pub fn host() -> Os {
 pub fn host() -> Os {
     Os::Windows
 }
+
+#[cfg(target_os = "freebsd")]
+pub fn host() -> Os {
+    Os::FreeBSD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_hosts_in_set() {
+        let any = HashSet::new();
+        let unix = HashSet::from([Os::Linux, Os::Mac]);
+
+        assert!(allows(&any, Os::Windows));
+        assert!(allows(&unix, Os::Linux));
+        assert!(allows(&unix, Os::Mac));
+        assert!(!allows(&unix, Os::Windows));
+        assert_eq!(describe(&unix), "Linux or Mac");
+    }
+
+    #[test]
+    fn aliases_round_trip() {
+        let aliases = [
+            (Os::Linux, &["linux", "Linux", "linux_os"][..]),
+            (Os::Mac, &["mac", "macos", "MacOS", "darwin", "osx", "OSX"]),
+            (Os::Windows, &["windows", "win", "win32", "Windows"]),
+            (Os::FreeBSD, &["freebsd", "FreeBSD", "bsd"]),
+        ];
+
+        for (os, names) in aliases {
+            for name in names {
+                assert_eq!(name.parse::<Os>().unwrap(), os, "{name}");
+            }
+            assert_eq!(os.to_string().parse::<Os>().unwrap(), os);
+        }
+        assert!("plan9".parse::<Os>().is_err());
+    }
+}
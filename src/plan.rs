@@ -0,0 +1,756 @@
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use dashmap::DashMap;
+use eyre::OptionExt;
+
+use crate::{
+    command::Command,
+    starlark::Reader,
+    target::{Group, Output, Source, TargetDef, TargetPath, TargetSet},
+};
+
+/// The full, deduplicated set of targets needed to build some roots, in an order where every
+/// target comes after all of its dependencies.
+#[derive(Debug)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug)]
+pub struct Step {
+    pub path: TargetPath,
+    pub dir: PathBuf,
+    pub deps: Vec<TargetPath>,
+    /// Resolved paths of the srcs of other targets referenced by the command.
+    pub sources: HashMap<Source, PathBuf>,
+
+    set: Arc<TargetSet>,
+}
+
+impl Step {
+    pub fn def(&self) -> &TargetDef {
+        &self.set.targets[self.path.name()]
+    }
+
+    /// The outputs a group or alias exposes under its own name, given those of the targets it
+    /// covers in `outputs`: an alias's under the same names, a group's prefixed by member. Empty
+    /// for other targets.
+    pub fn exports(
+        &self,
+        outputs: &DashMap<Output, PathBuf>,
+    ) -> eyre::Result<Vec<(Output, PathBuf)>> {
+        let of = |target: &TargetPath| {
+            outputs
+                .iter()
+                .filter(|o| o.key().target() == target)
+                .map(|o| (o.key().name().to_string(), o.value().clone()))
+                .collect::<Vec<_>>()
+        };
+
+        let mut exports = Vec::new();
+        match self.def() {
+            TargetDef::Alias(alias) => {
+                for (name, file) in of(&alias.actual) {
+                    exports.push((self.path.output(&name), file));
+                }
+            }
+            TargetDef::Group(group) => {
+                for member in &group.common.prereqs {
+                    for (name, file) in of(member) {
+                        let output = self.path.output(&Group::output_name(member, &name));
+                        let taken = outputs.contains_key(&output)
+                            || exports.iter().any(|(o, _)| o == &output);
+                        eyre::ensure!(
+                            !taken,
+                            "Group {} has conflicting output {}",
+                            self.path,
+                            output.name()
+                        );
+                        exports.push((output, file));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(exports)
+    }
+}
+
+impl Plan {
+    pub fn new<'t>(
+        reader: &Reader,
+        root: &Path,
+        targets: impl IntoIterator<Item = &'t TargetPath>,
+    ) -> eyre::Result<Plan> {
+        let mut planner = Planner::new(reader, root);
+        for target in targets {
+            planner.visit(target)?;
+        }
+        if let Some(cycle) = ordering_cycle(&planner.steps) {
+            let cycle = cycle.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+            eyre::bail!("Dependency cycle: {}", cycle.join(" -> "));
+        }
+
+        Ok(Plan {
+            steps: planner.steps,
+        })
+    }
+
+    /// The plan as printed by `--dump-plan`: like its [`Display`], with each step followed by
+    /// `label(step)` if there is one.
+    pub fn dump(&self, label: impl Fn(&TargetPath) -> Option<&'static str>) -> String {
+        let mut dump = String::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            dump.push_str(&format!("{:>3}. {}", i + 1, step.path));
+            if let Some(label) = label(&step.path) {
+                dump.push_str(&format!(" ({label})"));
+            }
+            dump.push('\n');
+            for dep in &step.deps {
+                dump.push_str(&format!("       <- {dep}\n"));
+            }
+        }
+        dump
+    }
+
+    /// The shortest chain of dependencies leading from `from` to `to`, including both ends.
+    pub fn path(&self, from: &TargetPath, to: &TargetPath) -> Option<Vec<TargetPath>> {
+        let deps = self
+            .steps
+            .iter()
+            .map(|s| (&s.path, &s.deps))
+            .collect::<HashMap<_, _>>();
+
+        let mut parents = HashMap::<&TargetPath, &TargetPath>::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(target) = queue.pop_front() {
+            if target == to {
+                let mut path = vec![target.clone()];
+                let mut current = target;
+                while let Some(parent) = parents.get(current) {
+                    path.push((*parent).clone());
+                    current = parent;
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            for dep in deps.get(target).into_iter().flat_map(|d| d.iter()) {
+                if dep != from && !parents.contains_key(dep) {
+                    parents.insert(dep, target);
+                    queue.push_back(dep);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// A cycle through the dependencies and `after` edges between `steps`, if there is one. `after`
+/// edges to targets outside `steps` are ignored, since they don't affect scheduling.
+fn ordering_cycle(steps: &[Step]) -> Option<Vec<TargetPath>> {
+    let index = steps
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (&s.path, i))
+        .collect::<HashMap<_, _>>();
+    let edges = steps
+        .iter()
+        .map(|s| {
+            s.deps
+                .iter()
+                .chain(&s.def().after)
+                .filter_map(|t| index.get(t).copied())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Visit {
+        New,
+        OnPath,
+        Done,
+    }
+    let mut visits = vec![Visit::New; steps.len()];
+
+    for start in 0..steps.len() {
+        if visits[start] != Visit::New {
+            continue;
+        }
+        visits[start] = Visit::OnPath;
+        // Each step on the path, with the index of the next edge to follow from it.
+        let mut path = vec![(start, 0)];
+
+        while let Some(&(node, next)) = path.last() {
+            let Some(&to) = edges[node].get(next) else {
+                visits[node] = Visit::Done;
+                path.pop();
+                continue;
+            };
+            path.last_mut().unwrap().1 += 1;
+
+            match visits[to] {
+                Visit::New => {
+                    visits[to] = Visit::OnPath;
+                    path.push((to, 0));
+                }
+                Visit::OnPath => {
+                    let begin = path.iter().position(|&(n, _)| n == to).unwrap();
+                    let cycle = path[begin..].iter().map(|&(n, _)| n).chain([to]);
+                    return Some(cycle.map(|n| steps[n].path.clone()).collect());
+                }
+                Visit::Done => {}
+            }
+        }
+    }
+
+    None
+}
+
+/// Every target reachable from `targets`, each listing its direct dependencies. Unlike
+/// [`Plan::new`], cycles are not an error: each target is visited once, so a back-edge just points
+/// at a target that was already visited. Steps are in visit order, not dependency order.
+pub fn reachable<'t>(
+    reader: &Reader,
+    root: &Path,
+    targets: impl IntoIterator<Item = &'t TargetPath>,
+) -> eyre::Result<Vec<Step>> {
+    let mut planner = Planner::new(reader, root);
+    let mut stack = targets.into_iter().cloned().collect::<Vec<_>>();
+    stack.reverse();
+
+    while let Some(target) = stack.pop() {
+        if !planner.visited.insert(target.clone()) {
+            continue;
+        }
+
+        let step = planner
+            .step(&target)
+            .map_err(|e| e.wrap_err(format!("Planning {target}")))?;
+        stack.extend(step.deps.iter().rev().cloned());
+        planner.steps.push(step);
+    }
+
+    Ok(planner.steps)
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.dump(|_| None))
+    }
+}
+
+struct Planner<'p> {
+    reader: &'p Reader,
+    root: &'p Path,
+
+    visited: HashSet<TargetPath>,
+    steps: Vec<Step>,
+}
+
+enum Frame {
+    /// Plan the target, then its dependencies.
+    Enter(TargetPath),
+    /// All dependencies of the step have been planned.
+    Exit(Step),
+}
+
+impl<'p> Planner<'p> {
+    fn new(reader: &'p Reader, root: &'p Path) -> Self {
+        Planner {
+            reader,
+            root,
+            visited: HashSet::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Walks dependencies with an explicit stack rather than recursion, so very deep chains are
+    /// bounded by the heap instead of the call stack.
+    fn visit(&mut self, target: &TargetPath) -> eyre::Result<()> {
+        let mut stack = vec![Frame::Enter(target.clone())];
+        // Targets with an `Exit` frame on the stack, i.e. the chain currently being planned.
+        let mut in_progress = HashSet::new();
+
+        while let Some(frame) = stack.pop() {
+            let target = match frame {
+                Frame::Enter(target) => target,
+                Frame::Exit(step) => {
+                    in_progress.remove(&step.path);
+                    self.steps.push(step);
+                    continue;
+                }
+            };
+
+            let ancestors = || {
+                stack.iter().filter_map(|f| match f {
+                    Frame::Exit(step) => Some(&step.path),
+                    Frame::Enter(_) => None,
+                })
+            };
+            if in_progress.contains(&target) {
+                let cycle = ancestors()
+                    .skip_while(|a| *a != &target)
+                    .chain([&target])
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>();
+                eyre::bail!("Dependency cycle: {}", cycle.join(" -> "));
+            }
+            if !self.visited.insert(target.clone()) {
+                continue;
+            }
+
+            let step = match self.step(&target) {
+                Ok(step) => step,
+                Err(e) => {
+                    let e = e.wrap_err(format!("Planning {target}"));
+                    let ancestors = ancestors().collect::<Vec<_>>();
+                    let e = ancestors
+                        .into_iter()
+                        .rev()
+                        .fold(e, |e, a| e.wrap_err(format!("Planning {a}")));
+                    return Err(e);
+                }
+            };
+
+            let deps = step
+                .deps
+                .iter()
+                .rev()
+                .cloned()
+                .map(Frame::Enter)
+                .collect::<Vec<_>>();
+            in_progress.insert(step.path.clone());
+            stack.push(Frame::Exit(step));
+            stack.extend(deps);
+        }
+
+        Ok(())
+    }
+
+    fn step(&self, target: &TargetPath) -> eyre::Result<Step> {
+        let definition = self.root.join(target.definition());
+        let set = self.reader.read(&definition)?;
+
+        let task = set
+            .targets
+            .get(target.name())
+            .ok_or_eyre(format!("Unknown task: {target}"))?;
+        if let TargetDef::Alias(_) = task {
+            self.resolve_alias(target)?;
+        }
+
+        let deps = task
+            .prereqs
+            .iter()
+            .cloned()
+            .chain(
+                task.cmd
+                    .iter()
+                    .flat_map(|c| c.targets().map(|t| t.borrow().clone())),
+            )
+            .chain(task.stdin.iter().map(|o| o.target().clone()))
+            .collect::<Vec<_>>();
+
+        let outputs = task.cmd.iter().flat_map(Command::outputs);
+        for output in outputs.chain(&task.stdin) {
+            self.ensure_declared(std::slice::from_ref(output))?;
+        }
+        for candidates in task.cmd.iter().flat_map(Command::fallbacks) {
+            self.ensure_declared(candidates)?;
+        }
+        let sources = task
+            .cmd
+            .iter()
+            .flat_map(Command::sources)
+            .map(|s| Ok((s.clone(), self.resolve_source(s)?)))
+            .collect::<eyre::Result<_>>()?;
+
+        Ok(Step {
+            path: target.clone(),
+            dir: definition
+                .parent()
+                .expect("definition is file")
+                .to_path_buf(),
+            deps,
+            sources,
+            set: Arc::clone(&set),
+        })
+    }
+
+    fn resolve_source(&self, source: &Source) -> eyre::Result<PathBuf> {
+        let target = source.target();
+        let definition = self.root.join(target.definition());
+        let set = self.reader.read(&definition)?;
+
+        let build = set
+            .targets
+            .get(target.name())
+            .ok_or_eyre(format!("Unknown task: {target}"))?
+            .as_build()
+            .ok_or_eyre(format!("{target} is not a build, so has no srcs"))?;
+        let path = build.named_srcs.get(source.name()).ok_or_eyre(format!(
+            "target {target} declares no src named '{}'",
+            source.name()
+        ))?;
+
+        Ok(definition.parent().expect("definition is file").join(path))
+    }
+
+    /// Ensures at least one of `candidates`, which all share a target, is declared.
+    fn ensure_declared(&self, candidates: &[Output]) -> eyre::Result<()> {
+        let target = candidates[0].target();
+        let declared = self.declared_outs(target)?;
+
+        if candidates
+            .iter()
+            .any(|c| declared.iter().any(|d| d == c.name()))
+        {
+            return Ok(());
+        }
+
+        let declared = if declared.is_empty() {
+            "none".to_string()
+        } else {
+            declared.join(", ")
+        };
+
+        match candidates {
+            [output] => eyre::bail!(
+                "target {target} declares no output named '{}' (declares: {declared})",
+                output.name()
+            ),
+            _ => {
+                let names = candidates
+                    .iter()
+                    .map(|c| format!("'{}'", c.name()))
+                    .collect::<Vec<_>>();
+                eyre::bail!(
+                    "target {target} declares none of the outputs {} (declares: {declared})",
+                    names.join(", ")
+                )
+            }
+        }
+    }
+
+    /// The non-alias target `target` refers to, following aliases of aliases.
+    fn resolve_alias(&self, target: &TargetPath) -> eyre::Result<TargetPath> {
+        let mut chain = vec![target.clone()];
+        loop {
+            let current = chain.last().unwrap();
+            let set = self.reader.read(self.root.join(current.definition()))?;
+            let Some(TargetDef::Alias(alias)) = set.targets.get(current.name()) else {
+                return Ok(current.clone());
+            };
+
+            let seen = chain.contains(&alias.actual);
+            chain.push(alias.actual.clone());
+            if seen {
+                let start = chain
+                    .iter()
+                    .position(|t| t == chain.last().unwrap())
+                    .unwrap();
+                let cycle = chain[start..]
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>();
+                eyre::bail!("Alias cycle: {}", cycle.join(" -> "));
+            }
+        }
+    }
+
+    fn declared_outs(&self, target: &TargetPath) -> eyre::Result<Vec<String>> {
+        let target = &self.resolve_alias(target)?;
+        let set = self.reader.read(self.root.join(target.definition()))?;
+        let task = set
+            .targets
+            .get(target.name())
+            .ok_or_eyre(format!("Unknown task: {target}"))?;
+
+        let mut declared = match task {
+            TargetDef::Group(_) => {
+                let mut declared = Vec::new();
+                for member in &task.prereqs {
+                    for out in self.declared_outs(member)? {
+                        declared.push(Group::output_name(member, &out));
+                    }
+                }
+                declared
+            }
+            _ => task.outs.keys().cloned().collect(),
+        };
+
+        declared.sort();
+        Ok(declared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::workspace;
+
+    fn plan_strings(root: &Path, targets: &[&str]) -> Vec<String> {
+        let reader = Reader::new(root);
+        let targets = targets
+            .iter()
+            .map(|t| t.parse().unwrap())
+            .collect::<Vec<TargetPath>>();
+
+        let plan = Plan::new(&reader, root, &targets).unwrap();
+        plan.steps.iter().map(|s| s.path.to_string()).collect()
+    }
+
+    #[test]
+    fn shared_dependency_appears_once() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("shared", "true")
+task("a", "true", prereqs = ["//shared"])
+task("b", "true", prereqs = ["//shared"])
+"#,
+        )]);
+
+        assert_eq!(
+            plan_strings(dir.path(), &["//a", "//b"]),
+            &["//shared", "//a", "//b"]
+        );
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let dir = workspace([
+            ("FFS", r#"task("top", "cat //lib/inner:out")"#),
+            (
+                "lib/FFS",
+                r#"task("inner", "touch out", outs = {"out": "out"})"#,
+            ),
+        ]);
+
+        assert_eq!(
+            plan_strings(dir.path(), &["//top"]),
+            &["//lib/inner", "//top"]
+        );
+    }
+
+    #[test]
+    fn order_respects_dependencies() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("top", "true", prereqs = ["//left", "//right"])
+task("left", "true", prereqs = ["//bottom"])
+task("right", "true", prereqs = ["//bottom"])
+task("bottom", "true")
+"#,
+        )]);
+
+        let reader = Reader::new(dir.path());
+        let target = "//top".parse().unwrap();
+        let plan = Plan::new(&reader, dir.path(), [&target]).unwrap();
+
+        let position = |t: &TargetPath| plan.steps.iter().position(|s| &s.path == t).unwrap();
+        for (i, step) in plan.steps.iter().enumerate() {
+            for dep in &step.deps {
+                assert!(position(dep) < i, "{dep} should come before {}", step.path);
+            }
+        }
+        assert_eq!(plan.steps.len(), 4);
+    }
+
+    #[test]
+    fn display_lists_edges() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "true", prereqs = ["//b"])
+task("b", "true")
+"#,
+        )]);
+
+        let reader = Reader::new(dir.path());
+        let target = "//a".parse().unwrap();
+        let plan = Plan::new(&reader, dir.path(), [&target]).unwrap();
+
+        assert_eq!(plan.to_string(), "  1. //b\n  2. //a\n       <- //b\n");
+    }
+
+    #[test]
+    fn undeclared_output() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "cat //t:out")
+task("t", "true")
+task("b", "cat //u:out")
+task("u", "true", outs = {"bin": "bin", "lib": "lib"})
+"#,
+        )]);
+        let reader = Reader::new(dir.path());
+
+        let err = Plan::new(&reader, dir.path(), [&"//a".parse().unwrap()]).unwrap_err();
+        assert!(
+            format!("{err:#}")
+                .contains("target //t declares no output named 'out' (declares: none)"),
+            "{err:#}"
+        );
+
+        let err = Plan::new(&reader, dir.path(), [&"//b".parse().unwrap()]).unwrap_err();
+        assert!(
+            format!("{err:#}")
+                .contains("target //u declares no output named 'out' (declares: bin, lib)"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn unknown_dependency() {
+        let dir = workspace([("FFS", r#"task("a", "true", prereqs = ["//missing"])"#)]);
+
+        let reader = Reader::new(dir.path());
+        let target = "//a".parse().unwrap();
+        assert!(Plan::new(&reader, dir.path(), [&target]).is_err());
+    }
+
+    #[test]
+    fn undeclared_source() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+build("lib", "true", {"main": "main.c"}, {})
+task("a", "cat //lib:src:other")
+"#,
+        )]);
+
+        let reader = Reader::new(dir.path());
+        let target = "//a".parse().unwrap();
+        let err = Plan::new(&reader, dir.path(), [&target]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("target //lib declares no src named 'other'"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn shortest_path() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("c", "true")
+task("b", "true", prereqs = ["//c"])
+task("a", "true", prereqs = ["//b", "//d"])
+task("d", "true")
+"#,
+        )]);
+
+        let reader = Reader::new(dir.path());
+        let [a, b, c] = ["//a", "//b", "//c"].map(|t| t.parse::<TargetPath>().unwrap());
+        let plan = Plan::new(&reader, dir.path(), [&a]).unwrap();
+
+        let path = plan.path(&a, &c).unwrap();
+        let path = path.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(path.join(" -> "), "//a -> //b -> //c");
+
+        assert_eq!(plan.path(&c, &b), None);
+    }
+
+    #[test]
+    fn very_deep_chain() {
+        const DEPTH: usize = 20_000;
+
+        let mut ffs = String::from("task(\"t0\", \"true\")\n");
+        for i in 1..DEPTH {
+            ffs.push_str(&format!(
+                "task(\"t{i}\", \"true\", prereqs = [\"//t{}\"])\n",
+                i - 1
+            ));
+        }
+        let dir = workspace([("FFS", ffs.as_str())]);
+
+        let plan = plan_strings(dir.path(), &[&format!("//t{}", DEPTH - 1)]);
+        assert_eq!(plan.len(), DEPTH);
+        assert_eq!(plan[0], "//t0");
+    }
+
+    #[test]
+    fn reachable_tolerates_cycles() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "true", prereqs = ["//b"])
+task("b", "true", prereqs = ["//a"])
+"#,
+        )]);
+        let root = dir.path();
+
+        let steps = reachable(&Reader::new(root), root, [&"//a".parse().unwrap()]).unwrap();
+        let edges = steps
+            .iter()
+            .flat_map(|s| s.deps.iter().map(|d| format!("{} -> {d}", s.path)))
+            .collect::<Vec<_>>();
+        assert_eq!(edges, ["//a -> //b", "//b -> //a"]);
+    }
+
+    #[test]
+    fn dependency_cycle() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "touch a", prereqs = ["//b"], outs = {"out": "a"})
+task("b", "cat //a:out")
+"#,
+        )]);
+
+        let reader = Reader::new(dir.path());
+        let target = "//a".parse().unwrap();
+        let err = Plan::new(&reader, dir.path(), [&target]).unwrap_err();
+        assert_eq!(err.to_string(), "Dependency cycle: //a -> //b -> //a");
+    }
+
+    #[test]
+    fn self_cycle() {
+        let dir = workspace([("FFS", r#"task("a", "true", prereqs = ["//a"])"#)]);
+
+        let reader = Reader::new(dir.path());
+        let target = "//a".parse().unwrap();
+        let err = Plan::new(&reader, dir.path(), [&target]).unwrap_err();
+        assert_eq!(err.to_string(), "Dependency cycle: //a -> //a");
+    }
+
+    #[test]
+    fn after_edges_in_cycles() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("a", "true", after = ["//b"])
+task("b", "true", prereqs = ["//a"])
+"#,
+        )]);
+
+        let reader = Reader::new(dir.path());
+        let err = Plan::new(&reader, dir.path(), [&"//b".parse().unwrap()]).unwrap_err();
+        assert_eq!(err.to_string(), "Dependency cycle: //a -> //b -> //a");
+    }
+
+    #[test]
+    fn alias_cycle() {
+        let dir = workspace([("FFS", "alias(\"a\", \"//b\")\nalias(\"b\", \"//a\")\n")]);
+
+        let reader = Reader::new(dir.path());
+        let target = "//a".parse().unwrap();
+        let err = Plan::new(&reader, dir.path(), [&target]).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "Alias cycle: //a -> //b -> //a"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use crate::{plan::Step, target::TargetPath};
+
+/// Every target `target` transitively depends on, sorted.
+pub fn deps(steps: &[Step], target: &TargetPath) -> eyre::Result<Vec<TargetPath>> {
+    let edges = steps
+        .iter()
+        .map(|s| (&s.path, s.deps.iter().collect()))
+        .collect();
+    closure(steps, &edges, target)
+}
+
+/// Every target transitively depending on `target`, sorted.
+pub fn rdeps(steps: &[Step], target: &TargetPath) -> eyre::Result<Vec<TargetPath>> {
+    let mut edges = HashMap::<_, Vec<_>>::new();
+    for step in steps {
+        for dep in &step.deps {
+            edges.entry(dep).or_default().push(&step.path);
+        }
+    }
+    closure(steps, &edges, target)
+}
+
+/// Targets reachable from `target` along `edges`, excluding `target` itself. Each target is
+/// visited once, so cycles terminate.
+fn closure(
+    steps: &[Step],
+    edges: &HashMap<&TargetPath, Vec<&TargetPath>>,
+    target: &TargetPath,
+) -> eyre::Result<Vec<TargetPath>> {
+    eyre::ensure!(
+        steps.iter().any(|s| &s.path == target),
+        "Unknown target: {target}"
+    );
+
+    let mut found = BTreeSet::new();
+    let mut queue = VecDeque::from([target]);
+    while let Some(current) = queue.pop_front() {
+        for next in edges.get(current).into_iter().flatten() {
+            if *next != target && found.insert(*next) {
+                queue.push_back(*next);
+            }
+        }
+    }
+
+    Ok(found.into_iter().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{plan, starlark::Reader, testing::workspace};
+
+    fn query(
+        ffs: &str,
+        f: fn(&[Step], &TargetPath) -> eyre::Result<Vec<TargetPath>>,
+    ) -> Vec<Vec<String>> {
+        let dir = workspace([("FFS", ffs)]);
+        let root = dir.path();
+        let targets = ["//a", "//b", "//c"].map(|t| t.parse().unwrap());
+        let steps = plan::reachable(&Reader::new(root), root, &targets).unwrap();
+
+        targets
+            .iter()
+            .map(|t| {
+                f(&steps, t)
+                    .unwrap()
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    const CHAIN: &str = r#"
+build("c", "touch c", [], {"out": "c"})
+task("b", "cat //c:out")
+task("a", "true", prereqs = ["//b"])
+"#;
+
+    #[test]
+    fn deps_of_chain() {
+        assert_eq!(
+            query(CHAIN, deps),
+            [vec!["//b", "//c"], vec!["//c"], vec![]]
+        );
+    }
+
+    #[test]
+    fn rdeps_of_chain() {
+        assert_eq!(
+            query(CHAIN, rdeps),
+            [vec![], vec!["//a"], vec!["//a", "//b"]]
+        );
+    }
+
+    #[test]
+    fn cycles_terminate() {
+        let cycle = r#"
+task("a", "true", prereqs = ["//b"])
+task("b", "true", prereqs = ["//c"])
+task("c", "true", prereqs = ["//a"])
+"#;
+
+        assert_eq!(
+            query(cycle, deps),
+            [vec!["//b", "//c"], vec!["//a", "//c"], vec!["//a", "//b"]]
+        );
+        assert_eq!(
+            query(cycle, rdeps),
+            [vec!["//b", "//c"], vec!["//a", "//c"], vec!["//a", "//b"]]
+        );
+    }
+
+    #[test]
+    fn unknown_target() {
+        let dir = workspace([("FFS", CHAIN)]);
+        let root = dir.path();
+        let steps = plan::reachable(&Reader::new(root), root, [&"//a".parse().unwrap()]).unwrap();
+
+        let e = deps(&steps, &"//missing".parse().unwrap()).unwrap_err();
+        assert_eq!(e.to_string(), "Unknown target: //missing");
+    }
+}
@@ -0,0 +1,363 @@
+//! Portable bundles of a finished build, for reproducing it elsewhere.
+//!
+//! A bundle is a directory containing:
+//!
+//! * `bundle.json` - A [`Bundle`], listing every step of the plan in execution order.
+//! * `files/` - Copies of each step's recorded files (build srcs, downloaded files), at their
+//!   paths relative to the workspace root.
+//! * `stdin/` - Copies of the files steps read as stdin, named by step index.
+//!
+//! Commands are stored fully resolved, with the workspace root replaced by [`ROOT_PLACEHOLDER`] so
+//! they can be replayed from any directory. Tasks may read files that were never declared, which
+//! can't be captured.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use eyre::WrapErr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    builder::Builder,
+    digest,
+    executor::{Execution, Executor},
+    plan::Plan,
+    target::TargetDef,
+};
+
+/// Bumped whenever the bundle format changes incompatibly.
+pub const VERSION: u32 = 1;
+
+pub const ROOT_PLACEHOLDER: &str = "@FFS_ROOT@";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    pub steps: Vec<RecordedStep>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedStep {
+    pub target: String,
+    /// Working directory, relative to the workspace root.
+    pub dir: PathBuf,
+    pub commands: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    /// Interpreter the commands run with, when not the default `sh`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<Vec<String>>,
+    /// Program and arguments for steps run without a shell. `commands` then only shows them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub argv: Option<Vec<String>>,
+    /// File the commands read as stdin, relative to the bundle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<PathBuf>,
+    /// Files copied into the bundle, relative to the workspace root, with their sha256.
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+/// Writes a bundle for `plan`, which `builder` must have already executed.
+pub fn record(bundle: &Path, root: &Path, plan: &Plan, builder: &Builder) -> eyre::Result<()> {
+    let root_str = root
+        .to_str()
+        .ok_or_else(|| eyre::eyre!("Path not utf8 {}", root.display()))?;
+
+    let mut steps = Vec::new();
+    for (i, step) in plan.steps.iter().enumerate() {
+        let def = step.def();
+
+        let files = match def {
+            TargetDef::Build(build) => build.srcs.iter().map(|s| step.dir.join(s)).collect(),
+            TargetDef::HttpFile(http) => http
+                .common
+                .outs
+                .values()
+                .map(|o| o.file(root, &step.dir))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let files = files
+            .iter()
+            .map(|file| files_under(file))
+            .collect::<eyre::Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .map(|file| {
+                let relative = file.strip_prefix(root)?.to_path_buf();
+                let copy = bundle.join("files").join(&relative);
+                std::fs::create_dir_all(copy.parent().expect("copy is file"))?;
+                std::fs::copy(&file, &copy)
+                    .wrap_err_with(|| format!("Copying {}", file.display()))?;
+
+                Ok((relative, digest::sha256_file(&file)?))
+            })
+            .collect::<eyre::Result<_>>()?;
+
+        let argv = match def.argv {
+            true => Some(
+                builder
+                    .argv(step)?
+                    .into_iter()
+                    .map(|a| a.replace(root_str, ROOT_PLACEHOLDER))
+                    .collect(),
+            ),
+            false => None,
+        };
+        let stdin = builder
+            .stdin(step)?
+            .map(|file| {
+                let relative = Path::new("stdin").join(i.to_string());
+                let copy = bundle.join(&relative);
+                std::fs::create_dir_all(copy.parent().expect("copy is file"))?;
+                std::fs::copy(&file, &copy)
+                    .wrap_err_with(|| format!("Copying {}", file.display()))?;
+                Ok::<_, eyre::Report>(relative)
+            })
+            .transpose()?;
+
+        steps.push(RecordedStep {
+            target: step.path.to_string(),
+            dir: builder.workdir(step).strip_prefix(root)?.to_path_buf(),
+            commands: builder
+                .commands(step)?
+                .into_iter()
+                .map(|c| c.replace(root_str, ROOT_PLACEHOLDER))
+                .collect(),
+            env: def.env.clone(),
+            shell: def.shell.clone(),
+            argv,
+            stdin,
+            files,
+        });
+    }
+
+    let contents = serde_json::to_vec_pretty(&Bundle {
+        version: VERSION,
+        steps,
+    })?;
+    std::fs::write(bundle.join("bundle.json"), contents)?;
+
+    Ok(())
+}
+
+/// `path`, or every file under it when it's a directory, so each is bundled with its own hash.
+fn files_under(path: &Path) -> eyre::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        files.extend(files_under(&entry?.path())?);
+    }
+    Ok(files)
+}
+
+/// Restores the files in `bundle` into `root`, then re-runs every recorded command there.
+pub fn replay(bundle: &Path, root: &Path, executor: &Executor) -> eyre::Result<()> {
+    let contents = std::fs::read(bundle.join("bundle.json"))
+        .wrap_err_with(|| format!("Reading bundle {}", bundle.display()))?;
+    let recorded: Bundle = serde_json::from_slice(&contents)?;
+    eyre::ensure!(
+        recorded.version == VERSION,
+        "Unsupported bundle version {}, expected {VERSION}",
+        recorded.version
+    );
+
+    let root_str = root
+        .to_str()
+        .ok_or_else(|| eyre::eyre!("Path not utf8 {}", root.display()))?;
+
+    for step in &recorded.steps {
+        for (relative, expected) in &step.files {
+            let file = root.join(relative);
+            std::fs::create_dir_all(file.parent().expect("file has parent"))?;
+            std::fs::copy(bundle.join("files").join(relative), &file)?;
+
+            let actual = digest::sha256_file(&file)?;
+            eyre::ensure!(
+                &actual == expected,
+                "Bundled file {} is corrupt: expected sha256:{expected}, got sha256:{actual}",
+                relative.display()
+            );
+        }
+
+        let target = step.target.parse()?;
+        let dir = root.join(&step.dir);
+        std::fs::create_dir_all(&dir)?;
+        let argv = step.argv.as_ref().map(|argv| {
+            argv.iter()
+                .map(|a| a.replace(ROOT_PLACEHOLDER, root_str))
+                .collect::<Vec<_>>()
+        });
+        let stdin = step.stdin.as_ref().map(|file| bundle.join(file));
+
+        for command in &step.commands {
+            let command = command.replace(ROOT_PLACEHOLDER, root_str);
+            let output = executor.execute(Execution {
+                path: &target,
+                command: &command,
+                dir: &dir,
+                runs_on: None,
+                arch: None,
+                env: Some(&step.env),
+                timeout: None,
+                dry_run: false,
+                shell: step.shell.as_deref(),
+                stdin: stdin.as_deref(),
+                argv: argv.as_deref(),
+            })?;
+            eyre::ensure!(
+                output.status.success(),
+                "Replaying {target} failed:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::{reporting::Quiet, starlark::Reader, testing::workspace};
+
+    #[test]
+    fn replay_reproduces_outputs() {
+        let dir = workspace([
+            (
+                "lib/FFS",
+                r#"build("upper", "tr a-z A-Z < in.txt > upper.txt", ["in.txt"], {"out": "upper.txt"}, env = {"SUFFIX": "!"})"#,
+            ),
+            ("lib/in.txt", "hello\n"),
+            (
+                "FFS",
+                r#"task("shout", "cat //lib/upper:out > shout.txt; echo $SUFFIX >> shout.txt", outs = {"out": "shout.txt"}, env = {"SUFFIX": "!"})"#,
+            ),
+        ]);
+        let root = dir.path();
+
+        let reader = Reader::new(root);
+        let plan = Plan::new(&reader, root, [&"//shout".parse().unwrap()]).unwrap();
+        let executor = Arc::new(Executor::new(Arc::new(Quiet)));
+        let builder = Builder::new(Arc::clone(&executor), root);
+        builder.execute(&plan).unwrap();
+
+        let bundle = tempfile::tempdir().unwrap();
+        record(bundle.path(), root, &plan, &builder).unwrap();
+
+        let elsewhere = tempfile::tempdir().unwrap();
+        replay(bundle.path(), elsewhere.path(), &executor).unwrap();
+
+        for file in ["lib/upper.txt", "shout.txt"] {
+            assert_eq!(
+                std::fs::read_to_string(elsewhere.path().join(file)).unwrap(),
+                std::fs::read_to_string(root.join(file)).unwrap(),
+                "{file}"
+            );
+        }
+        assert_eq!(
+            std::fs::read_to_string(elsewhere.path().join("shout.txt")).unwrap(),
+            "HELLO\n!\n"
+        );
+    }
+
+    #[test]
+    fn directory_srcs_are_bundled_file_by_file() {
+        let dir = workspace([
+            (
+                "FFS",
+                r#"build("b", "cat src/*/* > out.txt", ["src"], {"out": "out.txt"})"#,
+            ),
+            ("src/nested/in.txt", "one\n"),
+        ]);
+        let root = dir.path();
+
+        let reader = Reader::new(root);
+        let plan = Plan::new(&reader, root, [&"//b".parse().unwrap()]).unwrap();
+        let executor = Arc::new(Executor::new(Arc::new(Quiet)));
+        let builder = Builder::new(Arc::clone(&executor), root);
+        builder.execute(&plan).unwrap();
+
+        let bundle = tempfile::tempdir().unwrap();
+        record(bundle.path(), root, &plan, &builder).unwrap();
+
+        let contents = std::fs::read(bundle.path().join("bundle.json")).unwrap();
+        let recorded: Bundle = serde_json::from_slice(&contents).unwrap();
+        assert_eq!(
+            recorded.steps[0].files,
+            BTreeMap::from([(PathBuf::from("src/nested/in.txt"), digest::sha256("one\n"))])
+        );
+
+        let elsewhere = tempfile::tempdir().unwrap();
+        replay(bundle.path(), elsewhere.path(), &executor).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(elsewhere.path().join("out.txt")).unwrap(),
+            "one\n"
+        );
+    }
+
+    #[test]
+    fn replay_runs_in_workdir() {
+        let dir = workspace([
+            (
+                "pkg/FFS",
+                r#"task("t", "pwd > here", workdir = "sub", outs = {"here": "sub/here"})"#,
+            ),
+            ("pkg/sub/keep", ""),
+        ]);
+        let root = dir.path();
+
+        let reader = Reader::new(root);
+        let plan = Plan::new(&reader, root, [&"//pkg/t".parse().unwrap()]).unwrap();
+        let executor = Arc::new(Executor::new(Arc::new(Quiet)));
+        let builder = Builder::new(Arc::clone(&executor), root);
+        builder.execute(&plan).unwrap();
+
+        let bundle = tempfile::tempdir().unwrap();
+        record(bundle.path(), root, &plan, &builder).unwrap();
+
+        let replayed = tempfile::tempdir().unwrap();
+        let elsewhere = replayed.path().canonicalize().unwrap();
+        replay(bundle.path(), &elsewhere, &executor).unwrap();
+
+        let here = std::fs::read_to_string(elsewhere.join("pkg/sub/here")).unwrap();
+        assert_eq!(here.trim_end(), elsewhere.join("pkg/sub").to_str().unwrap());
+    }
+
+    #[test]
+    fn replay_passes_argv_and_stdin() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+task("gen", "printf 'from stdin' > gen.txt", outs = {"out": "gen.txt"})
+task("read", "cat > read.txt", stdin = "//gen:out", outs = {"out": "read.txt"})
+task("args", ["sh", "-c", "printf '%s' \"$1\" > args.txt", "sh", "a $HOME"], argv = True, outs = {"out": "args.txt"})
+"#,
+        )]);
+        let root = dir.path();
+
+        let reader = Reader::new(root);
+        let targets = ["//read", "//args"].map(|t| t.parse().unwrap());
+        let plan = Plan::new(&reader, root, &targets).unwrap();
+        let executor = Arc::new(Executor::new(Arc::new(Quiet)));
+        let builder = Builder::new(Arc::clone(&executor), root);
+        builder.execute(&plan).unwrap();
+
+        let bundle = tempfile::tempdir().unwrap();
+        record(bundle.path(), root, &plan, &builder).unwrap();
+
+        let elsewhere = tempfile::tempdir().unwrap();
+        replay(bundle.path(), elsewhere.path(), &executor).unwrap();
+
+        let read = |file: &str| std::fs::read_to_string(elsewhere.path().join(file)).unwrap();
+        assert_eq!(read("read.txt"), "from stdin");
+        assert_eq!(read("args.txt"), "a $HOME");
+    }
+}
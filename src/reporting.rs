@@ -1,45 +1,1131 @@
-use std::{io::Write, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
 
-use crate::target::TargetPath;
+use serde_json::json;
+
+use crate::{stats::Stats, target::TargetPath};
 
 #[derive(Debug, Clone, clap::Args)]
 pub struct Options {
+    /// Same as `--reporter quiet`.
     #[arg(long, short)]
     quiet: bool,
+
+    /// Print each command before it runs.
+    #[arg(long, short)]
+    verbose: bool,
+
+    #[arg(long, value_enum, default_value_t)]
+    reporter: Kind,
+
+    /// Write each target's status, duration, and command to this JSON file when the run ends.
+    #[arg(long)]
+    summary: Option<PathBuf>,
+
+    /// Write a JUnit XML report of targets tagged `test` to this file when the run ends.
+    #[arg(long)]
+    junit: Option<PathBuf>,
+}
+
+/// How progress is reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Kind {
+    /// Human readable lines on stderr, colored and with a live status line on a terminal.
+    #[default]
+    Stderr,
+    Quiet,
+    /// One JSON object per event on stdout. Child output goes to stderr instead.
+    Json,
 }
 
 pub fn build_reporter(options: &Options) -> Arc<dyn Reporter> {
-    if options.quiet {
-        return Arc::new(Quiet);
+    let kind = if options.quiet {
+        Kind::Quiet
+    } else {
+        options.reporter
+    };
+    let reporter: Arc<dyn Reporter> = match kind {
+        Kind::Stderr => stderr_reporter(std::io::stderr(), options.verbose),
+        Kind::Quiet => Arc::new(Quiet),
+        Kind::Json => Arc::new(Json::new(std::io::stdout())),
+    };
+
+    let mut reporters = vec![reporter];
+    if let Some(file) = &options.summary {
+        reporters.push(Arc::new(Summary::new(file)));
     }
+    if let Some(file) = &options.junit {
+        reporters.push(Arc::new(JUnit::new(file)));
+    }
+    match reporters.len() {
+        1 => reporters.remove(0),
+        _ => Arc::new(Tee(reporters)),
+    }
+}
+
+/// A [`Tty`] reporter when `out` is a terminal, otherwise plain [`Stderr`] lines.
+fn stderr_reporter(
+    out: impl Write + IsTerminal + Send + 'static,
+    verbose: bool,
+) -> Arc<dyn Reporter> {
+    if out.is_terminal() {
+        Arc::new(Tty::new(out, verbose))
+    } else {
+        Arc::new(Stderr::new(out, verbose))
+    }
+}
+
+/// Targets with this tag are reported as test cases by [`JUnit`].
+pub const TEST_TAG: &str = "test";
+
+/// Why a test target failed.
+#[derive(Clone)]
+pub struct TestFailure {
+    pub message: String,
+    /// Captured stderr of the failing command, if it got as far as running one.
+    pub stderr: String,
+}
 
-    Arc::new(Stderr(std::io::stderr()))
+/// How a target ended up in a build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Its command ran, or it has none.
+    Built,
+    /// Its outputs were restored from the cache.
+    Cached,
+    /// It was already up to date.
+    Skipped,
+    Failed,
 }
 
 #[allow(unused)]
-pub trait Reporter {
+pub trait Reporter: Send + Sync {
+    /// A build of `targets` targets is starting.
+    fn begin_build(&self, targets: usize) {}
     fn begin_execute(&self, task: &TargetPath) {}
+    /// `task` is about to run `cmd`, with secret environment values redacted.
+    fn command(&self, task: &TargetPath, cmd: &str) {}
     fn finish_execute(&self, task: &TargetPath, took: Duration) {}
+    /// `task` is done, whether or not it ran anything, after `took` in total.
+    fn finish_target(&self, task: &TargetPath, status: Status, took: Duration) {}
     fn finish_top_level(&self) {}
+    /// Roughly how much longer the build should take, from how long its pending targets took
+    /// in earlier runs.
+    fn estimate(&self, remaining: Duration) {}
+    /// A target tagged [`TEST_TAG`] is done, passing unless there's a `failure`.
+    fn finish_test(&self, task: &TargetPath, failure: Option<&TestFailure>) {}
+    fn note(&self, message: &str) {}
+    /// `task` failed and is about to run again, for the `attempt`th time after the first.
+    fn retry(&self, task: &TargetPath, attempt: u32) {}
+    /// A watched build is starting over because `changed` files changed.
+    fn begin_cycle(&self, changed: &[PathBuf]) {}
+    /// The chain of targets that took longest end to end, with how long each took.
+    fn critical_path(&self, path: &[(TargetPath, Duration)]) {}
+    /// Run `iteration` of `of` repeated runs finished after `took`.
+    fn finish_iteration(&self, iteration: usize, of: usize, took: Duration) {}
+    /// How long each of the repeated runs took.
+    fn repeat_stats(&self, stats: &Stats) {}
+    /// Whether events are written to stdout, in which case child output has to go to stderr to
+    /// keep stdout parseable.
+    fn owns_stdout(&self) -> bool {
+        false
+    }
+    /// Child output is about to be written straight to the terminal by `write`, so anything
+    /// drawn below the log, like a live footer, is cleared first and redrawn once the output
+    /// `ends_line`.
+    fn interleave(&self, ends_line: bool, write: &mut dyn FnMut()) {
+        write()
+    }
 }
 
-struct Quiet;
+pub struct Quiet;
 
 impl Reporter for Quiet {}
 
-struct Stderr(std::io::Stderr);
+/// Forwards every event to each reporter in turn.
+struct Tee(Vec<Arc<dyn Reporter>>);
+
+impl Reporter for Tee {
+    fn begin_build(&self, targets: usize) {
+        self.0.iter().for_each(|r| r.begin_build(targets));
+    }
+
+    fn begin_execute(&self, task: &TargetPath) {
+        self.0.iter().for_each(|r| r.begin_execute(task));
+    }
+
+    fn command(&self, task: &TargetPath, cmd: &str) {
+        self.0.iter().for_each(|r| r.command(task, cmd));
+    }
+
+    fn finish_execute(&self, task: &TargetPath, took: Duration) {
+        self.0.iter().for_each(|r| r.finish_execute(task, took));
+    }
+
+    fn finish_target(&self, task: &TargetPath, status: Status, took: Duration) {
+        self.0
+            .iter()
+            .for_each(|r| r.finish_target(task, status, took));
+    }
+
+    fn finish_top_level(&self) {
+        self.0.iter().for_each(|r| r.finish_top_level());
+    }
+
+    fn estimate(&self, remaining: Duration) {
+        self.0.iter().for_each(|r| r.estimate(remaining));
+    }
+
+    fn finish_test(&self, task: &TargetPath, failure: Option<&TestFailure>) {
+        self.0.iter().for_each(|r| r.finish_test(task, failure));
+    }
+
+    fn note(&self, message: &str) {
+        self.0.iter().for_each(|r| r.note(message));
+    }
+
+    fn retry(&self, task: &TargetPath, attempt: u32) {
+        self.0.iter().for_each(|r| r.retry(task, attempt));
+    }
+
+    fn begin_cycle(&self, changed: &[PathBuf]) {
+        self.0.iter().for_each(|r| r.begin_cycle(changed));
+    }
+
+    fn critical_path(&self, path: &[(TargetPath, Duration)]) {
+        self.0.iter().for_each(|r| r.critical_path(path));
+    }
+
+    fn finish_iteration(&self, iteration: usize, of: usize, took: Duration) {
+        self.0
+            .iter()
+            .for_each(|r| r.finish_iteration(iteration, of, took));
+    }
+
+    fn repeat_stats(&self, stats: &Stats) {
+        self.0.iter().for_each(|r| r.repeat_stats(stats));
+    }
+
+    fn owns_stdout(&self) -> bool {
+        self.0.iter().any(|r| r.owns_stdout())
+    }
+
+    fn interleave(&self, ends_line: bool, write: &mut dyn FnMut()) {
+        interleave_all(&self.0, ends_line, write)
+    }
+}
+
+/// Has each of `reporters` in turn wrap `write`.
+fn interleave_all(reporters: &[Arc<dyn Reporter>], ends_line: bool, write: &mut dyn FnMut()) {
+    match reporters.split_first() {
+        Some((first, rest)) => {
+            first.interleave(ends_line, &mut || interleave_all(rest, ends_line, write))
+        }
+        None => write(),
+    }
+}
+
+/// Human readable lines, printing commands too when `verbose`.
+struct Stderr {
+    out: Mutex<Box<dyn Write + Send>>,
+    verbose: bool,
+}
+
+impl Stderr {
+    fn new(out: impl Write + Send + 'static, verbose: bool) -> Self {
+        Stderr {
+            out: Mutex::new(Box::new(out)),
+            verbose,
+        }
+    }
+
+    fn out(&self) -> MutexGuard<'_, Box<dyn Write + Send>> {
+        self.out.lock().unwrap()
+    }
+}
 
 impl Reporter for Stderr {
     fn begin_execute(&self, task: &TargetPath) {
-        let _ = writeln!(&self.0, "Running {task}");
+        let _ = writeln!(self.out(), "Running {task}");
+    }
+
+    fn command(&self, task: &TargetPath, cmd: &str) {
+        if self.verbose {
+            let _ = writeln!(self.out(), "Command {task}: {cmd}");
+        }
+    }
+
+    fn finish_execute(&self, task: &TargetPath, took: Duration) {
+        let _ = writeln!(self.out(), "Finish  {task} in {:.3}s", took.as_secs_f64());
+    }
+
+    fn note(&self, message: &str) {
+        let _ = writeln!(self.out(), "Note: {message}");
+    }
+
+    fn retry(&self, task: &TargetPath, attempt: u32) {
+        let _ = writeln!(self.out(), "Retry   {task} (attempt {})", attempt + 1);
+    }
+
+    fn begin_cycle(&self, changed: &[PathBuf]) {
+        let first = changed.first().map(|f| f.display().to_string());
+        let _ = match (first, changed.len()) {
+            (Some(first), 1) => writeln!(self.out(), "--- {first} changed ---"),
+            (Some(first), n) => writeln!(self.out(), "--- {first} and {} more changed ---", n - 1),
+            (None, _) => writeln!(self.out(), "---"),
+        };
+    }
+
+    fn critical_path(&self, path: &[(TargetPath, Duration)]) {
+        if path.is_empty() {
+            return;
+        }
+
+        let total = path.iter().map(|(_, took)| *took).sum::<Duration>();
+        let mut out = self.out();
+        let _ = writeln!(out, "Critical path {:.3}s:", total.as_secs_f64());
+        for (target, took) in path {
+            let _ = writeln!(out, "  {:>8.3}s {target}", took.as_secs_f64());
+        }
+    }
+
+    fn finish_iteration(&self, iteration: usize, of: usize, took: Duration) {
+        let _ = writeln!(self.out(), "{}", iteration_line(iteration, of, took));
+    }
+
+    fn repeat_stats(&self, stats: &Stats) {
+        let _ = writeln!(self.out(), "{}", stats_line(stats));
+    }
+}
+
+fn iteration_line(iteration: usize, of: usize, took: Duration) -> String {
+    format!("Run {iteration}/{of} in {:.3}s", took.as_secs_f64())
+}
+
+fn stats_line(stats: &Stats) -> String {
+    format!(
+        "Runs took min {:.3}s, median {:.3}s, max {:.3}s",
+        stats.min.as_secs_f64(),
+        stats.median.as_secs_f64(),
+        stats.max.as_secs_f64()
+    )
+}
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// [`Stderr`] for a terminal: results are colored, and a footer listing running targets and how
+/// many are left is redrawn in place below the other lines.
+struct Tty {
+    out: Mutex<Box<dyn Write + Send>>,
+    verbose: bool,
+    progress: Mutex<Progress>,
+}
+
+#[derive(Default)]
+struct Progress {
+    running: BTreeSet<TargetPath>,
+    total: usize,
+    finished: usize,
+    /// The latest [`Reporter::estimate`].
+    eta: Option<Duration>,
+    /// Whether the footer is currently on screen.
+    drawn: bool,
+}
+
+impl Progress {
+    fn footer(&self, width: usize) -> String {
+        let left = self.total.saturating_sub(self.finished);
+        let running = self
+            .running
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>();
+        let eta = match self.eta {
+            Some(eta) => format!(", ~{}", approximate(eta)),
+            None => String::new(),
+        };
+        let footer = format!("[{left} left{eta}] {}", running.join(", "));
+
+        if footer.chars().count() <= width {
+            return footer;
+        }
+        let kept = footer
+            .chars()
+            .take(width.saturating_sub(1))
+            .collect::<String>();
+        format!("{kept}…")
+    }
+}
+
+/// `took` to the second, like `1m05s` or `42s`.
+fn approximate(took: Duration) -> String {
+    let secs = took.as_secs_f64().round() as u64;
+    match secs {
+        0..=59 => format!("{secs}s"),
+        _ => format!("{}m{:02}s", secs / 60, secs % 60),
+    }
+}
+
+impl Tty {
+    fn new(out: impl Write + Send + 'static, verbose: bool) -> Self {
+        Tty {
+            out: Mutex::new(Box::new(out)),
+            verbose,
+            progress: Default::default(),
+        }
+    }
+
+    /// Applies `update`, then prints `lines` above a redrawn footer.
+    fn print(&self, lines: &[String], update: impl FnOnce(&mut Progress)) {
+        let mut out = self.out.lock().unwrap();
+        let mut progress = self.progress.lock().unwrap();
+        update(&mut progress);
+
+        if progress.drawn {
+            // Back to the start of the footer, clearing it.
+            let _ = write!(out, "\x1b[1F\x1b[J");
+        }
+        for line in lines {
+            let _ = writeln!(out, "{line}");
+        }
+        Self::draw(&mut *out, &mut progress);
+    }
+
+    /// Draws the footer below everything printed so far, if anything is running.
+    fn draw(out: &mut impl Write, progress: &mut Progress) {
+        progress.drawn = !progress.running.is_empty();
+        if progress.drawn {
+            let width = terminal_size::terminal_size().map_or(80, |(w, _)| w.0 as usize);
+            let _ = writeln!(out, "{DIM}{}{RESET}", progress.footer(width));
+        }
+        let _ = out.flush();
+    }
+}
+
+impl Reporter for Tty {
+    fn begin_build(&self, targets: usize) {
+        self.print(&[], |p| {
+            p.total = targets;
+            p.finished = 0;
+            p.eta = None;
+        });
+    }
+
+    fn begin_execute(&self, task: &TargetPath) {
+        self.print(&[], |p| {
+            p.running.insert(task.clone());
+        });
+    }
+
+    fn command(&self, task: &TargetPath, cmd: &str) {
+        if self.verbose {
+            self.print(&[format!("{DIM}Command {task}: {cmd}{RESET}")], |_| {});
+        }
+    }
+
+    fn finish_execute(&self, task: &TargetPath, took: Duration) {
+        let line = format!("{GREEN}Finish{RESET}  {task} in {:.3}s", took.as_secs_f64());
+        self.print(&[line], |p| {
+            p.running.remove(task);
+        });
+    }
+
+    fn finish_target(&self, task: &TargetPath, status: Status, _: Duration) {
+        let lines = match status {
+            Status::Failed => vec![format!("{RED}Failed{RESET}  {task}")],
+            _ => Vec::new(),
+        };
+        self.print(&lines, |p| {
+            p.running.remove(task);
+            p.finished += 1;
+        });
+    }
+
+    fn finish_top_level(&self) {
+        self.print(&[], |p| p.running.clear());
+    }
+
+    fn estimate(&self, remaining: Duration) {
+        self.print(&[], |p| p.eta = Some(remaining));
+    }
+
+    fn note(&self, message: &str) {
+        self.print(&[format!("Note: {message}")], |_| {});
+    }
+
+    fn retry(&self, task: &TargetPath, attempt: u32) {
+        let line = format!("{YELLOW}Retry{RESET}   {task} (attempt {})", attempt + 1);
+        self.print(&[line], |_| {});
+    }
+
+    fn begin_cycle(&self, changed: &[PathBuf]) {
+        let first = changed.first().map(|f| f.display().to_string());
+        let line = match (first, changed.len()) {
+            (Some(first), 1) => format!("--- {first} changed ---"),
+            (Some(first), n) => format!("--- {first} and {} more changed ---", n - 1),
+            (None, _) => "---".to_string(),
+        };
+        self.print(&[line], |_| {});
+    }
+
+    fn critical_path(&self, path: &[(TargetPath, Duration)]) {
+        if path.is_empty() {
+            return;
+        }
+
+        let total = path.iter().map(|(_, took)| *took).sum::<Duration>();
+        let mut lines = vec![format!("Critical path {:.3}s:", total.as_secs_f64())];
+        for (target, took) in path {
+            lines.push(format!("  {:>8.3}s {target}", took.as_secs_f64()));
+        }
+        self.print(&lines, |_| {});
+    }
+
+    fn finish_iteration(&self, iteration: usize, of: usize, took: Duration) {
+        self.print(&[iteration_line(iteration, of, took)], |_| {});
+    }
+
+    fn repeat_stats(&self, stats: &Stats) {
+        self.print(&[stats_line(stats)], |_| {});
+    }
+
+    fn interleave(&self, ends_line: bool, write: &mut dyn FnMut()) {
+        let mut out = self.out.lock().unwrap();
+        let mut progress = self.progress.lock().unwrap();
+
+        if progress.drawn {
+            let _ = write!(out, "\x1b[1F\x1b[J");
+            let _ = out.flush();
+        }
+        write();
+        // Drawn after a partial line, the footer couldn't be cleared without erasing it.
+        match ends_line {
+            true => Self::draw(&mut *out, &mut progress),
+            false => progress.drawn = false,
+        }
+    }
+}
+
+/// Emits newline-delimited JSON events, ending with a `done` event counting finished targets.
+pub struct Json {
+    out: Mutex<Box<dyn Write + Send>>,
+    finished: AtomicUsize,
+}
+
+impl Json {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Json {
+            out: Mutex::new(Box::new(out)),
+            finished: AtomicUsize::new(0),
+        }
+    }
+
+    fn emit(&self, event: serde_json::Value) {
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{event}");
+        let _ = out.flush();
+    }
+}
+
+impl Reporter for Json {
+    fn owns_stdout(&self) -> bool {
+        true
+    }
+
+    fn begin_execute(&self, task: &TargetPath) {
+        self.emit(json!({"event": "begin", "target": task}));
+    }
+
+    fn finish_execute(&self, task: &TargetPath, took: Duration) {
+        self.finished.fetch_add(1, Ordering::Relaxed);
+        self.emit(json!({"event": "finish", "target": task, "ms": took.as_millis()}));
+    }
+
+    fn finish_top_level(&self) {
+        let count = self.finished.load(Ordering::Relaxed);
+        self.emit(json!({"event": "done", "count": count}));
+    }
+
+    fn estimate(&self, remaining: Duration) {
+        self.emit(json!({"event": "estimate", "ms": remaining.as_millis()}));
+    }
+
+    fn note(&self, message: &str) {
+        self.emit(json!({"event": "note", "message": message}));
+    }
+
+    fn retry(&self, task: &TargetPath, attempt: u32) {
+        self.emit(json!({"event": "retry", "target": task, "attempt": attempt}));
+    }
+
+    fn critical_path(&self, path: &[(TargetPath, Duration)]) {
+        let path = path
+            .iter()
+            .map(|(target, took)| json!({"target": target, "ms": took.as_millis()}))
+            .collect::<Vec<_>>();
+        self.emit(json!({"event": "critical_path", "path": path}));
+    }
+
+    fn finish_iteration(&self, iteration: usize, of: usize, took: Duration) {
+        let ms = took.as_millis();
+        self.emit(json!({"event": "iteration", "iteration": iteration, "of": of, "ms": ms}));
+    }
+
+    fn repeat_stats(&self, stats: &Stats) {
+        self.emit(json!({
+            "event": "repeat_stats",
+            "min_ms": stats.min.as_millis(),
+            "median_ms": stats.median.as_millis(),
+            "max_ms": stats.max.as_millis(),
+        }));
     }
+}
+
+/// Collects the outcome of every target, writing them to `file` as a JSON report when the run
+/// finishes.
+pub struct Summary {
+    file: PathBuf,
+    targets: Mutex<Vec<serde_json::Value>>,
+    /// Commands run by targets that haven't finished yet.
+    commands: Mutex<HashMap<TargetPath, Vec<String>>>,
+}
+
+impl Summary {
+    pub fn new(file: impl Into<PathBuf>) -> Self {
+        Summary {
+            file: file.into(),
+            targets: Default::default(),
+            commands: Default::default(),
+        }
+    }
+
+    fn write(&self, targets: Vec<serde_json::Value>) -> std::io::Result<()> {
+        let report = json!({"targets": targets});
+        replace_file(&self.file, &serde_json::to_vec_pretty(&report)?)
+    }
+}
+
+/// Writes to a temporary file renamed into place, so readers never see a partial report.
+fn replace_file(file: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut partial = file.to_path_buf().into_os_string();
+    partial.push(".partial");
+    std::fs::write(&partial, contents)?;
+    std::fs::rename(&partial, file)
+}
+
+impl Reporter for Summary {
+    fn command(&self, task: &TargetPath, cmd: &str) {
+        let mut commands = self.commands.lock().unwrap();
+        commands
+            .entry(task.clone())
+            .or_default()
+            .push(cmd.to_string());
+    }
+
+    fn finish_target(&self, task: &TargetPath, status: Status, took: Duration) {
+        let command = self.commands.lock().unwrap().remove(task);
+        self.targets.lock().unwrap().push(json!({
+            "target": task,
+            "status": status,
+            "ms": took.as_millis(),
+            "command": command.map(|c| c.join("\n")),
+        }));
+    }
+
+    fn finish_top_level(&self) {
+        let targets = std::mem::take(&mut *self.targets.lock().unwrap());
+        if let Err(e) = self.write(targets) {
+            eprintln!("Failed to write summary {}: {e}", self.file.display());
+        }
+    }
+}
 
+/// Writes a JUnit XML report with a test case per target tagged [`TEST_TAG`] when the run
+/// finishes, timed by how long their commands ran.
+pub struct JUnit {
+    file: PathBuf,
+    took: Mutex<HashMap<TargetPath, Duration>>,
+    cases: Mutex<Vec<(TargetPath, Duration, Option<TestFailure>)>>,
+}
+
+impl JUnit {
+    pub fn new(file: impl Into<PathBuf>) -> Self {
+        JUnit {
+            file: file.into(),
+            took: Default::default(),
+            cases: Default::default(),
+        }
+    }
+
+    fn render(cases: &[(TargetPath, Duration, Option<TestFailure>)]) -> String {
+        let failures = cases.iter().filter(|(_, _, f)| f.is_some()).count();
+        let total = cases.iter().map(|(_, took, _)| *took).sum::<Duration>();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+        xml.push_str(&format!(
+            "  <testsuite name=\"ffs\" tests=\"{}\" failures=\"{failures}\" time=\"{:.3}\">\n",
+            cases.len(),
+            total.as_secs_f64()
+        ));
+        for (task, took, failure) in cases {
+            let name = xml_escape(&task.to_string());
+            let time = took.as_secs_f64();
+            let Some(failure) = failure else {
+                xml.push_str(&format!(
+                    "    <testcase name=\"{name}\" classname=\"ffs\" time=\"{time:.3}\"/>\n"
+                ));
+                continue;
+            };
+            xml.push_str(&format!(
+                "    <testcase name=\"{name}\" classname=\"ffs\" time=\"{time:.3}\">\n"
+            ));
+            xml.push_str(&format!(
+                "      <failure message=\"{}\">{}</failure>\n",
+                xml_escape(&failure.message),
+                xml_escape(&failure.stderr)
+            ));
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+}
+
+impl Reporter for JUnit {
     fn finish_execute(&self, task: &TargetPath, took: Duration) {
-        let _ = writeln!(
-            &self.0,
-            "Finish  {task} in {}.{}s",
-            took.as_secs(),
-            took.subsec_millis()
+        *self.took.lock().unwrap().entry(task.clone()).or_default() += took;
+    }
+
+    fn finish_test(&self, task: &TargetPath, failure: Option<&TestFailure>) {
+        let took = self.took.lock().unwrap().remove(task).unwrap_or_default();
+        let failure = failure.cloned();
+        self.cases
+            .lock()
+            .unwrap()
+            .push((task.clone(), took, failure));
+    }
+
+    fn finish_top_level(&self) {
+        let mut cases = std::mem::take(&mut *self.cases.lock().unwrap());
+        cases.sort_by(|a, b| a.0.cmp(&b.0));
+        if let Err(e) = replace_file(&self.file, Self::render(&cases).as_bytes()) {
+            eprintln!("Failed to write JUnit report {}: {e}", self.file.display());
+        }
+    }
+}
+
+/// Escapes text for XML, dropping ANSI escape codes and control characters XML can't hold.
+fn xml_escape(text: &str) -> String {
+    let text = String::from_utf8_lossy(&strip_ansi(text.as_bytes())).into_owned();
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\r' | '\t' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// How ANSI escape codes in captured child output are treated when it is re-emitted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChildrenColor {
+    #[default]
+    Preserve,
+    Strip,
+}
+
+impl ChildrenColor {
+    pub fn apply<'b>(&self, output: &'b [u8]) -> Cow<'b, [u8]> {
+        match self {
+            ChildrenColor::Preserve => Cow::Borrowed(output),
+            ChildrenColor::Strip => Cow::Owned(strip_ansi(output)),
+        }
+    }
+}
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+fn strip_ansi(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != ESC {
+            result.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        i += 1;
+        match bytes.get(i) {
+            // CSI: parameters, then a final byte in 0x40..=0x7e.
+            Some(b'[') => {
+                i += 1;
+                while i < bytes.len() && !(0x40..=0x7e).contains(&bytes[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            // OSC: terminated by BEL or ST (`ESC \`).
+            Some(b']') => {
+                i += 1;
+                while i < bytes.len() {
+                    if bytes[i] == BEL {
+                        i += 1;
+                        break;
+                    }
+                    if bytes[i] == ESC && bytes.get(i + 1) == Some(&b'\\') {
+                        i += 2;
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            // Other escapes: intermediate bytes in 0x20..=0x2f, then a final byte.
+            Some(_) => {
+                while i < bytes.len() && (0x20..=0x2f).contains(&bytes[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            None => {}
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_removes_escape_codes() {
+        let colored = b"\x1b[1;31merror\x1b[0m: bad\n\x1b]0;title\x07done\x1b(B\n";
+
+        assert_eq!(
+            ChildrenColor::Strip.apply(colored).as_ref(),
+            b"error: bad\ndone\n"
+        );
+        assert_eq!(ChildrenColor::Preserve.apply(colored).as_ref(), colored);
+    }
+
+    #[test]
+    fn json_events_in_order() {
+        use std::io::{Read, Seek};
+
+        use crate::{builder::Builder, executor::Executor, plan::Plan, starlark::Reader};
+
+        let dir = crate::testing::workspace([(
+            "FFS",
+            r#"
+task("a", "true")
+task("b", "true", prereqs = ["//a"])
+"#,
+        )]);
+        let root = dir.path();
+
+        let mut file = tempfile::tempfile().unwrap();
+        let reporter = Arc::new(Json::new(file.try_clone().unwrap()));
+
+        let reader = Reader::new(root);
+        let plan = Plan::new(&reader, root, [&"//b".parse().unwrap()]).unwrap();
+        let executor = Arc::new(Executor::new(reporter.clone()));
+        Builder::new(executor, root).execute(&plan).unwrap();
+        reporter.finish_top_level();
+
+        let mut emitted = String::new();
+        file.rewind().unwrap();
+        file.read_to_string(&mut emitted).unwrap();
+        let events = emitted
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+
+        let summary = events
+            .iter()
+            .map(|e| format!("{} {}", e["event"].as_str().unwrap(), e["target"]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                "estimate null",
+                r#"begin "//a""#,
+                r#"finish "//a""#,
+                "estimate null",
+                r#"begin "//b""#,
+                r#"finish "//b""#,
+                "estimate null",
+                "done null",
+            ]
+        );
+        assert!(events[0]["ms"].is_u64());
+        assert!(events[2]["ms"].is_u64());
+        assert_eq!(events[7]["count"], 2);
+    }
+
+    #[test]
+    fn json_keeps_stdout_to_itself() {
+        assert!(Json::new(std::io::sink()).owns_stdout());
+        assert!(!Quiet.owns_stdout());
+        assert!(Tee(vec![Arc::new(Quiet), Arc::new(Json::new(std::io::sink()))]).owns_stdout());
+    }
+
+    #[test]
+    fn summary_records_each_status() {
+        use crate::{builder::Builder, executor::Executor, plan::Plan, starlark::Reader};
+
+        let dir = crate::testing::workspace([
+            (
+                "FFS",
+                r#"
+build("gen", "cp in out", ["in"], {"out": "out"})
+task("ok", "true")
+task("bad", "exit 3")
+"#,
+            ),
+            ("in", "in\n"),
+        ]);
+        let root = dir.path();
+        let reader = Reader::new(root);
+        let build = |targets: &[&str], reporter: Arc<dyn Reporter>| {
+            let targets = targets
+                .iter()
+                .map(|t| t.parse().unwrap())
+                .collect::<Vec<_>>();
+            let plan = Plan::new(&reader, root, &targets).unwrap();
+            Builder::new(Arc::new(Executor::new(reporter)), root)
+                .keep_going(true)
+                .execute(&plan)
+        };
+
+        build(&["//gen"], Arc::new(Quiet)).unwrap();
+        std::fs::remove_file(root.join("out")).unwrap();
+
+        let file = root.join("summary.json");
+        let summary = Arc::new(Summary::new(&file));
+        let e = build(&["//gen", "//ok", "//bad"], summary.clone()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "1 targets failed:\n  //bad: Task failed: //bad"
+        );
+        summary.finish_top_level();
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&file).unwrap()).unwrap();
+        let mut targets = report["targets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| {
+                assert!(t["ms"].is_u64());
+                let (target, status) = (&t["target"], &t["status"]);
+                format!("{target} {status} {}", t["command"])
+            })
+            .collect::<Vec<_>>();
+        targets.sort();
+        assert_eq!(
+            targets,
+            [
+                r#""//bad" "failed" "exit 3""#,
+                r#""//gen" "cached" null"#,
+                r#""//ok" "built" "true""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn junit_reports_test_targets() {
+        use crate::{builder::Builder, executor::Executor, plan::Plan, starlark::Reader};
+
+        let dir = crate::testing::workspace([(
+            "FFS",
+            r#"
+task("pass", "true", tags = ["test"])
+task("fail", "echo 'got <1> & more' >&2; exit 1", tags = ["test"])
+task("other", "true")
+"#,
+        )]);
+        let root = dir.path();
+        let file = root.join("junit.xml");
+        let junit = Arc::new(JUnit::new(&file));
+
+        let reader = Reader::new(root);
+        let targets = ["//pass", "//fail", "//other"].map(|t| t.parse().unwrap());
+        let plan = Plan::new(&reader, root, &targets).unwrap();
+        let e = Builder::new(Arc::new(Executor::new(junit.clone())), root)
+            .keep_going(true)
+            .execute(&plan)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "1 targets failed:\n  //fail: Task failed: //fail"
+        );
+        junit.finish_top_level();
+
+        let xml = std::fs::read_to_string(&file).unwrap();
+        assert!(xml.starts_with("<?xml version=\"1.0\""), "{xml}");
+        assert!(xml.contains(r#"tests="2" failures="1""#), "{xml}");
+        assert!(xml.contains(r#"<testcase name="//pass" classname="ffs" time=""#));
+        assert!(
+            xml.contains(r#"<failure message="Task failed: //fail">got &lt;1&gt; &amp; more"#),
+            "{xml}"
+        );
+        assert!(!xml.contains("//other"), "{xml}");
+        assert_eq!(xml.matches("<testcase").count(), 2);
+        assert_eq!(xml.matches("</testcase>").count(), 1);
+    }
+
+    fn replay(reporter: &dyn Reporter) {
+        let (a, b) = ("//a".parse().unwrap(), "//b".parse().unwrap());
+        reporter.begin_build(2);
+        reporter.begin_execute(&a);
+        reporter.begin_execute(&b);
+        reporter.finish_execute(&a, Duration::from_millis(1500));
+        reporter.finish_target(&a, Status::Built, Duration::from_millis(1500));
+        reporter.finish_execute(&b, Duration::from_millis(20));
+        reporter.finish_target(&b, Status::Failed, Duration::from_millis(20));
+        reporter.finish_top_level();
+    }
+
+    fn read(mut file: std::fs::File) -> String {
+        use std::io::{Read, Seek};
+
+        let mut printed = String::new();
+        file.rewind().unwrap();
+        file.read_to_string(&mut printed).unwrap();
+        printed
+    }
+
+    #[test]
+    fn plain_lines_when_not_a_terminal() {
+        let file = tempfile::tempfile().unwrap();
+        replay(&*stderr_reporter(file.try_clone().unwrap(), false));
+
+        assert_eq!(
+            read(file),
+            "Running //a\nRunning //b\nFinish  //a in 1.500s\nFinish  //b in 0.020s\n"
+        );
+    }
+
+    #[test]
+    fn tty_colors_results_and_redraws_footer() {
+        let file = tempfile::tempfile().unwrap();
+        replay(&Tty::new(file.try_clone().unwrap(), false));
+
+        let printed = read(file);
+        let clear = "\x1b[1F\x1b[J";
+        let expected = [
+            "\x1b[2m[2 left] //a\x1b[0m\n",
+            clear,
+            "\x1b[2m[2 left] //a, //b\x1b[0m\n",
+            clear,
+            "\x1b[32mFinish\x1b[0m  //a in 1.500s\n",
+            "\x1b[2m[2 left] //b\x1b[0m\n",
+            clear,
+            "\x1b[2m[1 left] //b\x1b[0m\n",
+            clear,
+            "\x1b[32mFinish\x1b[0m  //b in 0.020s\n",
+            "\x1b[31mFailed\x1b[0m  //b\n",
+        ];
+        assert_eq!(printed, expected.concat());
+    }
+
+    #[test]
+    fn footer_shows_estimate_and_fits_width() {
+        let progress = Progress {
+            running: ["//a", "//bb"]
+                .map(|t| t.parse::<TargetPath>().unwrap())
+                .into(),
+            total: 3,
+            finished: 1,
+            eta: Some(Duration::from_millis(89_600)),
+            drawn: false,
+        };
+
+        assert_eq!(progress.footer(80), "[2 left, ~1m30s] //a, //bb");
+        assert_eq!(progress.footer(22), "[2 left, ~1m30s] //a,…");
+        assert_eq!(approximate(Duration::from_millis(400)), "0s");
+    }
+
+    #[test]
+    fn streamed_output_goes_above_the_footer() {
+        use crate::{executor::Executor, testing::execution};
+
+        let file = tempfile::tempfile().unwrap();
+        let tty = Arc::new(Tty::new(file.try_clone().unwrap(), false));
+        let executor = Executor::new(tty.clone())
+            .stream_to(file.try_clone().unwrap(), file.try_clone().unwrap());
+        let (a, b) = ("//a".parse().unwrap(), "//b".parse().unwrap());
+        let run = |command| executor.execute(execution(&a, command)).unwrap();
+
+        tty.begin_build(2);
+        tty.begin_execute(&b);
+        run("echo one");
+        run("printf partial");
+
+        let printed = read(file);
+        let clear = "\x1b[1F\x1b[J";
+        let footer = "\x1b[2m[2 left] //a, //b\x1b[0m\n";
+        assert!(
+            printed.contains(&format!("{clear}one\n{footer}")),
+            "{printed:?}"
+        );
+        assert!(
+            printed.contains(&format!("{clear}partial\x1b[32mFinish")),
+            "{printed:?}"
+        );
+    }
+
+    #[test]
+    fn verbose_prints_commands() {
+        use crate::{
+            executor::{Execution, Executor},
+            testing::execution,
+        };
+
+        let printed = |verbose: bool| {
+            let file = tempfile::tempfile().unwrap();
+            let reporter = Stderr::new(file.try_clone().unwrap(), verbose);
+            let executor = Executor::new(Arc::new(reporter));
+            let env = [("API_TOKEN".to_string(), "hunter2".to_string())].into();
+            executor
+                .execute(Execution {
+                    env: Some(&env),
+                    ..execution(&"//a".parse().unwrap(), "true --token hunter2")
+                })
+                .unwrap();
+            read(file)
+        };
+
+        let verbose = printed(true);
+        assert!(
+            verbose.contains("Command //a: true --token <redacted>\n"),
+            "{verbose}"
         );
+        assert!(!verbose.contains("hunter2"), "{verbose}");
+        assert!(!printed(false).contains("Command"));
     }
 }
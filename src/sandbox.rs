@@ -0,0 +1,104 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::{cache, digest, target::TargetPath};
+
+/// A scratch copy of the workspace layout under `.ffs/sandbox` holding only what a build
+/// declared, so commands reading anything else fail. Removed when dropped.
+pub struct Sandbox {
+    workspace: PathBuf,
+    dir: PathBuf,
+}
+
+impl Sandbox {
+    /// An empty sandbox for `target`, replacing any left behind by an earlier run.
+    pub fn new(workspace: &Path, target: &TargetPath) -> eyre::Result<Self> {
+        let id = digest::sha256(target.to_string());
+        let dir = workspace.join(".ffs/sandbox").join(&id[..16]);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Sandbox {
+            workspace: workspace.to_path_buf(),
+            dir,
+        })
+    }
+
+    /// Where `path`, a location in the workspace, is in the sandbox.
+    pub fn path(&self, path: &Path) -> eyre::Result<PathBuf> {
+        let relative = path
+            .strip_prefix(&self.workspace)
+            .map_err(|_| eyre::eyre!("{} is outside the workspace", path.display()))?;
+
+        let mut result = self.dir.clone();
+        for component in relative.components() {
+            match component {
+                Component::Normal(c) => result.push(c),
+                Component::CurDir => {}
+                Component::ParentDir if result != self.dir => {
+                    result.pop();
+                }
+                _ => eyre::bail!("{} is outside the workspace", path.display()),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Makes `file` in the workspace visible at the same place in the sandbox.
+    pub fn link(&self, file: &Path) -> eyre::Result<()> {
+        eyre::ensure!(file.exists(), "Missing src {}", file.display());
+        let linked = self.path(file)?;
+        if linked.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = linked.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(file, &linked)?;
+        #[cfg(not(unix))]
+        cache::copy(file, &linked)?;
+
+        Ok(())
+    }
+
+    /// Copies `file` in the workspace to the same place in the sandbox, so writing to it there
+    /// leaves the workspace alone.
+    pub fn copy(&self, file: &Path) -> eyre::Result<()> {
+        eyre::ensure!(file.exists(), "Missing reference {}", file.display());
+        let copied = self.path(file)?;
+        if copied.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = copied.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        cache::copy(file, &copied)
+    }
+
+    /// Copies `file` out of the sandbox to its place in the workspace, if the sandboxed command
+    /// produced it. Directories replace whatever is already there.
+    pub fn extract(&self, file: &Path) -> eyre::Result<()> {
+        let produced = self.path(file)?;
+        match std::fs::symlink_metadata(&produced) {
+            Ok(meta) if !meta.file_type().is_symlink() => {}
+            _ => return Ok(()),
+        }
+
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if produced.is_dir() && file.exists() {
+            std::fs::remove_dir_all(file)?;
+        }
+        cache::copy(&produced, file)
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
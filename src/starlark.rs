@@ -1,193 +1,1266 @@
 use std::{
     cell::RefCell,
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
 };
 
 use anyhow::Context as _;
 use dashmap::DashMap;
+use either::Either;
 use starlark::{
     any::ProvidesStaticType,
-    environment::{FrozenModule, GlobalsBuilder, Module},
+    docs::{DocMember, DocParam},
+    environment::{FrozenModule, GlobalsBuilder, LibraryExtension, Module},
     eval::Evaluator,
     syntax::{AstModule, Dialect},
-    values::{list::UnpackList, none::NoneType},
+    values::{list::UnpackList, none::NoneType, Value},
+    PrintHandler,
 };
 
-use crate::target::{Build, Common, TargetDef, TargetSet, Task};
+use crate::{
+    command::Command,
+    digest,
+    glob::Patterns,
+    os::Os,
+    reporting::Reporter,
+    target::{
+        ident, Alias, Build, Common, Foreach, Group, HttpFile, Out, Output, RelativeTarget, Retry,
+        Selector, TargetDef, TargetInfo, TargetPath, TargetSet, Task,
+    },
+};
+
+/// Either a bare path, or a `(path, "sha256:<hex>")` pair.
+type OutValue = Either<String, (String, String)>;
+
+/// Either a single command, or a list run in order.
+type CmdValue = Either<String, UnpackList<String>>;
+
+/// Environment variables read with `env()`, and the values they had, if set.
+type Env = BTreeMap<String, Option<String>>;
+
+/// Looks up an environment variable for `env()`.
+type EnvLookup = dyn Fn(&str) -> Option<String> + Send + Sync;
 
 pub struct Reader {
     root: PathBuf,
-    cache: DashMap<PathBuf, Arc<TargetSet>>,
+    cache: DashMap<PathBuf, Cached>,
+    /// Libraries shared by every FFS file that `load()`s them.
+    modules: DashMap<PathBuf, CachedModule>,
+    /// Libraries currently being evaluated, outermost first.
+    loading: Mutex<Vec<PathBuf>>,
+    loads: AtomicUsize,
+    /// Receives `print()` output from FFS files, which otherwise goes to stderr.
+    reporter: Option<Arc<dyn Reporter>>,
+    /// Where `env()` reads variables from, the process environment unless overridden.
+    env: Box<EnvLookup>,
+}
+
+struct Cached {
+    modified: Option<SystemTime>,
+    set: Arc<TargetSet>,
+    env: Env,
+}
+
+struct CachedModule {
+    modified: Option<SystemTime>,
+    module: FrozenModule,
+    env: Env,
 }
 
 impl Reader {
     pub fn new(root: impl Into<PathBuf>) -> Self {
         let root = root.into();
         Self {
-            root: root.into(),
+            root,
             cache: Default::default(),
+            modules: Default::default(),
+            loading: Default::default(),
+            loads: AtomicUsize::new(0),
+            reporter: None,
+            env: Box::new(|name| std::env::var(name).ok()),
         }
     }
 
+    pub fn reporter(mut self, reporter: Arc<dyn Reporter>) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    /// Reads variables for `env()` from `lookup` instead of the process environment.
+    #[allow(unused)]
+    pub fn env(mut self, lookup: impl Fn(&str) -> Option<String> + Send + Sync + 'static) -> Self {
+        self.env = Box::new(lookup);
+        self
+    }
+
     pub fn read(&self, path: impl AsRef<Path>) -> eyre::Result<Arc<TargetSet>> {
+        self.cache
+            .remove_if(path.as_ref(), |_, cached| !self.env_unchanged(&cached.env));
         let v = match self.cache.entry(path.as_ref().to_path_buf()) {
-            dashmap::Entry::Occupied(o) => return Ok(Arc::clone(o.get())),
+            dashmap::Entry::Occupied(o) => return Ok(Arc::clone(&o.get().set)),
             dashmap::Entry::Vacant(v) => v,
         };
 
-        let tasks: TargetSet = self.load(path.as_ref())?;
-        let f = v.insert(Arc::new(tasks));
-        Ok(Arc::clone(&f))
+        let modified = modified(path.as_ref());
+        let (tasks, env) = self.load(path.as_ref())?;
+        self.loads.fetch_add(1, Ordering::Relaxed);
+
+        let f = v.insert(Cached {
+            modified,
+            set: Arc::new(tasks),
+            env,
+        });
+        Ok(Arc::clone(&f.set))
+    }
+
+    /// Describes a single target, reading only the FFS file defining it.
+    pub fn describe(&self, target: &TargetPath) -> eyre::Result<Option<TargetInfo>> {
+        let set = self.read(self.root.join(target.definition()))?;
+        Ok(set.targets.get(target.name()).map(TargetInfo::from))
     }
 
-    fn load(&self, path: impl AsRef<Path>) -> eyre::Result<TargetSet> {
+    /// Forgets any files that have changed on disk since they were read.
+    pub fn invalidate_stale(&self) {
+        let modules = self.modules.len();
+        self.modules
+            .retain(|path, cached| cached.modified.is_some() && modified(path) == cached.modified);
+        if self.modules.len() != modules {
+            // Any FFS file may have loaded a library that changed.
+            self.cache.clear();
+            return;
+        }
+        self.cache
+            .retain(|path, cached| cached.modified.is_some() && modified(path) == cached.modified);
+    }
+
+    /// Whether every variable in `env` still has the value it was read with.
+    fn env_unchanged(&self, env: &Env) -> bool {
+        env.iter().all(|(name, value)| (self.env)(name) == *value)
+    }
+
+    /// How many files have been parsed, excluding cache hits.
+    #[allow(unused)]
+    pub fn loads(&self) -> usize {
+        self.loads.load(Ordering::Relaxed)
+    }
+
+    fn load(&self, path: impl AsRef<Path>) -> eyre::Result<(TargetSet, Env)> {
         let path = path.as_ref();
-        let contents = std::fs::read_to_string(path)?;
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| eyre::eyre!(e).wrap_err(format!("Reading {}", path.display())))?;
 
-        let (_, result) = self
-            .exec_starlark(&path.display().to_string(), contents)
+        let (_, result, env) = self
+            .exec_starlark(&path.display().to_string(), contents, false)
             .map_err(|e| eyre::eyre!(e))?;
 
-        Ok(result)
+        Ok((result, env))
     }
 
-    fn exec_starlark<'s>(
-        &'s self,
+    /// Evaluates a file. Libraries can only define symbols for `load()`, not targets. Also returns
+    /// the environment variables it read, including those read by libraries it loaded.
+    fn exec_starlark(
+        &self,
         path: &str,
         contents: String,
-    ) -> anyhow::Result<(Module, TargetSet)> {
-        let ast =
-            AstModule::parse(path, contents, &Dialect::Standard).map_err(|e| e.into_anyhow())?;
+        library: bool,
+    ) -> anyhow::Result<(Module, TargetSet, Env)> {
+        let ast = AstModule::parse(path, contents, &Dialect::Standard).map_err(located)?;
+        let loads = ast
+            .loads()
+            .iter()
+            .filter_map(|l| l.module_id.strip_prefix("//"))
+            .map(|relative| self.root.join(relative))
+            .collect::<Vec<_>>();
 
         // TODO(shelbyd): Do all invocations of this have the task_definer?
-        let globals = GlobalsBuilder::standard().with(task_definer).build();
+        let globals = GlobalsBuilder::extended_by(&[LibraryExtension::Print])
+            .with(task_definer)
+            .build();
         let module = Module::new();
 
+        let file = match path.strip_prefix("//") {
+            Some(from_root) => PathBuf::from(from_root),
+            None => Path::new(path)
+                .strip_prefix(&self.root)
+                .unwrap_or(Path::new(path))
+                .to_path_buf(),
+        };
+        let dir = self
+            .root
+            .join(&file)
+            .parent()
+            .expect("file is in root")
+            .to_path_buf();
         let context = Context {
             path,
+            file,
+            dir,
+            library,
             task_out: RefCell::new(TargetSet::default()),
+            env: Default::default(),
+            lookup: &*self.env,
         };
+        let prints = self.reporter.as_deref().map(|reporter| ReportPrints {
+            file: &context.file,
+            reporter,
+        });
         {
             let mut eval = Evaluator::new(&module);
             eval.extra = Some(&context);
             eval.set_loader(self);
+            if let Some(prints) = &prints {
+                eval.set_print_handler(prints);
+            }
 
-            eval.eval_module(ast, &globals)
-                .map_err(|e| e.into_anyhow())?;
+            eval.eval_module(ast, &globals).map_err(located)?;
         }
 
-        Ok((module, context.task_out.into_inner()))
+        let mut env = context.env.into_inner();
+        for loaded in loads {
+            if let Some(cached) = self.modules.get(&loaded) {
+                env.extend(cached.env.clone());
+            }
+        }
+        Ok((module, context.task_out.into_inner(), env))
+    }
+}
+
+/// Leads with `path:line:col: message`, keeping Starlark's traceback and source snippet as the
+/// cause.
+pub(crate) fn located(e: starlark::Error) -> anyhow::Error {
+    let Some(span) = e.span() else {
+        return e.into_anyhow();
+    };
+    let begin = span.resolve_span().begin;
+    let head = format!(
+        "{}:{}:{}: {}",
+        span.filename(),
+        begin.line + 1,
+        begin.column + 1,
+        e.without_diagnostic()
+    );
+    e.into_anyhow().context(head)
+}
+
+/// Forwards `print()` to a [`Reporter`], labelled with the printing file.
+struct ReportPrints<'r> {
+    file: &'r Path,
+    reporter: &'r dyn Reporter,
+}
+
+impl PrintHandler for ReportPrints<'_> {
+    fn println(&self, text: &str) -> anyhow::Result<()> {
+        self.reporter
+            .note(&format!("{}: {text}", self.file.display()));
+        Ok(())
     }
 }
 
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
 impl starlark::eval::FileLoader for Reader {
     fn load(&self, path: &str) -> anyhow::Result<FrozenModule> {
-        let source = if let Some(path) = path.strip_prefix("//") {
-            let path = self.root.join(path);
-            std::fs::read_to_string(&path).context(format!("Reading: {}", path.display()))?
-        } else {
+        anyhow::ensure!(
+            !crate::watch::is_ffs_file(Path::new(path)),
+            "Cannot load FFS file {path}, move shared definitions into a .star file"
+        );
+        let Some(relative) = path.strip_prefix("//") else {
             anyhow::bail!("Unknown load schema: {path:?}");
         };
+        let file = self.root.join(relative);
+        self.modules
+            .remove_if(&file, |_, cached| !self.env_unchanged(&cached.env));
+        if let Some(cached) = self.modules.get(&file) {
+            return Ok(cached.module.clone());
+        }
+
+        {
+            let mut loading = self.loading.lock().unwrap();
+            if let Some(start) = loading.iter().position(|p| p == &file) {
+                let cycle = loading[start..]
+                    .iter()
+                    .chain([&file])
+                    .map(|p| format!("//{}", p.strip_prefix(&self.root).unwrap_or(p).display()))
+                    .collect::<Vec<_>>();
+                anyhow::bail!("Cycle in load(): {}", cycle.join(" -> "));
+            }
+            loading.push(file.clone());
+        }
+
+        let modified = modified(&file);
+        let module = std::fs::read_to_string(&file)
+            .context(format!("Reading: {}", file.display()))
+            .and_then(|source| self.exec_starlark(path, source, true))
+            .and_then(|(module, _, env)| Ok((module.freeze()?, env)));
+        self.loading.lock().unwrap().retain(|p| p != &file);
 
-        let (module, _) = self.exec_starlark(path, source)?;
-        Ok(module.freeze()?)
+        let (module, env) = module?;
+        self.modules.insert(
+            file,
+            CachedModule {
+                modified,
+                module: module.clone(),
+                env,
+            },
+        );
+        Ok(module)
     }
 }
 
 #[derive(ProvidesStaticType)]
 struct Context<'c> {
     path: &'c str,
+    /// The file being evaluated, relative to the root, for resolving relative targets.
+    file: PathBuf,
+    /// Absolute directory of the file being evaluated.
+    dir: PathBuf,
+    /// Whether the file is a library being loaded, which can't define targets itself.
+    library: bool,
     task_out: RefCell<TargetSet>,
+    env: RefCell<Env>,
+    /// The reader's source of environment variables.
+    lookup: &'c EnvLookup,
+}
+
+/// The context of the FFS file a target is being defined in. Functions from libraries can define
+/// targets when an FFS file calls them, but libraries can't define targets at the top level.
+fn defining<'e>(eval: &Evaluator<'_, 'e>) -> anyhow::Result<&'e Context<'e>> {
+    let context = eval.extra.unwrap().downcast_ref::<Context>().unwrap();
+    anyhow::ensure!(
+        !context.library,
+        "Targets can only be defined in FFS files, not in library {}",
+        context.file.display()
+    );
+    Ok(context)
+}
+
+/// Parameter names of the FFS builtin `function`, like `task`, in the order it declares them.
+/// Empty for unknown functions.
+pub fn params(function: &str) -> Vec<String> {
+    let globals = GlobalsBuilder::new().with(task_definer).build();
+    let docs =
+        globals
+            .documentation()
+            .members
+            .into_iter()
+            .find_map(|(name, member)| match member {
+                DocMember::Function(docs) if name == function => Some(docs),
+                _ => None,
+            });
+    let Some(docs) = docs else {
+        return Vec::new();
+    };
+    docs.params
+        .into_iter()
+        .filter_map(|param| match param {
+            DocParam::Arg { name, .. } => Some(name),
+            _ => None,
+        })
+        .collect()
 }
 
 #[starlark::starlark_module]
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 fn task_definer(builder: &mut GlobalsBuilder) {
     // TODO(shelbyd): Return path to task.
     fn task(
         name: String,
-        cmd: String,
+        cmd: CmdValue,
 
         #[starlark(require = named)] prereqs: Option<UnpackList<String>>,
         #[starlark(require = named)] tags: Option<UnpackList<String>>,
-        #[starlark(require = named)] outs: Option<BTreeMap<String, String>>,
+        #[starlark(require = named)] outs: Option<BTreeMap<String, OutValue>>,
+        #[starlark(require = named)] alias_basenames: Option<bool>,
+        #[starlark(require = named)] executable: Option<UnpackList<String>>,
+        #[starlark(require = named)] retries: Option<u32>,
+        #[starlark(require = named)] retry_on: Option<UnpackList<i32>>,
+        #[starlark(require = named)] env: Option<BTreeMap<String, String>>,
+        #[starlark(require = named)] lock: Option<String>,
+        #[starlark(require = named)] timeout: Option<u32>,
+        #[starlark(require = named)] shell: Option<String>,
+        #[starlark(require = named)] always: Option<bool>,
+        #[starlark(require = named)] after: Option<UnpackList<String>>,
+        #[starlark(require = named)] stdin: Option<String>,
+        #[starlark(require = named)] workdir: Option<String>,
+        #[starlark(require = named)] argv: Option<bool>,
+        #[starlark(require = named)] visibility: Option<UnpackList<String>>,
 
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let context = eval.extra.unwrap().downcast_ref::<Context>().unwrap();
+        let context = defining(eval)?;
         let mut set = context.task_out.borrow_mut();
 
-        set.targets.insert(
-            name.to_string(),
-            TargetDef::Task(Task {
-                common: common_from(cmd, prereqs, tags, outs)?,
-            }),
-        );
+        let mut common = common_from(context, Some(cmd), prereqs, tags, outs)?;
+        common.after = after
+            .into_iter()
+            .flatten()
+            .map(|t| target_in(&t, &context.file))
+            .collect::<eyre::Result<_>>()
+            .map_err(|e: eyre::Report| anyhow::anyhow!(e))?;
+        apply_run_options(
+            &mut common,
+            executable,
+            retries,
+            retry_on,
+            env,
+            lock,
+            timeout,
+            shell,
+            always,
+            workdir,
+            argv,
+            alias_basenames,
+        )
+        .map_err(|e| anyhow::anyhow!(e))?;
+        common.visibility = visibility_from(context, visibility)?;
+        common.stdin = stdin
+            .map(|s| output_in(&s, &context.file))
+            .transpose()
+            .map_err(|e: eyre::Report| anyhow::anyhow!(e))?;
+
+        define(
+            &mut set,
+            &context.file,
+            name,
+            TargetDef::Task(Task { common }),
+        )?;
 
         Ok(NoneType)
     }
 
     fn build(
         name: String,
-        cmd: String,
-        srcs: UnpackList<String>,
-        outs: BTreeMap<String, String>,
-        runs_on: Option<String>,
+        cmd: CmdValue,
+        srcs: Either<UnpackList<String>, BTreeMap<String, String>>,
+        outs: BTreeMap<String, OutValue>,
+        runs_on: Option<Either<String, UnpackList<String>>>,
 
         #[starlark(require = named)] prereqs: Option<UnpackList<String>>,
         #[starlark(require = named)] tags: Option<UnpackList<String>>,
+        #[starlark(require = named)] alias_basenames: Option<bool>,
+        #[starlark(require = named)] executable: Option<UnpackList<String>>,
+        #[starlark(require = named)] retries: Option<u32>,
+        #[starlark(require = named)] retry_on: Option<UnpackList<i32>>,
+        #[starlark(require = named)] env: Option<BTreeMap<String, String>>,
+        #[starlark(require = named)] lock: Option<String>,
+        #[starlark(require = named)] timeout: Option<u32>,
+        #[starlark(require = named)] shell: Option<String>,
+        #[starlark(require = named)] arch: Option<String>,
+        #[starlark(require = named)] always: Option<bool>,
+        #[starlark(require = named)] workdir: Option<String>,
+        #[starlark(require = named)] argv: Option<bool>,
+        #[starlark(require = named)] visibility: Option<UnpackList<String>>,
 
         eval: &mut Evaluator,
     ) -> starlark::Result<NoneType> {
-        let context = eval.extra.unwrap().downcast_ref::<Context>().unwrap();
+        let context = defining(eval)?;
         let mut set = context.task_out.borrow_mut();
 
-        set.targets.insert(
-            name.to_string(),
+        let mut common = common_from(context, Some(cmd), prereqs, tags, Some(outs))?;
+        apply_run_options(
+            &mut common,
+            executable,
+            retries,
+            retry_on,
+            env,
+            lock,
+            timeout,
+            shell,
+            always,
+            workdir,
+            argv,
+            alias_basenames,
+        )
+        .map_err(|e| anyhow::anyhow!(e))?;
+        common.visibility = visibility_from(context, visibility)?;
+
+        let (srcs, named_srcs) = match srcs {
+            Either::Left(srcs) => (srcs.into_iter().collect(), BTreeMap::new()),
+            Either::Right(named) => (named.values().cloned().collect(), named),
+        };
+
+        define(
+            &mut set,
+            &context.file,
+            name,
             TargetDef::Build(Build {
-                common: common_from(cmd, prereqs, tags, Some(outs))?,
-                srcs: srcs.into_iter().collect(),
-                runs_on: runs_on
+                common,
+                srcs,
+                named_srcs,
+                runs_on: match runs_on {
+                    None => HashSet::new(),
+                    Some(Either::Left(os)) => HashSet::from([os]),
+                    Some(Either::Right(oses)) => oses.into_iter().collect(),
+                }
+                .iter()
+                .map(|s| s.parse())
+                .collect::<eyre::Result<_>>()
+                .map_err(|e: eyre::Report| anyhow::anyhow!(e))?,
+                arch: arch
                     .map(|s| s.parse())
                     .transpose()
                     .map_err(|e: eyre::Report| anyhow::anyhow!(e))?,
             }),
-        );
+        )?;
 
         Ok(NoneType)
     }
 
+    /// Downloads `url` to `out`, which is verified against `sha256` and cached across runs.
+    fn http_file(
+        name: String,
+        url: String,
+        sha256: String,
+        out: String,
+
+        #[starlark(require = named)] tags: Option<UnpackList<String>>,
+        #[starlark(require = named)] visibility: Option<UnpackList<String>>,
+
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        let context = defining(eval)?;
+        let mut set = context.task_out.borrow_mut();
+
+        let outs = BTreeMap::from([("default".to_string(), Either::Right((out, sha256.clone())))]);
+        let mut common = common_from(context, None, None, tags, Some(outs))?;
+        common.visibility = visibility_from(context, visibility)?;
+
+        define(
+            &mut set,
+            &context.file,
+            name,
+            TargetDef::HttpFile(HttpFile {
+                url,
+                sha256: digest::parse_sha256(&sha256).map_err(|e| anyhow::anyhow!(e))?,
+                common,
+            }),
+        )?;
+
+        Ok(NoneType)
+    }
+
+    /// Builds every target in `targets`, exposing their outputs as `<member>-<output>`.
+    fn group(
+        name: String,
+        targets: UnpackList<String>,
+
+        #[starlark(require = named)] tags: Option<UnpackList<String>>,
+
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        let context = defining(eval)?;
+        let mut set = context.task_out.borrow_mut();
+
+        define(
+            &mut set,
+            &context.file,
+            name,
+            TargetDef::Group(Group {
+                common: common_from(context, None, Some(targets), tags, None)?,
+            }),
+        )?;
+
+        Ok(NoneType)
+    }
+
+    /// Another name for `actual`, with the same outputs.
+    fn alias(name: String, actual: String, eval: &mut Evaluator) -> starlark::Result<NoneType> {
+        let context = defining(eval)?;
+        let mut set = context.task_out.borrow_mut();
+
+        let actual = target_in(&actual, &context.file).map_err(|e| anyhow::anyhow!(e))?;
+        let mut common = common_from(context, None, None, None, None)?;
+        common.prereqs.insert(actual.clone());
+
+        define(
+            &mut set,
+            &context.file,
+            name,
+            TargetDef::Alias(Alias { actual, common }),
+        )?;
+
+        Ok(NoneType)
+    }
+
+    /// Marks `name` as what `ffs run` builds when given no selector in this package.
+    fn default(name: String, eval: &mut Evaluator) -> starlark::Result<NoneType> {
+        let context = defining(eval)?;
+        let mut set = context.task_out.borrow_mut();
+
+        if let Some(existing) = &set.default {
+            return Err(anyhow::anyhow!(
+                "{} declares more than one default: {existing:?} and {name:?}",
+                context.file.display()
+            )
+            .into());
+        }
+        set.default = Some(name);
+
+        Ok(NoneType)
+    }
+
+    /// Runs `cmd` once per item in parallel, substituting `{item}`.
+    fn foreach(
+        name: String,
+        items: UnpackList<String>,
+        cmd: CmdValue,
+
+        #[starlark(require = named)] prereqs: Option<UnpackList<String>>,
+        #[starlark(require = named)] tags: Option<UnpackList<String>>,
+
+        eval: &mut Evaluator,
+    ) -> starlark::Result<NoneType> {
+        let context = defining(eval)?;
+        let mut set = context.task_out.borrow_mut();
+
+        define(
+            &mut set,
+            &context.file,
+            name,
+            TargetDef::Foreach(Foreach {
+                items: items.into_iter().collect(),
+                common: common_from(context, Some(cmd), prereqs, tags, None)?,
+            }),
+        )?;
+
+        Ok(NoneType)
+    }
+
+    /// Files under the defining file's directory matching any of `include` and none of `exclude`,
+    /// relative to that directory.
+    fn glob(
+        include: UnpackList<String>,
+        exclude: Option<UnpackList<String>>,
+
+        #[starlark(require = named)] respect_gitignore: Option<bool>,
+        #[starlark(require = named)] allow_empty: Option<bool>,
+
+        eval: &mut Evaluator,
+    ) -> anyhow::Result<Vec<String>> {
+        let context = eval.extra.unwrap().downcast_ref::<Context>().unwrap();
+        let patterns = Patterns {
+            include: include.into_iter().collect(),
+            exclude: exclude.into_iter().flatten().collect(),
+            respect_gitignore: respect_gitignore.unwrap_or(true),
+            allow_empty: allow_empty.unwrap_or(true),
+        };
+        patterns
+            .expand(&context.dir)
+            .map_err(|e| anyhow::anyhow!(e))
+    }
+
     fn local_file(source: String, file: String) -> anyhow::Result<String> {
         let source_dir = source.rsplit_once("/").unwrap().0;
         Ok(format!("{source_dir}/{file}"))
     }
 
+    /// The host running ffs as `(os, arch)`, e.g. `("linux", "x86_64")`, named as `runs_on` and
+    /// `arch` accept them.
+    fn platform() -> anyhow::Result<(String, String)> {
+        Ok((
+            crate::os::host().to_string(),
+            crate::arch::host().to_string(),
+        ))
+    }
+
+    /// The value in `branches` keyed by the host OS, or else by `"default"`.
+    fn select<'v>(branches: BTreeMap<String, Value<'v>>) -> anyhow::Result<Value<'v>> {
+        select_for(crate::os::host(), branches).map_err(|e| anyhow::anyhow!(e))
+    }
+
+    /// The environment variable `name`, or `default` when it's unset. Files are re-read when a
+    /// variable they read changes.
+    fn env(name: String, default: Option<String>, eval: &mut Evaluator) -> anyhow::Result<String> {
+        let context = eval.extra.unwrap().downcast_ref::<Context>().unwrap();
+        let value = (context.lookup)(&name);
+        context.env.borrow_mut().insert(name.clone(), value.clone());
+        value.or(default).ok_or_else(|| {
+            anyhow::anyhow!("Environment variable {name} is not set and has no default")
+        })
+    }
+
     fn get_source(eval: &mut Evaluator) -> anyhow::Result<String> {
         let context = eval.extra.unwrap().downcast_ref::<Context>().unwrap();
         Ok(context.path.to_string())
     }
 }
 
+/// Adds `def` to the targets of `file`, which can't already have one named `name`. Names must be
+/// idents, so the target can be referenced as `//dir/name`.
+fn define(set: &mut TargetSet, file: &Path, name: String, def: TargetDef) -> anyhow::Result<()> {
+    match ident(&name) {
+        Ok("") => anyhow::bail!("{} defines a target with an empty name", file.display()),
+        Ok(_) => {}
+        Err(e) => anyhow::bail!(
+            "{} defines a target with invalid name {name:?}: {e}",
+            file.display()
+        ),
+    }
+    anyhow::ensure!(
+        !set.targets.contains_key(&name),
+        "{} defines more than one target named {name:?}",
+        file.display()
+    );
+    set.targets.insert(name, def);
+    Ok(())
+}
+
+fn select_for<'v>(host: Os, branches: BTreeMap<String, Value<'v>>) -> eyre::Result<Value<'v>> {
+    let mut default = None;
+    let mut matched = None;
+    for (key, value) in branches {
+        if key == "default" {
+            default = Some(value);
+            continue;
+        }
+        if key.parse::<Os>()? != host {
+            continue;
+        }
+        eyre::ensure!(
+            matched.is_none(),
+            "select() has more than one branch for {host}"
+        );
+        matched = Some(value);
+    }
+
+    matched
+        .or(default)
+        .ok_or_else(|| eyre::eyre!("select() has no branch for {host} and no default"))
+}
+
 fn common_from(
-    cmd: String,
+    context: &Context,
+    cmd: Option<CmdValue>,
     prereqs: Option<UnpackList<String>>,
     tags: Option<UnpackList<String>>,
-    outs: Option<BTreeMap<String, String>>,
+    outs: Option<BTreeMap<String, OutValue>>,
 ) -> starlark::Result<Common> {
     Ok(Common {
-        cmd: cmd.parse().map_err(|e: eyre::Report| anyhow::anyhow!(e))?,
+        cmd: commands_from(context, cmd).map_err(|e| anyhow::anyhow!(e))?,
         prereqs: prereqs
             .into_iter()
             .flatten()
-            .map(|p| p.parse())
+            .map(|p| target_in(&p, &context.file))
             .collect::<eyre::Result<_>>()
             .map_err(|e: eyre::Report| anyhow::anyhow!(e))?,
         tags: tags.into_iter().flatten().collect(),
         outs: outs
             .into_iter()
             .flatten()
-            .map(|(k, v)| (k, PathBuf::from(v)))
-            .collect(),
+            .map(|(name, value)| Ok((name, out_from(value)?)))
+            .collect::<eyre::Result<_>>()
+            .map_err(|e: eyre::Report| anyhow::anyhow!(e))?,
+        retry: Retry::default(),
+        env: Default::default(),
+        lock: None,
+        timeout: None,
+        shell: None,
+        always: false,
+        after: Default::default(),
+        stdin: None,
+        workdir: None,
+        argv: false,
+        visibility: None,
     })
 }
+
+/// Applies the options `task` and `build` share on how their commands run.
+#[allow(clippy::too_many_arguments)]
+fn apply_run_options(
+    common: &mut Common,
+    executable: Option<UnpackList<String>>,
+    retries: Option<u32>,
+    retry_on: Option<UnpackList<i32>>,
+    env: Option<BTreeMap<String, String>>,
+    lock: Option<String>,
+    timeout: Option<u32>,
+    shell: Option<String>,
+    always: Option<bool>,
+    workdir: Option<String>,
+    argv: Option<bool>,
+    alias_basenames: Option<bool>,
+) -> eyre::Result<()> {
+    common.mark_executable(executable.into_iter().flatten())?;
+    common.retry = Retry {
+        retries: retries.unwrap_or(0),
+        on: retry_on.map(|codes| codes.into_iter().collect()),
+    };
+    common.env = env.into_iter().flatten().collect();
+    common.lock = lock;
+    common.timeout = timeout.map(|secs| Duration::from_secs(secs.into()));
+    common.shell = shell.map(|s| parse_shell(&s)).transpose()?;
+    common.always = always.unwrap_or(false);
+    common.workdir = workdir;
+    common.argv = argv.unwrap_or(false);
+    eyre::ensure!(
+        !(common.argv && common.shell.is_some()),
+        "argv = True runs cmd without a shell, so shell can't be set"
+    );
+    if alias_basenames.unwrap_or(false) {
+        common.alias_basenames()?;
+    }
+    Ok(())
+}
+
+/// Selectors for the targets allowed to depend on one, with `%/...` relative to the defining
+/// file.
+fn visibility_from(
+    context: &Context,
+    visibility: Option<UnpackList<String>>,
+) -> starlark::Result<Option<Vec<Selector>>> {
+    let Some(visibility) = visibility else {
+        return Ok(None);
+    };
+    let selectors = visibility
+        .into_iter()
+        .map(|s| s.parse::<Selector>()?.relative_to(&context.file))
+        .collect::<eyre::Result<_>>()
+        .map_err(|e: eyre::Report| anyhow::anyhow!(e))?;
+    Ok(Some(selectors))
+}
+
+fn commands_from(context: &Context, cmd: Option<CmdValue>) -> eyre::Result<Vec<Command>> {
+    let cmd = match cmd {
+        None => return Ok(Vec::new()),
+        Some(Either::Left(cmd)) => vec![cmd],
+        Some(Either::Right(list)) => list.into_iter().collect(),
+    };
+    eyre::ensure!(!cmd.is_empty(), "cmd must not be empty");
+
+    cmd.iter()
+        .map(|c| Command::parse_in(c, &context.file))
+        .collect()
+}
+
+/// Splits a `shell` like `"python3 -c"` into its program and arguments. A bare program gets `-c`,
+/// like `sh`.
+fn parse_shell(shell: &str) -> eyre::Result<Vec<String>> {
+    let mut words = shell
+        .split_whitespace()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    eyre::ensure!(!words.is_empty(), "shell must not be empty");
+    if words.len() == 1 {
+        words.push("-c".to_string());
+    }
+    Ok(words)
+}
+
+/// Parses an absolute target, or a relative one like `%/name` as written in `file`.
+fn target_in(s: &str, file: &Path) -> eyre::Result<TargetPath> {
+    if s.starts_with('%') {
+        s.parse::<RelativeTarget>()?.resolve(file)
+    } else {
+        s.parse()
+    }
+}
+
+/// Parses an output like `//target:out`, or a relative one like `%/target:out` as written in
+/// `file`. Without a name, it's the target's `default` output.
+fn output_in(s: &str, file: &Path) -> eyre::Result<Output> {
+    let (target, name) = s.split_once(':').unwrap_or((s, "default"));
+    Ok(target_in(target, file)?.output(ident(name)?))
+}
+
+/// A trailing `/` declares a directory output.
+fn out_from(value: OutValue) -> eyre::Result<Out> {
+    let (path, sha256) = match value {
+        Either::Left(path) => (path, None),
+        Either::Right((path, checksum)) => (path, Some(digest::parse_sha256(&checksum)?)),
+    };
+    let (path, dir) = match path.strip_suffix('/') {
+        Some(dir) => (dir.to_string(), true),
+        None => (path, false),
+    };
+    eyre::ensure!(
+        !(dir && sha256.is_some()),
+        "Output directory {path} can't have a checksum"
+    );
+
+    Ok(Out {
+        path: PathBuf::from(path),
+        sha256,
+        executable: false,
+        dir,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::workspace;
+
+    #[test]
+    fn describe_target() {
+        let dir = workspace([(
+            "lib/FFS",
+            r#"
+task("dep", "true")
+task("t", "cat //lib/dep:out > res", prereqs = ["//lib/dep"], tags = ["b", "a"], outs = {"res": "res"})
+"#,
+        )]);
+        let reader = Reader::new(dir.path());
+
+        let info = reader
+            .describe(&"//lib/t".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            info,
+            TargetInfo {
+                kind: "task",
+                command: "cat //lib/dep:out > res".to_string(),
+                prereqs: vec!["//lib/dep".parse().unwrap()],
+                tags: vec!["a".to_string(), "b".to_string()],
+                outs: BTreeMap::from([("res".to_string(), PathBuf::from("res"))]),
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&info).unwrap()["prereqs"],
+            serde_json::json!(["//lib/dep"])
+        );
+
+        assert_eq!(
+            reader.describe(&"//lib/missing".parse().unwrap()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn relative_targets_resolve_against_defining_file() {
+        let dir = workspace([(
+            "lib/FFS",
+            r#"
+task("t", "cat %/dep:out %sub/gen:src:main", prereqs = ["%/setup"])
+"#,
+        )]);
+        let reader = Reader::new(dir.path());
+
+        let info = reader
+            .describe(&"//lib/t".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.command, "cat //lib/dep:out //lib/sub/gen:src:main");
+        assert_eq!(info.prereqs, vec!["//lib/setup".parse().unwrap()]);
+    }
+
+    #[test]
+    fn relative_stdin_resolves_against_defining_file() {
+        let dir = workspace([("lib/FFS", r#"task("t", "cat", stdin = "%/gen:out")"#)]);
+        let reader = Reader::new(dir.path());
+
+        let set = reader.read(dir.path().join("lib/FFS")).unwrap();
+        assert_eq!(
+            set.targets["t"].stdin,
+            Some("//lib/gen:out".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn params_follow_declaration_order() {
+        let build = params("build");
+        assert_eq!(build[..5], ["name", "cmd", "srcs", "outs", "runs_on"]);
+        assert_eq!(build.last().map(String::as_str), Some("visibility"));
+
+        let task = params("task");
+        assert_eq!(task[..3], ["name", "cmd", "prereqs"]);
+        assert!(params("unknown").is_empty());
+    }
+
+    #[test]
+    fn glob_srcs_are_relative_to_defining_file() {
+        let dir = workspace([
+            (
+                "pkg/FFS",
+                r#"build("b", "true", glob(["**/*.rs"], ["gen/**"]), {"out": "out"})"#,
+            ),
+            ("pkg/main.rs", ""),
+            ("pkg/src/lib.rs", ""),
+            ("pkg/gen/out.rs", ""),
+        ]);
+        let reader = Reader::new(dir.path());
+
+        let set = reader.read(dir.path().join("pkg/FFS")).unwrap();
+        let build = set.targets["b"].as_build().unwrap();
+        assert_eq!(build.srcs, ["main.rs", "src/lib.rs"]);
+    }
+
+    #[test]
+    fn runs_on_accepts_one_or_many() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+build("any", "true", [], {})
+build("one", "true", [], {}, runs_on = "linux")
+build("listed", "true", [], {}, runs_on = ["linux"])
+build("unix", "true", [], {}, runs_on = ["linux", "macos"])
+"#,
+        )]);
+        let reader = Reader::new(dir.path());
+
+        let set = reader.read(dir.path().join("FFS")).unwrap();
+        let runs_on = |name: &str| set.targets[name].as_build().unwrap().runs_on.clone();
+        assert_eq!(runs_on("any"), HashSet::new());
+        assert_eq!(runs_on("one"), HashSet::from([Os::Linux]));
+        assert_eq!(runs_on("listed"), HashSet::from([Os::Linux]));
+        assert_eq!(runs_on("unix"), HashSet::from([Os::Linux, Os::Mac]));
+    }
+
+    #[test]
+    fn platform_picks_host_command() {
+        let dir = workspace([(
+            "FFS",
+            r#"
+os, arch = platform()
+task("t", "ver" if os == "windows" else "uname " + arch)
+"#,
+        )]);
+        let reader = Reader::new(dir.path());
+
+        let info = reader.describe(&"//t".parse().unwrap()).unwrap().unwrap();
+        let expected = match crate::os::host() {
+            Os::Windows => "ver".to_string(),
+            _ => format!("uname {}", crate::arch::host()),
+        };
+        assert_eq!(info.command, expected);
+    }
+
+    fn select_command(branches: &str) -> eyre::Result<String> {
+        let ffs = format!("task(\"t\", select({{{branches}}}))");
+        let dir = workspace([("FFS", ffs.as_str())]);
+        let reader = Reader::new(dir.path());
+        Ok(reader.describe(&"//t".parse().unwrap())?.unwrap().command)
+    }
+
+    /// An OS other than the host.
+    fn elsewhere() -> Os {
+        match crate::os::host() {
+            Os::Linux => Os::Windows,
+            _ => Os::Linux,
+        }
+    }
+
+    #[test]
+    fn select_matches_host() {
+        let host = crate::os::host();
+        let branches = format!(r#""{host}": "here", "{}": "there""#, elsewhere());
+        assert_eq!(select_command(&branches).unwrap(), "here");
+    }
+
+    #[test]
+    fn select_falls_back_to_default() {
+        let branches = format!(r#""{}": "there", "default": "fallback""#, elsewhere());
+        assert_eq!(select_command(&branches).unwrap(), "fallback");
+    }
+
+    #[test]
+    fn select_without_match_or_default() {
+        let e = select_command(&format!(r#""{}": "there""#, elsewhere())).unwrap_err();
+        assert!(
+            format!("{e:?}").contains("select() has no branch for"),
+            "{e:?}"
+        );
+    }
+
+    /// Describes a task echoing `call`, with `vars` as the environment. Checks the file is
+    /// re-read once `var` changes.
+    fn env_command(vars: &[(&str, &str)], var: &str, call: &str) -> eyre::Result<String> {
+        let ffs = format!("task(\"t\", \"echo \" + {call})");
+        let dir = workspace([("FFS", ffs.as_str())]);
+        let vars = Arc::new(Mutex::new(
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+        ));
+        let lookup = Arc::clone(&vars);
+        let reader =
+            Reader::new(dir.path()).env(move |name| lookup.lock().unwrap().get(name).cloned());
+
+        let first = reader.describe(&"//t".parse().unwrap())?.unwrap().command;
+        vars.lock()
+            .unwrap()
+            .insert(var.to_string(), "changed".to_string());
+        let second = reader.describe(&"//t".parse().unwrap())?.unwrap().command;
+        assert_eq!(second, "echo changed", "cached across changed env");
+        Ok(first)
+    }
+
+    #[test]
+    fn env_reads_present_variable() {
+        assert_eq!(
+            env_command(&[("VERSION", "1.2.3")], "VERSION", r#"env("VERSION")"#).unwrap(),
+            "echo 1.2.3"
+        );
+    }
+
+    #[test]
+    fn env_falls_back_to_default() {
+        assert_eq!(
+            env_command(&[], "STAGE", r#"env("STAGE", "dev")"#).unwrap(),
+            "echo dev"
+        );
+    }
+
+    #[test]
+    fn env_without_default_is_an_error() {
+        let e = env_command(&[], "STAGE", r#"env("STAGE")"#).unwrap_err();
+        assert!(
+            format!("{e:?}").contains("STAGE is not set and has no default"),
+            "{e:?}"
+        );
+    }
+
+    #[test]
+    fn argv_rejects_shell() {
+        for def in [
+            r#"task("t", "true", argv = True, shell = "bash")"#,
+            r#"build("b", "true", [], {}, argv = True, shell = "bash")"#,
+        ] {
+            let dir = workspace([("FFS", def)]);
+            let reader = Reader::new(dir.path());
+
+            let e = reader.read(dir.path().join("FFS")).unwrap_err();
+            assert!(
+                format!("{e:?}").contains("argv = True runs cmd without a shell"),
+                "{e:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_target_names_are_an_error() {
+        let ffs = "task(\"same\", \"true\")\ntask(\"same\", \"false\")\n";
+        let dir = workspace([("lib/FFS", ffs)]);
+        let reader = Reader::new(dir.path());
+
+        let e = reader.read(dir.path().join("lib/FFS")).unwrap_err();
+        assert!(
+            format!("{e:?}").contains("lib/FFS defines more than one target named \"same\""),
+            "{e:?}"
+        );
+    }
+
+    #[test]
+    fn target_names_must_be_idents() {
+        let dir = workspace([
+            ("ok/FFS", "task(\"build-all_2\", \"true\")\n"),
+            ("bad/FFS", "task(\"a/b\", \"true\")\n"),
+        ]);
+        let reader = Reader::new(dir.path());
+
+        let set = reader.read(dir.path().join("ok/FFS")).unwrap();
+        assert!(set.targets.contains_key("build-all_2"));
+
+        let e = reader.read(dir.path().join("bad/FFS")).unwrap_err();
+        assert!(
+            format!("{e:?}").contains(
+                "bad/FFS defines a target with invalid name \"a/b\": Invalid ident char '/'"
+            ),
+            "{e:?}"
+        );
+    }
+
+    #[test]
+    fn errors_name_file_and_line() {
+        let dir = workspace([("lib/FFS", "task(\"t\", \"true\")\n\nfail(\"boom\")\n")]);
+        let reader = Reader::new(dir.path());
+
+        let e = reader.read(dir.path().join("lib/FFS")).unwrap_err();
+        let path = dir.path().join("lib/FFS");
+        assert_eq!(e.to_string(), format!("{}:3:1: fail: boom", path.display()));
+    }
+
+    #[derive(Default)]
+    struct Notes(std::sync::Mutex<Vec<String>>);
+    impl Reporter for Notes {
+        fn note(&self, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn prints_are_reported() {
+        let dir = workspace([("lib/FFS", "print(\"hello\", 1)\n")]);
+        let notes = Arc::new(Notes::default());
+        let reader = Reader::new(dir.path()).reporter(notes.clone());
+
+        reader.read(dir.path().join("lib/FFS")).unwrap();
+        assert_eq!(*notes.0.lock().unwrap(), ["lib/FFS: hello 1"]);
+    }
+
+    #[test]
+    fn libraries_export_functions() {
+        let dir = workspace([
+            (
+                "helpers.star",
+                "def mk(name):\n    task(name, \"echo \" + name)\n",
+            ),
+            ("lib/FFS", "load(\"//helpers.star\", \"mk\")\nmk(\"t\")\n"),
+        ]);
+        let reader = Reader::new(dir.path());
+
+        let info = reader
+            .describe(&"//lib/t".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.command, "echo t");
+    }
+
+    #[test]
+    fn libraries_cannot_define_targets() {
+        let dir = workspace([
+            ("helpers.star", "task(\"hidden\", \"true\")\n"),
+            ("lib/FFS", "load(\"//helpers.star\", \"task\")\n"),
+            ("other/FFS", "load(\"//lib/FFS\", \"task\")\n"),
+        ]);
+        let reader = Reader::new(dir.path());
+
+        let e = reader.read(dir.path().join("lib/FFS")).unwrap_err();
+        assert!(
+            format!("{e:?}").contains("not in library helpers.star"),
+            "{e:?}"
+        );
+
+        let e = reader.read(dir.path().join("other/FFS")).unwrap_err();
+        assert!(format!("{e:?}").contains("Cannot load FFS file"), "{e:?}");
+    }
+
+    #[test]
+    fn libraries_are_evaluated_once() {
+        let dir = workspace([
+            ("helpers.star", "print(\"loaded\")\nX = 1\n"),
+            ("a/FFS", "load(\"//helpers.star\", \"X\")\n"),
+            ("b/FFS", "load(\"//helpers.star\", \"X\")\n"),
+        ]);
+        let notes = Arc::new(Notes::default());
+        let reader = Reader::new(dir.path()).reporter(notes.clone());
+
+        reader.read(dir.path().join("a/FFS")).unwrap();
+        reader.read(dir.path().join("b/FFS")).unwrap();
+        assert_eq!(*notes.0.lock().unwrap(), ["helpers.star: loaded"]);
+    }
+
+    #[test]
+    fn load_cycles_are_named() {
+        let dir = workspace([
+            ("a.star", "load(\"//b.star\", \"B\")\nA = 1\n"),
+            ("b.star", "load(\"//a.star\", \"A\")\nB = 1\n"),
+            ("lib/FFS", "load(\"//a.star\", \"A\")\n"),
+        ]);
+        let reader = Reader::new(dir.path());
+
+        let e = reader.read(dir.path().join("lib/FFS")).unwrap_err();
+        assert!(
+            format!("{e:?}").contains("Cycle in load(): //a.star -> //b.star -> //a.star"),
+            "{e:?}"
+        );
+    }
+}
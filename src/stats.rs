@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+/// The spread of a set of durations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub min: Duration,
+    pub median: Duration,
+    pub max: Duration,
+}
+
+impl Stats {
+    /// Stats of `samples`, or `None` if there are none. An even number of samples has the mean
+    /// of the middle two as its median.
+    pub fn of(samples: &[Duration]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+
+        let mid = sorted.len() / 2;
+        let median = match sorted.len() {
+            0 => return None,
+            n if n % 2 == 0 => (sorted[mid - 1] + sorted[mid]) / 2,
+            _ => sorted[mid],
+        };
+        Some(Stats {
+            min: sorted[0],
+            median,
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(samples: &[u64]) -> Vec<Duration> {
+        samples.iter().copied().map(Duration::from_millis).collect()
+    }
+
+    #[test]
+    fn odd_and_even_counts() {
+        assert_eq!(
+            Stats::of(&ms(&[300, 100, 200])),
+            Some(Stats {
+                min: Duration::from_millis(100),
+                median: Duration::from_millis(200),
+                max: Duration::from_millis(300),
+            })
+        );
+        assert_eq!(
+            Stats::of(&ms(&[400, 100, 200, 300])).unwrap().median,
+            Duration::from_millis(250)
+        );
+        assert_eq!(Stats::of(&[]), None);
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, SystemTime},
+};
+
+mod output;
+mod relative;
+mod selector;
+mod source;
+#[allow(clippy::module_inception)]
+mod target;
+
+pub use output::*;
+pub use relative::*;
+pub use selector::*;
+pub use source::*;
+pub use target::*;
+
+use serde::Serialize;
+
+use crate::{arch::Arch, command::Command, os::Os};
+
+#[derive(Debug, Default)]
+pub struct TargetSet {
+    pub targets: BTreeMap<String, TargetDef>,
+    /// The target `ffs run` builds when given no selector in this package.
+    pub default: Option<String>,
+}
+
+impl TargetSet {
+    pub fn targets(&self) -> impl Iterator<Item = (&String, &TargetDef)> {
+        self.targets.iter()
+    }
+}
+
+#[derive(Debug)]
+pub struct Task {
+    pub common: Common,
+}
+
+#[derive(Debug)]
+pub struct Build {
+    /// In declaration order, or ordered by name when declared by name.
+    pub srcs: Vec<String>,
+    /// Srcs declared by name, which other targets can reference as `//target:src:name`.
+    pub named_srcs: BTreeMap<String, String>,
+    /// OSes the build can run on, any when empty.
+    pub runs_on: HashSet<Os>,
+    pub arch: Option<Arch>,
+
+    pub common: Common,
+}
+
+impl Build {
+    /// The src the build is mainly about, e.g. the file a compile command compiles: the first
+    /// src when they're listed, or the one named `src` when they're named.
+    pub fn primary_src(&self) -> Option<&str> {
+        match self.named_srcs.is_empty() {
+            true => self.srcs.first(),
+            false => self.named_srcs.get("src"),
+        }
+        .map(String::as_str)
+    }
+
+    /// Whether every output is newer than every src and every file in `inputs`, so the command
+    /// can be skipped. Missing outputs are always stale, and directory srcs count as their newest
+    /// file.
+    pub fn is_up_to_date(&self, root: &Path, dir: &Path, inputs: &[PathBuf]) -> bool {
+        let oldest_out = self
+            .common
+            .outs
+            .values()
+            .map(|out| match out.dir {
+                true => oldest_modified(&out.file(root, dir)),
+                false => modified(&out.file(root, dir)),
+            })
+            .min();
+        let Some(Some(oldest_out)) = oldest_out else {
+            return false;
+        };
+
+        self.srcs
+            .iter()
+            .map(|src| dir.join(src))
+            .chain(inputs.iter().cloned())
+            .all(|input| newest_modified(&input).is_some_and(|t| t < oldest_out))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+/// The oldest file in a directory, or the directory itself when it is empty.
+fn oldest_modified(dir: &Path) -> Option<SystemTime> {
+    let mut oldest = None;
+    for entry in std::fs::read_dir(dir).ok()? {
+        let path = entry.ok()?.path();
+        let modified = match path.is_dir() {
+            true => oldest_modified(&path)?,
+            false => modified(&path)?,
+        };
+        oldest = Some(oldest.map_or(modified, |o: SystemTime| o.min(modified)));
+    }
+    oldest.or_else(|| modified(dir))
+}
+
+fn newest_modified(path: &Path) -> Option<SystemTime> {
+    let meta = std::fs::metadata(path).ok()?;
+    let mut newest = meta.modified().ok()?;
+    if meta.is_dir() {
+        for entry in std::fs::read_dir(path).ok()? {
+            newest = newest.max(newest_modified(&entry.ok()?.path())?);
+        }
+    }
+    Some(newest)
+}
+
+/// Downloads `url` into its `default` output, which must match `sha256`.
+#[derive(Debug)]
+pub struct HttpFile {
+    pub url: String,
+    pub sha256: String,
+
+    pub common: Common,
+}
+
+/// Builds all of its members (`prereqs`), re-exporting their outputs as `<member>-<output>`.
+#[derive(Debug)]
+pub struct Group {
+    pub common: Common,
+}
+
+impl Group {
+    pub fn output_name(member: &TargetPath, output: &str) -> String {
+        format!("{}-{output}", member.name())
+    }
+}
+
+/// Another name for `actual` (its only prereq), exposing its outputs under the same names.
+#[derive(Debug)]
+pub struct Alias {
+    pub actual: TargetPath,
+
+    pub common: Common,
+}
+
+/// Runs its command once per item, with `{item}` replaced by the quoted item.
+#[derive(Debug)]
+pub struct Foreach {
+    pub items: Vec<String>,
+
+    pub common: Common,
+}
+
+#[derive(Debug)]
+pub struct Common {
+    /// Run in order, stopping at the first that fails.
+    pub cmd: Vec<Command>,
+    pub prereqs: BTreeSet<TargetPath>,
+    /// Targets that must finish first when they are being built too. Unlike `prereqs`, they
+    /// aren't built just for this target.
+    pub after: BTreeSet<TargetPath>,
+    pub tags: HashSet<String>,
+    pub outs: HashMap<String, Out>,
+    pub retry: Retry,
+    /// Variables set for the command, on top of the inherited environment.
+    pub env: BTreeMap<String, String>,
+    /// Name of a build-wide mutex held while the command runs.
+    pub lock: Option<String>,
+    /// How long the command may run before it is killed.
+    pub timeout: Option<Duration>,
+    /// Interpreter program and arguments the command is passed to, instead of `sh -e -c`.
+    pub shell: Option<Vec<String>>,
+    /// Run on every build, never skipped as up to date, cached, or already built.
+    pub always: bool,
+    /// Another target's output fed to the command on stdin.
+    pub stdin: Option<Output>,
+    /// Where the command runs instead of the package directory, relative to it or, starting
+    /// with `//`, to the workspace root.
+    pub workdir: Option<String>,
+    /// `cmd` is a program and its arguments, run without a shell.
+    pub argv: bool,
+    /// Targets outside this package that may depend on this one, or anyone when `None`.
+    pub visibility: Option<Vec<Selector>>,
+}
+
+impl Common {
+    /// Also exposes each output under its file name, so `outs = {"bin": "target/app"}` can be
+    /// referenced as both `:bin` and `:app`.
+    pub fn alias_basenames(&mut self) -> eyre::Result<()> {
+        let mut names = self.outs.keys().collect::<Vec<_>>();
+        names.sort();
+
+        let mut aliases = BTreeMap::<String, (&String, &Out)>::new();
+        for name in names {
+            let out = &self.outs[name];
+            let Some(base) = out.path.file_name().and_then(|f| f.to_str()) else {
+                continue;
+            };
+            if base == name || ident(base).is_err() {
+                continue;
+            }
+
+            if let Some(declared) = self.outs.get(base) {
+                eyre::ensure!(
+                    declared.path == out.path,
+                    "Basename of output {name} conflicts with declared output {base}"
+                );
+                continue;
+            }
+            if let Some((other, _)) = aliases.insert(base.to_string(), (name, out)) {
+                eyre::bail!("Outputs {other} and {name} share the basename {base:?}");
+            }
+        }
+
+        let aliases = aliases
+            .into_iter()
+            .map(|(alias, (_, out))| (alias, out.clone()))
+            .collect::<Vec<_>>();
+        self.outs.extend(aliases);
+        Ok(())
+    }
+
+    /// Marks the named outputs as executable, so they can be run as `//target:output`.
+    pub fn mark_executable(&mut self, names: impl IntoIterator<Item = String>) -> eyre::Result<()> {
+        for name in names {
+            let out = self
+                .outs
+                .get_mut(&name)
+                .ok_or_else(|| eyre::eyre!("Cannot mark unknown output {name} executable"))?;
+            eyre::ensure!(!out.dir, "Cannot mark output directory {name} executable");
+            out.executable = true;
+        }
+        Ok(())
+    }
+
+    /// Whether `consumer` may depend on `target`, which has these settings. Targets in the same
+    /// package always can.
+    pub fn visible_to(
+        &self,
+        target: &TargetPath,
+        consumer: &TargetPath,
+        tags: &HashSet<String>,
+    ) -> bool {
+        match &self.visibility {
+            None => true,
+            Some(_) if target.definition() == consumer.definition() => true,
+            Some(allowed) => allowed.iter().any(|s| s.matches(consumer, tags)),
+        }
+    }
+}
+
+/// Delay before the first retry, doubled for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// When a failed command is re-run.
+#[derive(Debug, Default, Clone)]
+pub struct Retry {
+    /// Attempts allowed after the first.
+    pub retries: u32,
+    /// Exit codes worth retrying, or any failure when `None`.
+    pub on: Option<Vec<i32>>,
+}
+
+impl Retry {
+    /// Whether to run again after `attempt` (zero-based) exited with `status`.
+    pub fn should_retry(&self, attempt: u32, status: &ExitStatus) -> bool {
+        if status.success() || attempt >= self.retries {
+            return false;
+        }
+
+        match (&self.on, status.code()) {
+            (None, _) => true,
+            (Some(codes), Some(code)) => codes.contains(&code),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// How long to wait before running again after `attempt` (zero-based) failed.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        RETRY_BACKOFF
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_BACKOFF)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Out {
+    pub path: PathBuf,
+    /// Lowercase hex digest the produced file must match.
+    pub sha256: Option<String>,
+    /// Whether to set the execute bits once the file is produced.
+    pub executable: bool,
+    /// Whether the output is a whole directory, declared with a trailing `/`.
+    pub dir: bool,
+}
+
+impl Out {
+    /// The produced file, relative to `dir` unless prefixed with `//`, in which case it is
+    /// relative to `root`.
+    pub fn file(&self, root: &Path, dir: &Path) -> PathBuf {
+        match self.path.to_str().and_then(|p| p.strip_prefix("//")) {
+            Some(from_root) => root.join(from_root),
+            None => dir.join(&self.path),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TargetDef {
+    Task(Task),
+    Build(Build),
+    HttpFile(HttpFile),
+    Group(Group),
+    Alias(Alias),
+    Foreach(Foreach),
+}
+
+impl TargetDef {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TargetDef::Task(_) => "task",
+            TargetDef::Build(_) => "build",
+            TargetDef::HttpFile(_) => "http_file",
+            TargetDef::Group(_) => "group",
+            TargetDef::Alias(_) => "alias",
+            TargetDef::Foreach(_) => "foreach",
+        }
+    }
+
+    pub(crate) fn as_build(&self) -> Option<&Build> {
+        match self {
+            TargetDef::Build(b) => Some(b),
+            TargetDef::Task(_)
+            | TargetDef::HttpFile(_)
+            | TargetDef::Group(_)
+            | TargetDef::Alias(_)
+            | TargetDef::Foreach(_) => None,
+        }
+    }
+}
+
+impl Deref for TargetDef {
+    type Target = Common;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            TargetDef::Task(t) => &t.common,
+            TargetDef::Build(b) => &b.common,
+            TargetDef::HttpFile(h) => &h.common,
+            TargetDef::Group(g) => &g.common,
+            TargetDef::Alias(a) => &a.common,
+            TargetDef::Foreach(f) => &f.common,
+        }
+    }
+}
+
+/// A summary of a single target's definition, for tools built on ffs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TargetInfo {
+    pub kind: &'static str,
+    pub command: String,
+    pub prereqs: Vec<TargetPath>,
+    pub tags: Vec<String>,
+    pub outs: BTreeMap<String, PathBuf>,
+}
+
+impl From<&TargetDef> for TargetInfo {
+    fn from(def: &TargetDef) -> Self {
+        let mut prereqs = def.prereqs.iter().cloned().collect::<Vec<_>>();
+        prereqs.sort();
+        let mut tags = def.tags.iter().cloned().collect::<Vec<_>>();
+        tags.sort();
+
+        TargetInfo {
+            kind: def.kind(),
+            command: def
+                .cmd
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" && "),
+            prereqs,
+            tags,
+            outs: def
+                .outs
+                .iter()
+                .map(|(name, out)| (name.clone(), out.path.clone()))
+                .collect(),
+        }
+    }
+}
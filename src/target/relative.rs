@@ -0,0 +1,97 @@
+use std::{path::Path, str::FromStr};
+
+use super::{target::segment, TargetPath};
+
+/// A target written relative to the FFS file it appears in. `%/name` is a sibling target, and
+/// `%sub/name` (or `%/sub/name`) is a target in a subdirectory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelativeTarget {
+    /// Segments of the subdirectory, empty for the same directory.
+    dir: Vec<String>,
+    name: String,
+}
+
+impl RelativeTarget {
+    /// The absolute target, for a reference appearing in `defining_file`, relative to the root.
+    pub fn resolve(&self, defining_file: &Path) -> eyre::Result<TargetPath> {
+        let dir = defining_file.parent().unwrap_or(Path::new(""));
+        let dir = self.dir.iter().fold(dir.to_path_buf(), |d, s| d.join(s));
+        TargetPath::from_path_name(&dir, &self.name)
+    }
+}
+
+impl FromStr for RelativeTarget {
+    type Err = eyre::Report;
+
+    #[context_attr::eyre("Parsing {s:?} as RelativeTarget")]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix('%') else {
+            eyre::bail!("Relative target must start with %");
+        };
+        // Requiring a `/` keeps words like `%s` in commands from being read as targets.
+        eyre::ensure!(rest.contains('/'), "Relative target must contain /");
+        let rest = rest.strip_prefix('/').unwrap_or(rest);
+
+        let mut segments = rest
+            .split('/')
+            .map(|s| segment(s).map(ToString::to_string))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let name = segments.pop().expect("split yields at least one segment");
+
+        Ok(RelativeTarget {
+            dir: segments,
+            name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let valid = ["%/target", "%sub/target", "%/sub/target", "%a/b/target"];
+
+        for t in valid {
+            assert!(
+                t.parse::<RelativeTarget>().is_ok(),
+                "{t:?} failed parsing as RelativeTarget"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_parsing() {
+        let invalid = [
+            "%target", "//target", "%/", "%/a//b", "%/../b", "%/a:out", "%s",
+        ];
+
+        for t in invalid {
+            assert!(
+                t.parse::<RelativeTarget>().is_err(),
+                "{t:?} parsed as RelativeTarget but should have failed"
+            );
+        }
+    }
+
+    #[test]
+    fn resolution() {
+        let resolve = |t: &str, file: &str| {
+            t.parse::<RelativeTarget>()
+                .unwrap()
+                .resolve(Path::new(file))
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(resolve("%/target", "FFS"), "//target");
+        assert_eq!(resolve("%/target", "some/FFS"), "//some/target");
+        assert_eq!(
+            resolve("%another/target", "some/FFS"),
+            "//some/another/target"
+        );
+        assert_eq!(resolve("%/a/b/target", "some/FFS"), "//some/a/b/target");
+        assert_eq!(resolve("%/sub/target", "FFS"), "//sub/target");
+    }
+}
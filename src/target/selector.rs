@@ -0,0 +1,309 @@
+use std::{collections::HashSet, fmt::Display, path::Path, str::FromStr};
+
+use super::{target::ffs_dir, TargetPath};
+
+#[derive(Clone, Debug, Default)]
+pub struct Selector {
+    target: String,
+    allow_children: bool,
+    required_tags: HashSet<String>,
+    /// Tags written as `!tag`, which matching targets must not have.
+    excluded_tags: HashSet<String>,
+    /// Written as `%/...` relative to the invoking directory, and not matchable until resolved
+    /// with [`Selector::relative_to`].
+    relative: bool,
+    original: String,
+}
+
+impl Selector {
+    pub fn matches<T>(&self, path: &TargetPath, tags: &HashSet<T>) -> bool
+    where
+        T: std::borrow::Borrow<str> + Eq + std::hash::Hash,
+    {
+        let path = path.to_string();
+
+        for req in &self.required_tags {
+            if !tags.contains(req.as_str()) {
+                return false;
+            }
+        }
+        for excluded in &self.excluded_tags {
+            if tags.contains(excluded.as_str()) {
+                return false;
+            }
+        }
+
+        let Some(child) = path.strip_prefix(&self.target) else {
+            return false;
+        };
+
+        if child.is_empty() {
+            return true;
+        }
+
+        if self.allow_children {
+            return child.starts_with("/");
+        }
+
+        false
+    }
+
+    /// Whether this can match targets beyond a single named one, like `//path/...`.
+    pub fn is_wildcard(&self) -> bool {
+        self.allow_children
+    }
+
+    /// Resolves a `%/...` selector against `dir`, relative to the root. Absolute selectors are
+    /// returned unchanged.
+    pub fn relative_to(&self, dir: &Path) -> eyre::Result<Selector> {
+        if !self.relative {
+            return Ok(self.clone());
+        }
+
+        let rest = &self.original["%/".len()..];
+        let base = std_to_ffs(dir);
+        if base == "//" {
+            format!("//{rest}").parse()
+        } else {
+            format!("{base}/{rest}").parse()
+        }
+    }
+
+    pub(crate) fn matches_file(&self, path: impl AsRef<Path>) -> bool {
+        let path = std_to_ffs(path);
+
+        if self.allow_children {
+            return path.starts_with(&self.target);
+        }
+
+        let (target_parent, _) = self.target.rsplit_once("/").unwrap();
+        path == target_parent || (path == "//" && target_parent == "/")
+    }
+}
+
+impl FromStr for Selector {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = Selector {
+            original: s.to_string(),
+            ..Default::default()
+        };
+
+        let s = if let Some((s, tags)) = s.split_once("@") {
+            for tag in tags.split(",") {
+                match tag.strip_prefix("!") {
+                    Some(excluded) => result.excluded_tags.insert(excluded.to_string()),
+                    None => result.required_tags.insert(tag.to_string()),
+                };
+            }
+            s
+        } else {
+            s
+        };
+
+        if matches!(s, "*" | "") {
+            result.target = "/".to_string();
+            result.allow_children = true;
+            return Ok(result);
+        }
+
+        if s.starts_with("%/") {
+            result.relative = true;
+            return Ok(result);
+        }
+
+        eyre::ensure!(s.starts_with("//"));
+
+        if let Some(parent) = s.strip_suffix("/...") {
+            result.target = parent.to_string();
+            result.allow_children = true;
+            return Ok(result);
+        }
+
+        result.target = s.to_string();
+        Ok(result)
+    }
+}
+
+impl Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.original)
+    }
+}
+
+fn std_to_ffs(file_or_dir: impl AsRef<Path>) -> String {
+    let file_or_dir = file_or_dir.as_ref();
+    assert!(
+        file_or_dir.is_relative(),
+        "Expected {} to be relative",
+        file_or_dir.display()
+    );
+
+    format!("//{}", ffs_dir(file_or_dir).display()).replace("///", "//")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector_matches<'a>(
+        sel: &str,
+        target: &str,
+        tags: impl IntoIterator<Item = &'a str>,
+    ) -> bool {
+        let sel = sel.parse::<Selector>().unwrap();
+        let target_path = target.parse().unwrap();
+        sel.matches(&target_path, &tags.into_iter().collect())
+    }
+
+    #[test]
+    fn selector_star_matches_everything() {
+        assert!(selector_matches("*", "//some/target", []));
+    }
+
+    #[test]
+    fn selector_exact_does_not_match_other() {
+        assert!(!selector_matches("//a/target", "//another/target", []));
+    }
+
+    #[test]
+    fn selector_matches_exact() {
+        assert!(selector_matches("//a/target", "//a/target", []));
+    }
+
+    #[test]
+    fn glob_matches_children() {
+        assert!(selector_matches(
+            "//some/path/...",
+            "//some/path/actual_target",
+            []
+        ));
+    }
+
+    #[test]
+    fn glob_does_not_match_sibling_directory() {
+        assert!(!selector_matches(
+            "//some/path/...",
+            "//some/path_suffix/actual_target",
+            []
+        ));
+    }
+
+    #[test]
+    fn matches_with_tags() {
+        assert!(selector_matches("@test", "//some/target", ["test"]));
+    }
+
+    #[test]
+    fn does_not_match_without_tags() {
+        assert!(!selector_matches("@test", "//some/target", ["deploy"]));
+    }
+
+    #[test]
+    fn matches_with_all_tags() {
+        assert!(selector_matches(
+            "@test,deploy",
+            "//some/target",
+            ["deploy", "test"]
+        ));
+    }
+
+    #[test]
+    fn does_not_match_with_some_tags() {
+        assert!(!selector_matches(
+            "@test,deploy",
+            "//some/target",
+            ["deploy"]
+        ));
+    }
+
+    #[test]
+    fn excluded_tag_matches_without_it() {
+        assert!(selector_matches("@!slow", "//some/target", []));
+        assert!(selector_matches("@!slow", "//some/target", ["test"]));
+    }
+
+    #[test]
+    fn excluded_tag_rejects_with_it() {
+        assert!(!selector_matches("@!slow", "//some/target", ["slow"]));
+        assert!(!selector_matches(
+            "//...@!slow",
+            "//some/target",
+            ["test", "slow"]
+        ));
+    }
+
+    #[test]
+    fn required_and_excluded_tags() {
+        assert!(selector_matches("@test,!slow", "//some/target", ["test"]));
+        assert!(!selector_matches("@test,!slow", "//some/target", []));
+        assert!(!selector_matches(
+            "@test,!slow",
+            "//some/target",
+            ["test", "slow"]
+        ));
+    }
+
+    #[test]
+    fn exact_does_not_match_child() {
+        assert!(!selector_matches("//a/target", "//a/target/child", []));
+    }
+
+    #[test]
+    fn bad_target_specifier() {
+        assert!("bad/target".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn relative_resolves_against_dir() {
+        let resolve = |sel: &str, dir: &str| {
+            let sel = sel.parse::<Selector>().unwrap();
+            sel.relative_to(Path::new(dir)).unwrap().to_string()
+        };
+
+        assert_eq!(resolve("%/target", "lib/sub"), "//lib/sub/target");
+        assert_eq!(resolve("%/...@test", "lib"), "//lib/...@test");
+        assert_eq!(resolve("%/...", ""), "//...");
+        assert_eq!(resolve("//abs/target", "lib"), "//abs/target");
+    }
+
+    fn selector_matches_file(sel: &str, file: &str) -> bool {
+        let sel = sel.parse::<Selector>().unwrap();
+        sel.matches_file(file)
+    }
+
+    #[test]
+    fn exact_matches_file() {
+        assert!(selector_matches_file("//path/to/target", "./path/to/FFS"));
+    }
+
+    #[test]
+    fn exact_but_different_file() {
+        assert!(!selector_matches_file(
+            "//path/to/target",
+            "./path/elsewhere/FFS"
+        ));
+    }
+
+    #[test]
+    fn child_file_match() {
+        assert!(selector_matches_file(
+            "//path/to/...",
+            "./path/to/some/child/FFS"
+        ));
+    }
+
+    #[test]
+    fn poorly_named_sibling() {
+        assert!(!selector_matches_file(
+            "//path/to_elsewhere/target",
+            "./path/to/FFS"
+        ));
+    }
+
+    #[test]
+    fn root_file() {
+        assert!(selector_matches_file("//root_target", "./FFS"));
+    }
+}
@@ -0,0 +1,68 @@
+use std::{fmt::Display, str::FromStr};
+
+use super::{ident, TargetPath};
+
+/// A named src of another build, referenced as `//path/to/target:src:name`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Source {
+    pub target: TargetPath,
+    pub name: String,
+}
+
+impl Source {
+    pub fn target(&self) -> &TargetPath {
+        &self.target
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl FromStr for Source {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((target, name)) = s.split_once(":src:") else {
+            eyre::bail!("Source must look like //target:src:name");
+        };
+        eyre::ensure!(!name.is_empty(), "Empty source name");
+
+        Ok(Source {
+            target: target.parse()?,
+            name: ident(name)?.to_string(),
+        })
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:src:{}", self.target, self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses() {
+        let source = "//path/to/target:src:main".parse::<Source>().unwrap();
+
+        assert_eq!(source.target().to_string(), "//path/to/target");
+        assert_eq!(source.name(), "main");
+        assert_eq!(source.to_string(), "//path/to/target:src:main");
+    }
+
+    #[test]
+    fn invalid_parses() {
+        let cases = ["//target:main", "//target:src:", "//target:src:a/b"];
+
+        for t in cases {
+            assert!(
+                t.parse::<Source>().is_err(),
+                "{t:?} parsed as Source, but should have failed"
+            );
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use crate::{executor::Execution, target::TargetPath};
+
+/// Creates a temporary workspace containing the provided `(path, contents)` files.
+pub fn workspace<'f>(files: impl IntoIterator<Item = (&'f str, &'f str)>) -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    write_files(dir.path(), files);
+    dir
+}
+
+pub fn write_files<'f>(root: &Path, files: impl IntoIterator<Item = (&'f str, &'f str)>) {
+    for (path, contents) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+}
+
+/// An [`Execution`] of `command` in the current directory, with nothing else set.
+pub fn execution<'l>(path: &'l TargetPath, command: &'l str) -> Execution<'l> {
+    Execution {
+        path,
+        command,
+        dir: Path::new("."),
+        runs_on: None,
+        arch: None,
+        env: None,
+        timeout: None,
+        dry_run: false,
+        shell: None,
+        stdin: None,
+        argv: None,
+    }
+}
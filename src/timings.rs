@@ -0,0 +1,177 @@
+use std::{collections::BTreeMap, path::PathBuf, sync::Mutex, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{stats::Stats, target::TargetPath};
+
+/// Bumped whenever what a timing covers changes, so older files are discarded.
+const VERSION: u32 = 1;
+
+/// How many of its most recent durations are kept per target.
+const SAMPLES: usize = 10;
+
+/// How long targets took to build in recent runs, persisted across runs to estimate how long
+/// the rest of a build will take.
+///
+/// Stored as JSON, written to a temporary file and renamed into place like
+/// [`Fingerprints`](crate::fingerprint::Fingerprints).
+pub struct Timings {
+    file: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Milliseconds per run, oldest first.
+    targets: BTreeMap<String, Vec<u64>>,
+    changed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    version: u32,
+    targets: BTreeMap<String, Vec<u64>>,
+}
+
+impl Timings {
+    /// Reads `file`, starting empty if it is missing, corrupt, or from another version.
+    pub fn load(file: impl Into<PathBuf>) -> Self {
+        let file = file.into();
+        let targets = std::fs::read(&file)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Stored>(&bytes).ok())
+            .filter(|stored| stored.version == VERSION)
+            .map(|stored| stored.targets)
+            .unwrap_or_default();
+
+        Self {
+            file,
+            state: Mutex::new(State {
+                targets,
+                changed: false,
+            }),
+        }
+    }
+
+    /// Adds a run of `target` taking `took`, forgetting the oldest beyond the last few.
+    pub fn record(&self, target: &TargetPath, took: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let samples = state.targets.entry(target.to_string()).or_default();
+        samples.push(took.as_millis() as u64);
+        if samples.len() > SAMPLES {
+            samples.remove(0);
+        }
+        state.changed = true;
+    }
+
+    /// The median of `target`'s recorded durations, if it has any.
+    pub fn median(&self, target: &TargetPath) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        let samples = state.targets.get(&target.to_string())?;
+        let samples = samples
+            .iter()
+            .copied()
+            .map(Duration::from_millis)
+            .collect::<Vec<_>>();
+        Stats::of(&samples).map(|stats| stats.median)
+    }
+
+    /// How long building `target` should take: its median, or `default` if it was never
+    /// recorded.
+    pub fn expected(&self, target: &TargetPath, default: Duration) -> Duration {
+        self.median(target).unwrap_or(default)
+    }
+
+    /// Writes the timings, if any were recorded since loading.
+    pub fn save(&self) -> eyre::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.changed {
+            return Ok(());
+        }
+
+        let stored = Stored {
+            version: VERSION,
+            targets: state.targets.clone(),
+        };
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let partial = self.file.with_extension("json.partial");
+        std::fs::write(&partial, serde_json::to_vec_pretty(&stored)?)?;
+        std::fs::rename(&partial, &self.file)?;
+
+        state.changed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(dir: &tempfile::TempDir) -> Timings {
+        let timings = Timings::load(dir.path().join(".ffs/timings.json"));
+        let runs = [
+            ("//a", 1000),
+            ("//a", 3000),
+            ("//a", 2000),
+            ("//b", 1000),
+            ("//b", 2000),
+        ];
+        for (target, ms) in runs {
+            timings.record(&target.parse().unwrap(), Duration::from_millis(ms));
+        }
+        timings
+    }
+
+    fn targets(targets: &[&str]) -> Vec<TargetPath> {
+        targets.iter().map(|t| t.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn medians() {
+        let dir = tempfile::tempdir().unwrap();
+        let timings = history(&dir);
+
+        let median = |target: &str| timings.median(&target.parse().unwrap());
+        assert_eq!(median("//a"), Some(Duration::from_secs(2)));
+        assert_eq!(median("//b"), Some(Duration::from_millis(1500)));
+        assert_eq!(median("//c"), None);
+    }
+
+    #[test]
+    fn expected_falls_back_to_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let timings = history(&dir);
+        let default = Duration::from_secs(5);
+
+        let expected = |target: &str| timings.expected(&target.parse().unwrap(), default);
+        assert_eq!(expected("//a"), Duration::from_secs(2));
+        assert_eq!(expected("//b"), Duration::from_millis(1500));
+        assert_eq!(expected("//c"), default);
+    }
+
+    #[test]
+    fn keeps_only_recent_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let timings = Timings::load(dir.path().join("timings.json"));
+        let target = "//a".parse().unwrap();
+
+        timings.record(&target, Duration::from_secs(100));
+        for _ in 0..SAMPLES {
+            timings.record(&target, Duration::from_secs(1));
+        }
+        assert_eq!(timings.median(&target), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        history(&dir).save().unwrap();
+
+        let timings = Timings::load(dir.path().join(".ffs/timings.json"));
+        for (target, median) in targets(&["//a", "//b"]).iter().zip([2000, 1500]) {
+            assert_eq!(timings.median(target), Some(Duration::from_millis(median)));
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::Duration,
+};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::plan::Plan;
+
+/// How often to check whether watching should stop while no files are changing.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Files under a root that have changed since it started being watched.
+pub struct Changes {
+    root: PathBuf,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    _watcher: RecommendedWatcher,
+}
+
+impl Changes {
+    pub fn new(root: &Path) -> eyre::Result<Self> {
+        let root = root.canonicalize()?;
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        Ok(Changes {
+            root,
+            events,
+            _watcher: watcher,
+        })
+    }
+
+    /// Blocks until a file `watched` contains changes, then keeps collecting changes until none
+    /// arrive for `debounce`. Returns the changed files relative to the root, or `None` once
+    /// `stop` is set.
+    pub fn next(
+        &self,
+        watched: &Watched,
+        debounce: Duration,
+        stop: &AtomicBool,
+    ) -> eyre::Result<Option<Vec<PathBuf>>> {
+        let mut changed = BTreeSet::new();
+        loop {
+            let timeout = if changed.is_empty() { POLL } else { debounce };
+            match self.events.recv_timeout(timeout) {
+                Ok(event) => {
+                    let event = event?;
+                    if matches!(event.kind, EventKind::Access(_)) {
+                        continue;
+                    }
+                    let relevant = event
+                        .paths
+                        .iter()
+                        .filter_map(|p| p.strip_prefix(&self.root).ok())
+                        .filter(|p| watched.contains(p))
+                        .map(Path::to_path_buf);
+                    changed.extend(relevant);
+                }
+                Err(RecvTimeoutError::Timeout) if changed.is_empty() => {
+                    if stop.load(Ordering::Relaxed) {
+                        return Ok(None);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(Some(changed.into_iter().collect())),
+                Err(RecvTimeoutError::Disconnected) => eyre::bail!("File watcher stopped"),
+            }
+        }
+    }
+}
+
+/// The files a build depends on: every FFS file, and the srcs of its builds.
+#[derive(Debug, Default)]
+pub struct Watched {
+    /// Relative to the root. Directory srcs cover everything inside them.
+    srcs: Vec<PathBuf>,
+}
+
+impl Watched {
+    pub fn new(plan: &Plan, root: &Path) -> Self {
+        let srcs = plan
+            .steps
+            .iter()
+            .filter_map(|step| Some((step, step.def().as_build()?)))
+            .flat_map(|(step, build)| build.srcs.iter().map(|src| step.dir.join(src)))
+            .filter_map(|src| Some(src.strip_prefix(root).ok()?.to_path_buf()))
+            .collect();
+        Watched { srcs }
+    }
+
+    /// Whether `path`, relative to the root, is watched.
+    pub fn contains(&self, path: &Path) -> bool {
+        is_ffs_file(path) || self.srcs.iter().any(|src| path.starts_with(src))
+    }
+}
+
+pub fn is_ffs_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|f| f == "FFS")
+}